@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Runtime};
+use tauri_plugin_http::reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client,
+};
+
+use crate::error::{Error, Result};
+
+/// the tokens we've got for each server, keyed by server url. these are
+/// saved in a file only the current user can read
+pub struct Credentials {
+    path: PathBuf,
+    tokens: Mutex<HashMap<String, String>>,
+}
+impl Credentials {
+    pub fn load(path: &Path) -> Self {
+        let tokens = match fs::read(path) {
+            Ok(saved) => serde_json::from_slice(&saved).unwrap_or_else(|e| {
+                eprintln!("couldn't parse credentials: {e}");
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                eprintln!("couldn't read credentials: {e}");
+                HashMap::new()
+            }
+        };
+        Self {
+            path: path.into(),
+            tokens: Mutex::new(tokens),
+        }
+    }
+
+    pub fn token(&self, url: &str) -> Option<String> {
+        self.tokens.lock().unwrap().get(url).cloned()
+    }
+
+    /// sets (or with `None`, forgets) the token for `url` and saves
+    pub fn set(&self, url: &str, token: Option<String>) -> Result<()> {
+        let mut tokens = self.tokens.lock().unwrap();
+        match token {
+            Some(t) => tokens.insert(url.into(), t),
+            None => tokens.remove(url),
+        };
+        save_private(&self.path, &serde_json::to_vec(&*tokens).unwrap())
+            .map_err(|e| Error::Storage(e.to_string()))
+    }
+}
+
+/// the file is written from scratch and moved into place, so one that's
+/// already there with looser permissions doesn't keep them
+fn save_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    // left over from a save that didn't finish
+    let _ = fs::remove_file(&tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// a client that sends `token` with every request
+pub fn build_client(token: Option<&str>) -> Result<Client> {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(|_| Error::InvalidSettings("token has invalid characters".into()))?;
+        // keeps it out of debug output
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Client::builder()
+        .default_headers(headers)
+        .build()
+        .map_err(Error::from)
+}
+
+#[derive(Serialize)]
+struct LoginReq<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+#[derive(Deserialize)]
+struct LoginResp {
+    token: String,
+}
+
+/// trades a username and password for a token
+pub async fn login(url: &str, username: &str, password: &str) -> Result<String> {
+    let resp = Client::new()
+        .post(format!("{url}/login"))
+        .json(&LoginReq { username, password })
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<LoginResp>()
+        .await?;
+    Ok(resp.token)
+}
+
+/// lets the frontend know it has to log in again if `error` came from the
+/// server turning down our credentials
+pub fn notify_unauthorized<R: Runtime>(app: &AppHandle<R>, error: &Error) {
+    if let Error::Unauthorized(_) = error {
+        if let Err(e) = app.emit("login-required", ()) {
+            eprintln!("couldn't ask for a login: {e}");
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+
+    #[test]
+    fn saved_credentials_are_private_even_if_they_werent_before() {
+        let path = env::temp_dir().join(format!("pi-fi-credentials-{}.json", process::id()));
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        save_private(&path, b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let contents = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, b"secret");
+    }
+}
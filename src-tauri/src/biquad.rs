@@ -0,0 +1,158 @@
+use std::f64::consts::PI;
+
+/// a second order iir filter, run in transposed direct form II
+#[derive(Clone)]
+pub struct Biquad {
+    b: [f64; 3],
+    /// `a[0]` is always 1, the rest are divided through by it
+    a: [f64; 3],
+    z1: f64,
+    z2: f64,
+}
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|b| b / a[0]),
+            a: a.map(|a_| a_ / a[0]),
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// boosts or cuts around `frequency`, `q` sets how wide. the designs
+    /// here are from the audio eq cookbook
+    pub fn peaking(rate: f64, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let (a, cos, alpha) = cookbook(rate, frequency, q, gain_db);
+        Self::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    /// boosts or cuts everything below `frequency`
+    pub fn low_shelf(rate: f64, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let (a, cos, alpha) = cookbook(rate, frequency, q, gain_db);
+        let sq = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + sq),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sq),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + sq,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sq,
+            ],
+        )
+    }
+
+    /// boosts or cuts everything above `frequency`
+    pub fn high_shelf(rate: f64, frequency: f64, q: f64, gain_db: f64) -> Self {
+        let (a, cos, alpha) = cookbook(rate, frequency, q, gain_db);
+        let sq = 2.0 * a.sqrt() * alpha;
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + sq),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sq),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + sq,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sq,
+            ],
+        )
+    }
+
+    /// takes over `old`'s state, for when the coefficients change partway
+    /// through a stream
+    pub fn carry_over(&mut self, old: &Biquad) {
+        self.z1 = old.z1;
+        self.z2 = old.z2;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z1;
+        self.z1 = self.b[1] * x - self.a[1] * y + self.z2;
+        self.z2 = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+/// the intermediate values all the cookbook designs share. frequencies past
+/// nyquist don't mean anything, so they're pulled back just under it
+fn cookbook(rate: f64, frequency: f64, q: f64, gain_db: f64) -> (f64, f64, f64) {
+    let a = 10f64.powf(gain_db / 40.0);
+    let w0 = 2.0 * PI * frequency.min(rate * 0.49) / rate;
+    (a, w0.cos(), w0.sin() / (2.0 * q))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the filter's gain at `frequency` in dB, straight from its transfer
+    /// function
+    fn response_db(filter: &Biquad, rate: f64, frequency: f64) -> f64 {
+        let w = 2.0 * PI * frequency / rate;
+        let magnitude = |c: &[f64; 3]| {
+            let re = c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos();
+            let im = -(c[1] * w.sin() + c[2] * (2.0 * w).sin());
+            re.hypot(im)
+        };
+        20.0 * (magnitude(&filter.b) / magnitude(&filter.a)).log10()
+    }
+
+    fn assert_db(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{actual} dB, not {expected}"
+        );
+    }
+
+    #[test]
+    fn peaking_is_at_its_gain_in_the_middle() {
+        for rate in [44100.0, 48000.0, 96000.0] {
+            for gain_db in [-12.0, -3.0, 6.0, 12.0] {
+                let filter = Biquad::peaking(rate, 1000.0, 1.4, gain_db);
+                assert_db(response_db(&filter, rate, 1000.0), gain_db);
+                // and leaves everything far enough away alone
+                assert_db(response_db(&filter, rate, 10.0), 0.0);
+                assert_db(response_db(&filter, rate, rate / 2.0), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn low_shelf_boosts_the_bottom() {
+        let filter = Biquad::low_shelf(48000.0, 200.0, 0.707, 6.0);
+        assert_db(response_db(&filter, 48000.0, 0.0), 6.0);
+        // halfway there at the corner
+        assert_db(response_db(&filter, 48000.0, 200.0), 3.0);
+        assert_db(response_db(&filter, 48000.0, 24000.0), 0.0);
+    }
+
+    #[test]
+    fn high_shelf_boosts_the_top() {
+        let filter = Biquad::high_shelf(48000.0, 8000.0, 0.707, -6.0);
+        assert_db(response_db(&filter, 48000.0, 24000.0), -6.0);
+        assert_db(response_db(&filter, 48000.0, 8000.0), -3.0);
+        assert_db(response_db(&filter, 48000.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn no_gain_passes_straight_through() {
+        let mut filter = Biquad::peaking(48000.0, 1000.0, 1.0, 0.0);
+        for i in 0..100 {
+            let x = (i as f64 * 0.37).sin();
+            assert!((filter.process(x) - x).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn frequencies_past_nyquist_are_pulled_back() {
+        let filter = Biquad::peaking(44100.0, 30000.0, 1.0, 6.0);
+        assert_db(response_db(&filter, 44100.0, 44100.0 * 0.49), 6.0);
+    }
+}
@@ -1,26 +1,79 @@
-use std::{collections::BTreeMap, sync::Mutex};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
 
-use crate::SERVER_URL;
+use crate::{
+    error::{Error, Result},
+    library_db::{LibraryDb, ALBUMS, ARTISTS, LOUDNESS, TRACKS},
+    loudness::TrackLoudness,
+};
 
 use serde::{Deserialize, Serialize};
-use tauri::async_runtime::block_on;
-use tauri_plugin_http::reqwest::Client;
+use tauri_plugin_http::reqwest::{Client, StatusCode};
+use tokio::sync::Mutex as AsyncMutex;
+
+type AlbumFetches = Mutex<HashMap<i64, Arc<AsyncMutex<()>>>>;
+
+/// a place in line to fetch an album, everyone fetching the same album
+/// shares a lock so only one of them asks the server. the album is taken
+/// out of `album_fetches` when the last one in line is done with it, or
+/// gives up
+struct AlbumFetch<'a> {
+    fetches: &'a AlbumFetches,
+    id: i64,
+    lock: Arc<AsyncMutex<()>>,
+}
+impl<'a> AlbumFetch<'a> {
+    fn join(fetches: &'a AlbumFetches, id: i64) -> Self {
+        let lock = fetches.lock().unwrap().entry(id).or_default().clone();
+        Self { fetches, id, lock }
+    }
+}
+impl Drop for AlbumFetch<'_> {
+    fn drop(&mut self) {
+        let mut fetches = self.fetches.lock().unwrap();
+        // the lock only gets handed out with `fetches` locked, so nobody
+        // else can be joining while we count
+        let last = Arc::strong_count(&self.lock) == 2;
+        if last
+            && fetches
+                .get(&self.id)
+                .is_some_and(|l| Arc::ptr_eq(l, &self.lock))
+        {
+            fetches.remove(&self.id);
+        }
+    }
+}
 
 pub struct Cache {
     albums: Mutex<BTreeMap<i64, Album>>,
     artists: Mutex<BTreeMap<i64, Artist>>,
     tracks: Mutex<BTreeMap<i64, Track>>,
+    /// loudness we measured for tracks without replaygain tags
+    loudness: Mutex<BTreeMap<i64, TrackLoudness>>,
+    /// the server revision our library is from
+    revision: Mutex<Option<String>>,
+    /// albums that are being fetched from the server right now
+    album_fetches: AlbumFetches,
 
-    client: Client,
+    /// swapped out when we log in or out
+    client: Mutex<Client>,
+    db: LibraryDb,
+    /// base url of the server this library comes from
+    server_url: String,
 }
+#[derive(Serialize, Deserialize, PartialEq)]
 struct Album {
     title: String,
     artist_id: i64,
     track_ids: Vec<i64>,
 }
+#[derive(Serialize, Deserialize, PartialEq)]
 struct Artist {
     name: String,
 }
+#[derive(Serialize, Deserialize, PartialEq)]
 struct Track {
     title: String,
     track_number: u32,
@@ -30,8 +83,32 @@ struct Track {
 
 #[derive(Deserialize, Clone)]
 struct GetLibResp {
+    #[serde(default)]
+    revision: Option<String>,
+    albums: Vec<GetLibRespAlbum>,
+    artists: Vec<GetLibRespArtist>,
+    tracks: Vec<GetLibRespTrack>,
+}
+#[derive(Deserialize, Clone)]
+struct GetLibChangesResp {
+    revision: String,
+    #[serde(default)]
     albums: Vec<GetLibRespAlbum>,
+    #[serde(default)]
     artists: Vec<GetLibRespArtist>,
+    #[serde(default)]
+    tracks: Vec<GetLibRespTrack>,
+    #[serde(default)]
+    deleted_albums: Vec<i64>,
+    #[serde(default)]
+    deleted_artists: Vec<i64>,
+    #[serde(default)]
+    deleted_tracks: Vec<i64>,
+}
+#[derive(Deserialize, Clone)]
+struct GetAlbumServerResp {
+    album: GetLibRespAlbum,
+    artist: GetLibRespArtist,
     tracks: Vec<GetLibRespTrack>,
 }
 #[derive(Deserialize, Clone)]
@@ -55,128 +132,445 @@ struct GetLibRespTrack {
     track_number: u32,
 }
 
+impl GetLibRespAlbum {
+    fn into_entry(self) -> (i64, Album) {
+        (
+            self.id,
+            Album {
+                title: self.title,
+                artist_id: self.artist_id,
+                track_ids: self.track_ids,
+            },
+        )
+    }
+}
+impl GetLibRespArtist {
+    fn into_entry(self) -> (i64, Artist) {
+        (self.id, Artist { name: self.name })
+    }
+}
+impl GetLibRespTrack {
+    fn into_entry(self) -> (i64, Track) {
+        (
+            self.id,
+            Track {
+                title: self.title,
+                artist_id: self.artist_id,
+                album_id: self.album_id,
+                track_number: self.track_number,
+            },
+        )
+    }
+}
+
 impl Cache {
-    pub fn new(client: Client) -> Self {
-        let mut albums = BTreeMap::new();
-        let mut artists = BTreeMap::new();
-        let mut tracks = BTreeMap::new();
-
-        let get_lib_resp = block_on(async {
-            let resp = client
-                .get(format!("{SERVER_URL}/get-library"))
-                .send()
-                .await
-                .unwrap();
-            resp.json::<GetLibResp>().await.unwrap()
+    /// loads whatever library we saved last time, call `refresh` to bring it
+    /// up to date with the server
+    pub fn new(client: Client, db: LibraryDb, server_url: String) -> Self {
+        let (albums, artists, tracks) = match (db.load(ALBUMS), db.load(ARTISTS), db.load(TRACKS)) {
+            (Ok(albums), Ok(artists), Ok(tracks)) => (albums, artists, tracks),
+            (albums, artists, tracks) => {
+                for e in [albums.err(), artists.err(), tracks.err()]
+                    .into_iter()
+                    .flatten()
+                {
+                    eprintln!("couldn't load saved library: {e}");
+                }
+                Default::default()
+            }
+        };
+
+        let loudness = db.load(LOUDNESS).unwrap_or_else(|e| {
+            eprintln!("couldn't load saved loudness: {e}");
+            BTreeMap::new()
         });
 
-        for album in get_lib_resp.albums {
-            albums.insert(
-                album.id,
-                Album {
-                    title: album.title,
-                    artist_id: album.artist_id,
-                    track_ids: album.track_ids,
-                },
-            );
-        }
-        for artist in get_lib_resp.artists {
-            artists.insert(artist.id, Artist { name: artist.name });
-        }
-        for track in get_lib_resp.tracks {
-            tracks.insert(
-                track.id,
-                Track {
-                    title: track.title,
-                    artist_id: track.artist_id,
-                    album_id: track.album_id,
-                    track_number: track.track_number,
-                },
-            );
-        }
+        let revision = db.load_revision().unwrap_or_else(|e| {
+            eprintln!("couldn't load saved library revision: {e}");
+            None
+        });
 
         Self {
             albums: Mutex::new(albums),
             artists: Mutex::new(artists),
             tracks: Mutex::new(tracks),
-            client,
+            loudness: Mutex::new(loudness),
+            revision: Mutex::new(revision),
+            album_fetches: Mutex::new(HashMap::new()),
+            client: Mutex::new(client),
+            db,
+            server_url,
         }
     }
 
-    pub fn get_library(&self) -> Result<LibraryData, ()> {
+    pub fn server_url(&self) -> &str {
+        &self.server_url
+    }
+
+    pub fn client(&self) -> Client {
+        self.client.lock().unwrap().clone()
+    }
+
+    pub fn set_client(&self, client: Client) {
+        *self.client.lock().unwrap() = client;
+    }
+
+    /// brings the library up to date with the server and saves it to disk.
+    /// if we know what revision we're at only the changes since then are
+    /// fetched, otherwise (or if the server can't do that) we get everything
+    pub async fn refresh(&self) -> Result<LibraryChanges> {
+        let revision = self.revision.lock().unwrap().clone();
+        if let Some(revision) = revision {
+            let resp = self
+                .client()
+                .get(format!("{}/get-library-changes", self.server_url))
+                .query(&[("since", &revision)])
+                .send()
+                .await?;
+            match resp.status() {
+                // the server doesn't know about that revision anymore (or
+                // doesn't do incremental syncs at all)
+                StatusCode::NOT_FOUND | StatusCode::GONE => {}
+                _ => {
+                    let changes = resp.error_for_status()?.json::<GetLibChangesResp>().await?;
+                    return Ok(self.apply_changes(changes));
+                }
+            }
+        }
+
+        let get_lib_resp = self
+            .client()
+            .get(format!("{}/get-library", self.server_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<GetLibResp>()
+            .await?;
+        Ok(self.replace_library(get_lib_resp))
+    }
+
+    fn replace_library(&self, resp: GetLibResp) -> LibraryChanges {
+        let albums: BTreeMap<_, _> = resp.albums.into_iter().map(|a| a.into_entry()).collect();
+        let artists: BTreeMap<_, _> = resp.artists.into_iter().map(|a| a.into_entry()).collect();
+        let tracks: BTreeMap<_, _> = resp.tracks.into_iter().map(|t| t.into_entry()).collect();
+        // measurements are kept for as long as their tracks are around
+        let gone: Vec<_> = self
+            .loudness
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|id| !tracks.contains_key(id))
+            .copied()
+            .collect();
+
+        let saved = self.db.write(|w| {
+            w.clear(ALBUMS)?;
+            w.clear(ARTISTS)?;
+            w.clear(TRACKS)?;
+            w.upsert(ALBUMS, &albums)?;
+            w.upsert(ARTISTS, &artists)?;
+            w.upsert(TRACKS, &tracks)?;
+            w.delete(LOUDNESS, &gone)?;
+            w.set_revision(resp.revision.as_deref())
+        });
+        if let Err(e) = saved {
+            eprintln!("couldn't save library: {e}");
+        }
+
+        // always lock albums, then tracks, then artists so we don't deadlock
+        let mut album_cache = self.albums.lock().unwrap();
+        let mut track_cache = self.tracks.lock().unwrap();
+        let mut artist_cache = self.artists.lock().unwrap();
+        let changes = LibraryChanges {
+            albums: ChangedIds::between(&album_cache, &albums),
+            artists: ChangedIds::between(&artist_cache, &artists),
+            tracks: ChangedIds::between(&track_cache, &tracks),
+        };
+        *album_cache = albums;
+        *artist_cache = artists;
+        *track_cache = tracks;
+        let mut loudness = self.loudness.lock().unwrap();
+        for id in &gone {
+            loudness.remove(id);
+        }
+        *self.revision.lock().unwrap() = resp.revision;
+        changes
+    }
+
+    fn apply_changes(&self, resp: GetLibChangesResp) -> LibraryChanges {
+        let albums: BTreeMap<_, _> = resp.albums.into_iter().map(|a| a.into_entry()).collect();
+        let artists: BTreeMap<_, _> = resp.artists.into_iter().map(|a| a.into_entry()).collect();
+        let tracks: BTreeMap<_, _> = resp.tracks.into_iter().map(|t| t.into_entry()).collect();
+
+        let saved = self.db.write(|w| {
+            w.upsert(ALBUMS, &albums)?;
+            w.upsert(ARTISTS, &artists)?;
+            w.upsert(TRACKS, &tracks)?;
+            w.delete(ALBUMS, &resp.deleted_albums)?;
+            w.delete(ARTISTS, &resp.deleted_artists)?;
+            w.delete(TRACKS, &resp.deleted_tracks)?;
+            w.delete(LOUDNESS, &resp.deleted_tracks)?;
+            w.set_revision(Some(&resp.revision))
+        });
+        if let Err(e) = saved {
+            eprintln!("couldn't save library changes: {e}");
+        }
+
+        let changes = LibraryChanges {
+            albums: ChangedIds::new(&albums, resp.deleted_albums),
+            artists: ChangedIds::new(&artists, resp.deleted_artists),
+            tracks: ChangedIds::new(&tracks, resp.deleted_tracks),
+        };
+
+        // always lock albums, then tracks, then artists so we don't deadlock
+        let mut album_cache = self.albums.lock().unwrap();
+        let mut track_cache = self.tracks.lock().unwrap();
+        let mut artist_cache = self.artists.lock().unwrap();
+        album_cache.extend(albums);
+        track_cache.extend(tracks);
+        artist_cache.extend(artists);
+        for id in &changes.albums.deleted {
+            album_cache.remove(id);
+        }
+        let mut loudness = self.loudness.lock().unwrap();
+        for id in &changes.tracks.deleted {
+            track_cache.remove(id);
+            loudness.remove(id);
+        }
+        for id in &changes.artists.deleted {
+            artist_cache.remove(id);
+        }
+        *self.revision.lock().unwrap() = Some(resp.revision);
+        changes
+    }
+
+    pub fn get_library(&self) -> Result<LibraryData> {
         let album_cache = self.albums.lock().unwrap();
         let mut albums = Vec::with_capacity(album_cache.len());
         for (album_id, album_data) in album_cache.iter() {
             let artist_cache = self.artists.lock().unwrap();
+            // a sync can take the artist away and leave the album behind,
+            // that shouldn't take the rest of the library with it
+            let Some(artist) = artist_cache.get(&album_data.artist_id) else {
+                eprintln!(
+                    "skipping album {album_id}, its artist {} is missing",
+                    album_data.artist_id
+                );
+                continue;
+            };
             albums.push(AlbumData {
                 id: *album_id,
                 title: album_data.title.clone(),
-                artist_name: artist_cache
-                    .get(&album_data.artist_id)
-                    .unwrap()
-                    .name
-                    .clone(),
+                artist_name: artist.name.clone(),
             });
         }
 
         Ok(LibraryData { albums })
     }
 
-    pub fn get_album(&self, id: i64) -> Result<GetAlbumResp, ()> {
+    /// gets an album from the cache, fetching it from the server if we
+    /// don't have it. concurrent calls for the same album share one fetch
+    pub async fn get_album(&self, id: i64) -> Result<GetAlbumResp> {
+        if let Some(album) = self.cached_album(id) {
+            return Ok(album);
+        }
+
+        let fetch = AlbumFetch::join(&self.album_fetches, id);
+        let _fetching = fetch.lock.lock().await;
+        // whoever had the lock before us might have just fetched it
+        if let Some(album) = self.cached_album(id) {
+            return Ok(album);
+        }
+
+        self.fetch_album(id).await?;
+        self.cached_album(id)
+            .ok_or_else(|| Error::ServerProtocol(format!("server sent an incomplete album {id}")))
+    }
+
+    #[cfg(test)]
+    pub fn album_fetches(&self) -> usize {
+        self.album_fetches.lock().unwrap().len()
+    }
+
+    /// cover art isn't cached, the webview keeps its own copy
+    pub async fn get_image(&self, id: i64) -> Result<Vec<u8>> {
+        let bytes = self
+            .client()
+            .get(format!("{}/get-image", self.server_url))
+            .query(&[("id", id)])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(bytes.into())
+    }
+
+    /// gets a single album (with its tracks and artist) from the server and
+    /// adds it to the cache
+    async fn fetch_album(&self, id: i64) -> Result<()> {
+        let resp = self
+            .client()
+            .get(format!("{}/get-album", self.server_url))
+            .query(&[("id", id)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<GetAlbumServerResp>()
+            .await?;
+
+        let album = BTreeMap::from([resp.album.into_entry()]);
+        let artist = BTreeMap::from([resp.artist.into_entry()]);
+        let tracks: BTreeMap<_, _> = resp.tracks.into_iter().map(|t| t.into_entry()).collect();
+
+        let saved = self.db.write(|w| {
+            w.upsert(ALBUMS, &album)?;
+            w.upsert(ARTISTS, &artist)?;
+            w.upsert(TRACKS, &tracks)
+        });
+        if let Err(e) = saved {
+            eprintln!("couldn't save album {id}: {e}");
+        }
+
+        // always lock albums, then tracks, then artists so we don't deadlock
+        let mut album_cache = self.albums.lock().unwrap();
+        let mut track_cache = self.tracks.lock().unwrap();
+        let mut artist_cache = self.artists.lock().unwrap();
+        album_cache.extend(album);
+        track_cache.extend(tracks);
+        artist_cache.extend(artist);
+        Ok(())
+    }
+
+    /// returns `None` if we don't have the album, or only have part of it
+    fn cached_album(&self, id: i64) -> Option<GetAlbumResp> {
         let album_cache = self.albums.lock().unwrap();
         match album_cache.get(&id) {
             Some(a) => {
                 let track_cache = self.tracks.lock().unwrap();
                 let mut tracks = Vec::new();
                 for track_id in a.track_ids.iter() {
-                    let track = track_cache.get(track_id).unwrap();
+                    let track = track_cache.get(track_id)?;
                     tracks.push(GetAlbumRespTrack {
                         id: *track_id,
                         title: track.title.clone(),
                         track_number: track.track_number,
                     });
                 }
-                tracks.sort_by(|a, b| a.track_number.cmp(&b.track_number));
+                tracks.sort_by_key(|t| t.track_number);
                 let artist_cache = self.artists.lock().unwrap();
-                let artist = artist_cache.get(&a.artist_id).unwrap();
-                Ok(GetAlbumResp {
+                let artist = artist_cache.get(&a.artist_id)?;
+                Some(GetAlbumResp {
                     title: a.title.clone(),
                     artist_id: a.artist_id,
                     artist_name: artist.name.clone(),
                     tracks,
                 })
             }
-            None => todo!("fetch from server"),
+            None => None,
         }
     }
 
-    pub fn get_track(&self, id: i64) -> GetTrackResp {
+    pub fn get_track(&self, id: i64) -> Result<GetTrackResp> {
         let tracks = self.tracks.lock().unwrap();
         let artists = self.artists.lock().unwrap();
-        let albums = self.albums.lock().unwrap();
 
-        let track = tracks.get(&id).unwrap();
-        let artist = artists.get(&track.artist_id).unwrap();
-        let album = albums.get(&track.album_id).unwrap();
+        let track = tracks
+            .get(&id)
+            .ok_or_else(|| Error::NotFound(format!("track {id}")))?;
+        let artist = artists
+            .get(&track.artist_id)
+            .ok_or_else(|| Error::NotFound(format!("artist {}", track.artist_id)))?;
 
-        // TODO: really want to figure out a way to keep these sorted by track_number
-        let mut sorted_tracks = Vec::new();
-        for track_id in &album.track_ids {
-            let t = tracks.get(track_id).unwrap();
-            sorted_tracks.push((*track_id, t.track_number));
-        }
-        sorted_tracks.sort_by(|a, b| a.1.cmp(&b.1));
-        let next_track = match sorted_tracks.get(track.track_number as usize) {
-            Some(n) => Some(n.0),
-            None => None,
-        };
-
-        GetTrackResp {
+        Ok(GetTrackResp {
             title: track.title.clone(),
             artist_name: artist.name.clone(),
             cover_art_id: track.album_id,
-            next_track,
+            album_id: track.album_id,
+        })
+    }
+
+    /// loudness we measured for the track earlier, if any
+    pub fn track_loudness(&self, id: i64) -> Option<TrackLoudness> {
+        self.loudness.lock().unwrap().get(&id).copied()
+    }
+
+    pub fn save_track_loudness(&self, id: i64, loudness: TrackLoudness) {
+        let saved = self.db.write(|w| w.upsert(LOUDNESS, [(&id, &loudness)]));
+        if let Err(e) = saved {
+            eprintln!("couldn't save loudness for track {id}: {e}");
         }
+        self.loudness.lock().unwrap().insert(id, loudness);
+    }
+
+    /// returns the ids of every track on the album that `track_id` belongs
+    /// to, ordered by track number
+    pub fn get_album_track_ids(&self, track_id: i64) -> Result<Vec<i64>> {
+        let albums = self.albums.lock().unwrap();
+        let tracks = self.tracks.lock().unwrap();
+
+        let track = tracks
+            .get(&track_id)
+            .ok_or_else(|| Error::NotFound(format!("track {track_id}")))?;
+        let album = albums
+            .get(&track.album_id)
+            .ok_or_else(|| Error::NotFound(format!("album {}", track.album_id)))?;
+
+        // we can't play tracks we know nothing about, so leave them out
+        let mut sorted_tracks: Vec<_> = album
+            .track_ids
+            .iter()
+            .copied()
+            .filter(|id| tracks.contains_key(id))
+            .collect();
+        sorted_tracks.sort_by_key(|id| tracks[id].track_number);
+        Ok(sorted_tracks)
+    }
+}
+
+/// what a refresh changed, this gets sent to the frontend so it knows what
+/// to reload
+#[derive(Serialize, Clone)]
+pub struct LibraryChanges {
+    albums: ChangedIds,
+    artists: ChangedIds,
+    tracks: ChangedIds,
+}
+impl LibraryChanges {
+    pub fn is_empty(&self) -> bool {
+        self.albums.is_empty() && self.artists.is_empty() && self.tracks.is_empty()
+    }
+}
+#[derive(Serialize, Clone)]
+struct ChangedIds {
+    upserted: Vec<i64>,
+    deleted: Vec<i64>,
+}
+impl ChangedIds {
+    fn new<V>(upserted: &BTreeMap<i64, V>, deleted: Vec<i64>) -> Self {
+        Self {
+            upserted: upserted.keys().copied().collect(),
+            deleted,
+        }
+    }
+    /// rows that are new or different in `new` count as upserted,
+    /// everything only in `old` was deleted
+    fn between<V: PartialEq>(old: &BTreeMap<i64, V>, new: &BTreeMap<i64, V>) -> Self {
+        Self {
+            upserted: new
+                .iter()
+                .filter(|(id, row)| old.get(id) != Some(row))
+                .map(|(id, _)| *id)
+                .collect(),
+            deleted: old
+                .keys()
+                .filter(|id| !new.contains_key(id))
+                .copied()
+                .collect(),
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.deleted.is_empty()
     }
 }
 
@@ -208,5 +602,5 @@ pub struct GetTrackResp {
     pub title: String,
     pub artist_name: String,
     pub cover_art_id: i64,
-    pub next_track: Option<i64>,
+    pub album_id: i64,
 }
@@ -0,0 +1,272 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+use symphonia::core::audio::Channels;
+
+/// mixes a track's channels into however many channels the output device
+/// has, using the usual ITU-R BS.775 style coefficients
+pub struct ChannelMixer {
+    in_channels: usize,
+    out_channels: usize,
+    /// `out_channels` rows of `in_channels` gains
+    matrix: Vec<f32>,
+    /// the track's channels line up one to one with the device's
+    identity: bool,
+}
+impl ChannelMixer {
+    pub fn new(layout: Channels, out_channels: usize) -> Self {
+        let in_channels = layout.count();
+        let out_layout = device_layout(out_channels);
+
+        let mut matrix = vec![0.0; out_channels * in_channels];
+        if in_channels == 1 {
+            // mono files say they're front left, but they're really meant to
+            // come out of the middle
+            match out_layout.iter().position(|c| *c == Channels::FRONT_CENTRE) {
+                Some(o) => matrix[o * in_channels] = 1.0,
+                None => {
+                    for (o, c) in out_layout.iter().enumerate() {
+                        if *c == Channels::FRONT_LEFT || *c == Channels::FRONT_RIGHT {
+                            matrix[o * in_channels] = 1.0;
+                        }
+                    }
+                }
+            }
+        } else {
+            for (i, channel) in layout.iter().enumerate() {
+                for (o, gain) in route(channel, &out_layout, 0) {
+                    matrix[o * in_channels + i] += gain;
+                }
+            }
+        }
+
+        // downmixing can add several full scale channels together, so scale
+        // everything down until no output can go over full scale
+        let loudest = matrix
+            .chunks(in_channels)
+            .map(|row| row.iter().sum::<f32>())
+            .fold(0.0, f32::max);
+        if loudest > 1.0 {
+            matrix.iter_mut().for_each(|g| *g /= loudest);
+        }
+
+        let identity = in_channels == out_channels
+            && matrix.chunks(in_channels).enumerate().all(|(o, row)| {
+                row.iter()
+                    .enumerate()
+                    .all(|(i, g)| *g == if i == o { 1.0 } else { 0.0 })
+            });
+
+        Self {
+            in_channels,
+            out_channels,
+            matrix,
+            identity,
+        }
+    }
+
+    pub fn in_channels(&self) -> usize {
+        self.in_channels
+    }
+
+    pub fn out_channels(&self) -> usize {
+        self.out_channels
+    }
+
+    /// mixes `planes` (one per track channel, all the same length) and
+    /// appends the result to `out`, interleaved
+    pub fn mix_into<P: AsRef<[f32]>>(&self, planes: &[P], out: &mut Vec<f32>) {
+        let frames = planes.first().map_or(0, |p| p.as_ref().len());
+        out.reserve(frames * self.out_channels);
+        for f in 0..frames {
+            if self.identity {
+                out.extend(planes.iter().map(|p| p.as_ref()[f]));
+                continue;
+            }
+            for row in self.matrix.chunks(self.in_channels) {
+                out.push(row.iter().zip(planes).map(|(g, p)| g * p.as_ref()[f]).sum());
+            }
+        }
+    }
+}
+
+/// the speaker each of a device's channels goes to. cpal only tells us how
+/// many channels there are, so we assume the usual (WAVE) order
+fn device_layout(channels: usize) -> Vec<Channels> {
+    use Channels as C;
+    let mut layout = match channels {
+        1 => vec![C::FRONT_CENTRE],
+        2 => vec![C::FRONT_LEFT, C::FRONT_RIGHT],
+        3 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE],
+        4 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT],
+        5 => vec![
+            C::FRONT_LEFT,
+            C::FRONT_RIGHT,
+            C::FRONT_CENTRE,
+            C::REAR_LEFT,
+            C::REAR_RIGHT,
+        ],
+        6 => vec![
+            C::FRONT_LEFT,
+            C::FRONT_RIGHT,
+            C::FRONT_CENTRE,
+            C::LFE1,
+            C::REAR_LEFT,
+            C::REAR_RIGHT,
+        ],
+        7 => vec![
+            C::FRONT_LEFT,
+            C::FRONT_RIGHT,
+            C::FRONT_CENTRE,
+            C::LFE1,
+            C::REAR_CENTRE,
+            C::SIDE_LEFT,
+            C::SIDE_RIGHT,
+        ],
+        _ => vec![
+            C::FRONT_LEFT,
+            C::FRONT_RIGHT,
+            C::FRONT_CENTRE,
+            C::LFE1,
+            C::REAR_LEFT,
+            C::REAR_RIGHT,
+            C::SIDE_LEFT,
+            C::SIDE_RIGHT,
+        ],
+    };
+    // anything past what we know about stays silent
+    layout.resize(channels, Channels::empty());
+    layout
+}
+
+/// which of the device's channels `channel` ends up in, and how loud
+fn route(channel: Channels, out_layout: &[Channels], depth: u8) -> Vec<(usize, f32)> {
+    use Channels as C;
+    if let Some(o) = out_layout.iter().position(|c| *c == channel) {
+        return vec![(o, 1.0)];
+    }
+    // every layout has either a front left or a front centre, so this only
+    // guards against going round in circles
+    if depth > 3 {
+        return Vec::new();
+    }
+    let has = |c: Channels| out_layout.contains(&c);
+
+    let fallback: Vec<(Channels, f32)> = match channel {
+        C::FRONT_LEFT => vec![(C::FRONT_CENTRE, FRAC_1_SQRT_2)],
+        C::FRONT_RIGHT => vec![(C::FRONT_CENTRE, FRAC_1_SQRT_2)],
+        C::FRONT_CENTRE | C::FRONT_CENTRE_HIGH | C::TOP_FRONT_CENTRE | C::TOP_CENTRE => vec![
+            (C::FRONT_LEFT, FRAC_1_SQRT_2),
+            (C::FRONT_RIGHT, FRAC_1_SQRT_2),
+        ],
+        // most downmixes just leave the sub out
+        C::LFE1 | C::LFE2 => Vec::new(),
+        C::FRONT_LEFT_CENTRE | C::FRONT_LEFT_WIDE | C::FRONT_LEFT_HIGH | C::TOP_FRONT_LEFT => {
+            vec![(C::FRONT_LEFT, 1.0)]
+        }
+        C::FRONT_RIGHT_CENTRE | C::FRONT_RIGHT_WIDE | C::FRONT_RIGHT_HIGH | C::TOP_FRONT_RIGHT => {
+            vec![(C::FRONT_RIGHT, 1.0)]
+        }
+        C::SIDE_LEFT if has(C::REAR_LEFT) => vec![(C::REAR_LEFT, 1.0)],
+        C::REAR_LEFT if has(C::SIDE_LEFT) => vec![(C::SIDE_LEFT, 1.0)],
+        C::SIDE_LEFT | C::REAR_LEFT => vec![(C::FRONT_LEFT, FRAC_1_SQRT_2)],
+        C::SIDE_RIGHT if has(C::REAR_RIGHT) => vec![(C::REAR_RIGHT, 1.0)],
+        C::REAR_RIGHT if has(C::SIDE_RIGHT) => vec![(C::SIDE_RIGHT, 1.0)],
+        C::SIDE_RIGHT | C::REAR_RIGHT => vec![(C::FRONT_RIGHT, FRAC_1_SQRT_2)],
+        C::REAR_LEFT_CENTRE | C::TOP_REAR_LEFT => vec![(C::REAR_LEFT, 1.0)],
+        C::REAR_RIGHT_CENTRE | C::TOP_REAR_RIGHT => vec![(C::REAR_RIGHT, 1.0)],
+        C::REAR_CENTRE | C::TOP_REAR_CENTRE => vec![
+            (C::REAR_LEFT, FRAC_1_SQRT_2),
+            (C::REAR_RIGHT, FRAC_1_SQRT_2),
+        ],
+        _ => Vec::new(),
+    };
+
+    fallback
+        .into_iter()
+        .flat_map(|(c, gain)| {
+            route(c, out_layout, depth + 1)
+                .into_iter()
+                .map(move |(o, g)| (o, g * gain))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: Channels = Channels::FRONT_LEFT.union(Channels::FRONT_RIGHT);
+
+    fn surround() -> Channels {
+        STEREO
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+    }
+
+    fn assert_matrix(mixer: &ChannelMixer, expected: &[&[f32]]) {
+        let rows: Vec<_> = mixer.matrix.chunks(mixer.in_channels).collect();
+        assert_eq!(rows.len(), expected.len());
+        for (row, expected) in rows.iter().zip(expected) {
+            assert_eq!(row.len(), expected.len());
+            for (g, e) in row.iter().zip(*expected) {
+                assert!((g - e).abs() < 1e-6, "{rows:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn mono_comes_out_of_both_sides() {
+        let mixer = ChannelMixer::new(Channels::FRONT_LEFT, 2);
+        assert_matrix(&mixer, &[&[1.0], &[1.0]]);
+        assert!(!mixer.identity);
+    }
+
+    #[test]
+    fn stereo_to_mono_halves_each_side() {
+        let mixer = ChannelMixer::new(STEREO, 1);
+        assert_matrix(&mixer, &[&[0.5, 0.5]]);
+
+        // full scale on both sides stays at full scale
+        let mut out = Vec::new();
+        mixer.mix_into(&[[1.0], [1.0]], &mut out);
+        assert_eq!(out, [1.0]);
+    }
+
+    #[test]
+    fn surround_to_stereo_leaves_the_sub_out_and_doesnt_clip() {
+        let mixer = ChannelMixer::new(surround(), 2);
+        // front, centre and rear all go into their side at -3 dB relative to
+        // the front, then the whole lot is scaled so a side adds up to 1
+        let front = 1.0 / (1.0 + 2.0 * FRAC_1_SQRT_2);
+        let other = FRAC_1_SQRT_2 * front;
+        assert_matrix(
+            &mixer,
+            &[
+                &[front, 0.0, other, 0.0, other, 0.0],
+                &[0.0, front, other, 0.0, 0.0, other],
+            ],
+        );
+
+        let mut out = Vec::new();
+        mixer.mix_into(&[[1.0]; 6], &mut out);
+        for s in out {
+            assert!(s <= 1.0 + 1e-6, "{s}");
+        }
+    }
+
+    #[test]
+    fn matching_layouts_pass_straight_through() {
+        for (layout, channels) in [(STEREO, 2), (surround(), 6)] {
+            let mixer = ChannelMixer::new(layout, channels);
+            assert!(mixer.identity);
+
+            let planes: Vec<_> = (0..channels).map(|c| [c as f32 * 0.1]).collect();
+            let mut out = Vec::new();
+            mixer.mix_into(&planes, &mut out);
+            let expected: Vec<_> = (0..channels).map(|c| c as f32 * 0.1).collect();
+            assert_eq!(out, expected);
+        }
+    }
+}
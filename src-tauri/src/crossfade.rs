@@ -0,0 +1,108 @@
+use std::{
+    f32::consts::FRAC_PI_2,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// the shape of the gain ramps used when crossfading between tracks
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    /// keeps the combined power roughly constant, so there's no dip in the
+    /// middle of the fade
+    EqualPower,
+}
+impl FadeCurve {
+    /// gain for a track that is `t` (0..=1) of the way through fading in
+    pub fn gain(self, t: f32) -> f32 {
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EqualPower => (t * FRAC_PI_2).sin(),
+        }
+    }
+}
+
+/// crossfading as the user set it up
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct CrossfadeSettings {
+    /// in seconds, 0 turns crossfading off
+    pub duration: f64,
+    pub curve: FadeCurve,
+}
+
+/// crossfade settings shared between the main stream and its handle, a
+/// duration of 0 turns crossfading off
+#[derive(Default)]
+pub struct CrossfadeState {
+    duration_ms: AtomicU32,
+    curve: AtomicU8,
+}
+impl CrossfadeState {
+    pub fn set(&self, duration_ms: u32, curve: FadeCurve) {
+        self.curve.store(curve as u8, Ordering::Release);
+        self.duration_ms.store(duration_ms, Ordering::Release);
+    }
+    pub fn curve(&self) -> FadeCurve {
+        match self.curve.load(Ordering::Acquire) {
+            c if c == FadeCurve::EqualPower as u8 => FadeCurve::EqualPower,
+            _ => FadeCurve::Linear,
+        }
+    }
+    /// how many interleaved samples a fade lasts at `out_rate`
+    pub fn samples(&self, out_rate: u32, channels: usize) -> u64 {
+        self.duration_ms.load(Ordering::Acquire) as u64 * out_rate as u64 / 1000 * channels as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [FadeCurve; 2] = [FadeCurve::Linear, FadeCurve::EqualPower];
+
+    fn steps() -> impl Iterator<Item = f32> {
+        (0..=100).map(|i| i as f32 / 100.0)
+    }
+
+    #[test]
+    fn fades_go_from_silent_to_full_scale() {
+        for curve in CURVES {
+            assert_eq!(curve.gain(0.0), 0.0);
+            assert!((curve.gain(1.0) - 1.0).abs() < 1e-6, "{curve:?}");
+            for t in steps() {
+                assert!(
+                    curve.gain(t) <= curve.gain((t + 0.01).min(1.0)),
+                    "{curve:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn linear_fades_add_up_to_full_scale() {
+        for t in steps() {
+            let sum = FadeCurve::Linear.gain(t) + FadeCurve::Linear.gain(1.0 - t);
+            assert!((sum - 1.0).abs() < 1e-6, "{t}: {sum}");
+        }
+    }
+
+    #[test]
+    fn equal_power_fades_keep_the_power_constant() {
+        let curve = FadeCurve::EqualPower;
+        for t in steps() {
+            let power = curve.gain(t).powi(2) + curve.gain(1.0 - t).powi(2);
+            assert!((power - 1.0).abs() < 1e-6, "{t}: {power}");
+        }
+    }
+
+    #[test]
+    fn state_hands_back_what_was_set() {
+        let state = CrossfadeState::default();
+        assert_eq!(state.samples(48000, 2), 0);
+        state.set(1500, FadeCurve::EqualPower);
+        assert_eq!(state.curve(), FadeCurve::EqualPower);
+        assert_eq!(state.samples(48000, 2), 144_000);
+    }
+}
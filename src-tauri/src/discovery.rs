@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use tauri::{async_runtime::spawn, AppHandle, Emitter, Runtime};
+
+/// the dns-sd service type pi-fi servers advertise themselves as
+const SERVICE_TYPE: &str = "_pi-fi._tcp.local.";
+
+/// a server we found on the local network
+#[derive(Serialize, Clone)]
+pub struct DiscoveredServer {
+    pub name: String,
+    pub url: String,
+}
+
+/// browses the local network for pi-fi servers for as long as it's alive,
+/// the frontend gets a "servers-discovered" event with the full list every
+/// time it changes
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    servers: Arc<Mutex<BTreeMap<String, DiscoveredServer>>>,
+}
+impl Discovery {
+    pub fn start<R: Runtime>(app: AppHandle<R>) -> Result<Self, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+        // so a server running on this machine shows up too
+        daemon.enable_interface(IfKind::LoopbackV4)?;
+        let events = daemon.browse(SERVICE_TYPE)?;
+        let servers = Arc::new(Mutex::new(BTreeMap::new()));
+
+        let found = servers.clone();
+        spawn(async move {
+            // this ends once the daemon shuts down
+            while let Ok(event) = events.recv_async().await {
+                let changed = {
+                    let mut servers = found.lock().unwrap();
+                    apply_event(&mut servers, event)
+                };
+                if changed {
+                    let servers: Vec<_> = found.lock().unwrap().values().cloned().collect();
+                    if let Err(e) = app.emit("servers-discovered", servers) {
+                        eprintln!("couldn't announce discovered servers: {e}");
+                    }
+                }
+            }
+        });
+
+        Ok(Self { daemon, servers })
+    }
+
+    pub fn servers(&self) -> Vec<DiscoveredServer> {
+        self.servers.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn server(&self, name: &str) -> Option<DiscoveredServer> {
+        self.servers
+            .lock()
+            .unwrap()
+            .values()
+            .find(|s| s.name == name)
+            .cloned()
+    }
+}
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// updates `servers` (keyed by the service's full name), returns whether
+/// anything changed
+fn apply_event(servers: &mut BTreeMap<String, DiscoveredServer>, event: ServiceEvent) -> bool {
+    match event {
+        ServiceEvent::ServiceResolved(info) => match discovered_server(&info) {
+            Some(server) => {
+                servers.insert(info.get_fullname().into(), server);
+                true
+            }
+            None => false,
+        },
+        ServiceEvent::ServiceRemoved(_, fullname) => servers.remove(&fullname).is_some(),
+        _ => false,
+    }
+}
+
+fn discovered_server(info: &ServiceInfo) -> Option<DiscoveredServer> {
+    // prefer ipv4, link-local ipv6 addresses need a scope id we can't put in
+    // a url so they're no good at all
+    let addrs = info.get_addresses();
+    let addr = addrs.iter().find(|a| a.is_ipv4()).or_else(|| {
+        addrs.iter().find(|a| match a {
+            IpAddr::V6(a) => !a.is_unicast_link_local(),
+            IpAddr::V4(_) => false,
+        })
+    })?;
+    let host = match addr {
+        IpAddr::V4(a) => a.to_string(),
+        IpAddr::V6(a) => format!("[{a}]"),
+    };
+    let name = info
+        .get_fullname()
+        .trim_end_matches(info.get_type())
+        .trim_end_matches('.');
+
+    Some(DiscoveredServer {
+        name: name.into(),
+        url: format!("http://{host}:{}", info.get_port()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use tauri::test::mock_app;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    fn service(name: &str, addrs: &str) -> ServiceInfo {
+        let props: HashMap<String, String> = HashMap::new();
+        ServiceInfo::new(SERVICE_TYPE, name, "pi-fi.local.", addrs, 8080, props).unwrap()
+    }
+
+    #[test]
+    fn servers_are_found_at_an_address_we_can_use() {
+        let server = discovered_server(&service("Living Room", "fe80::1,10.0.0.2")).unwrap();
+        assert_eq!(server.name, "Living Room");
+        assert_eq!(server.url, "http://10.0.0.2:8080");
+
+        let server = discovered_server(&service("Kitchen", "fe80::1,2001:db8::2")).unwrap();
+        assert_eq!(server.url, "http://[2001:db8::2]:8080");
+
+        assert!(discovered_server(&service("Attic", "fe80::1")).is_none());
+    }
+
+    #[test]
+    fn events_update_the_servers() {
+        let mut servers = BTreeMap::new();
+        let fullname = format!("Living Room.{SERVICE_TYPE}");
+
+        let resolved = ServiceEvent::ServiceResolved(service("Living Room", "10.0.0.2"));
+        assert!(apply_event(&mut servers, resolved));
+        assert_eq!(servers[&fullname].url, "http://10.0.0.2:8080");
+
+        let unusable = ServiceEvent::ServiceResolved(service("Attic", "fe80::1"));
+        assert!(!apply_event(&mut servers, unusable));
+        let searching = ServiceEvent::SearchStarted(SERVICE_TYPE.into());
+        assert!(!apply_event(&mut servers, searching));
+
+        let removed = ServiceEvent::ServiceRemoved(SERVICE_TYPE.into(), fullname.clone());
+        assert!(apply_event(&mut servers, removed));
+        assert!(servers.is_empty());
+        let removed = ServiceEvent::ServiceRemoved(SERVICE_TYPE.into(), fullname);
+        assert!(!apply_event(&mut servers, removed));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finds_a_server_on_this_machine() {
+        let responder = ServiceDaemon::new().unwrap();
+        responder.enable_interface(IfKind::LoopbackV4).unwrap();
+        responder
+            .register(service("Test Server", "127.0.0.1"))
+            .unwrap();
+
+        let app = mock_app();
+        let discovery = Discovery::start(app.handle().clone()).unwrap();
+        for _ in 0..100 {
+            if let Some(server) = discovery.server("Test Server") {
+                assert_eq!(server.url, "http://127.0.0.1:8080");
+                let _ = responder.shutdown();
+                return;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("didn't find the server");
+    }
+}
@@ -0,0 +1,411 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    biquad::Biquad,
+    error::{Error, Result},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BandKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+}
+
+/// one filter in the eq
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub kind: BandKind,
+    /// the center of a peaking band, or the corner of a shelf
+    pub frequency: f32,
+    pub gain_db: f32,
+    /// how wide the band is, higher is narrower
+    pub q: f32,
+}
+impl EqBand {
+    fn filter(&self, rate: u32) -> Biquad {
+        let (rate, frequency, q, gain_db) = (
+            rate as f64,
+            self.frequency as f64,
+            self.q as f64,
+            self.gain_db as f64,
+        );
+        match self.kind {
+            BandKind::Peaking => Biquad::peaking(rate, frequency, q, gain_db),
+            BandKind::LowShelf => Biquad::low_shelf(rate, frequency, q, gain_db),
+            BandKind::HighShelf => Biquad::high_shelf(rate, frequency, q, gain_db),
+        }
+    }
+}
+
+/// a set of bands, either the one that's playing or a saved preset
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct EqCurve {
+    /// applied before the bands, so boosting doesn't have to clip
+    pub preamp_db: f32,
+    pub bands: Vec<EqBand>,
+}
+impl EqCurve {
+    /// a flat curve doesn't change anything, so it isn't run at all
+    pub fn is_flat(&self) -> bool {
+        self.preamp_db == 0.0 && self.bands.iter().all(|b| b.gain_db == 0.0)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |what: &str| Err(Error::InvalidSettings(what.into()));
+        if !self.preamp_db.is_finite() {
+            return invalid("eq preamp has to be a number");
+        }
+        for band in &self.bands {
+            if !(band.frequency.is_finite() && band.frequency > 0.0) {
+                return invalid("eq band frequencies have to be above 0");
+            }
+            if !(band.q.is_finite() && band.q > 0.0) {
+                return invalid("eq band q has to be above 0");
+            }
+            if !band.gain_db.is_finite() {
+                return invalid("eq band gain has to be a number");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EqPreset {
+    pub name: String,
+    pub curve: EqCurve,
+}
+
+/// the eq as it's saved in settings
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    /// the curve that's in use (when enabled)
+    pub curve: EqCurve,
+    pub presets: Vec<EqPreset>,
+}
+impl EqualizerSettings {
+    /// what should actually be playing, turning the eq off is the same as
+    /// making it flat
+    pub fn active_curve(&self) -> EqCurve {
+        match self.enabled {
+            true => self.curve.clone(),
+            false => EqCurve::default(),
+        }
+    }
+
+    fn preset(&self, name: &str) -> Result<&EqPreset> {
+        self.presets
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| Error::NotFound(format!("eq preset \"{name}\"")))
+    }
+
+    /// saves the current curve as a preset, replacing the one with the same
+    /// name
+    pub fn save_preset(&mut self, name: String) -> Result<()> {
+        if name.is_empty() {
+            return Err(Error::InvalidSettings("eq preset needs a name".into()));
+        }
+        let curve = self.curve.clone();
+        match self.presets.iter_mut().find(|p| p.name == name) {
+            Some(p) => p.curve = curve,
+            None => self.presets.push(EqPreset { name, curve }),
+        }
+        Ok(())
+    }
+
+    pub fn remove_preset(&mut self, name: &str) -> Result<()> {
+        self.preset(name)?;
+        self.presets.retain(|p| p.name != name);
+        Ok(())
+    }
+
+    /// makes a preset the current curve
+    pub fn load_preset(&mut self, name: &str) -> Result<()> {
+        self.curve = self.preset(name)?.curve.clone();
+        Ok(())
+    }
+}
+
+/// hands new curves over to the main stream without it ever having to wait
+/// on a lock. the filters are built here, off the output callback, and the
+/// ones they replace get dropped here too
+#[derive(Default)]
+pub struct EqUpdates {
+    changed: AtomicBool,
+    inner: Mutex<EqUpdatesInner>,
+}
+#[derive(Default)]
+struct EqUpdatesInner {
+    /// the latest curve that was sent
+    curve: EqCurve,
+    /// what the output is running at, the filters are built for this
+    rate: u32,
+    channels: usize,
+    /// the eq for the latest curve, waiting for the main stream to pick it
+    /// up. `Some(None)` turns the eq off
+    pending: Option<Option<Equalizer>>,
+    /// the eq the main stream swapped out, dropped on the next `send`
+    retired: Option<Equalizer>,
+}
+impl EqUpdates {
+    pub fn send(&self, curve: EqCurve) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.retired = None;
+        inner.pending = Some(Equalizer::new(&curve, inner.rate, inner.channels));
+        inner.curve = curve;
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// swaps `eq` for the one built for the latest curve, if there's been
+    /// one since the last call. the filters pick up where the old ones left
+    /// off, and the old ones are kept to be dropped off the output callback.
+    /// if `send` is in the middle of things nothing happens and the curve
+    /// gets picked up next time
+    pub fn swap(&self, eq: &mut Option<Equalizer>) {
+        if !self.changed.load(Ordering::Acquire) {
+            return;
+        }
+        let Ok(mut inner) = self.inner.try_lock() else {
+            return;
+        };
+        self.changed.store(false, Ordering::Release);
+        let Some(mut new) = inner.pending.take() else {
+            return;
+        };
+        if let (Some(new), Some(old)) = (new.as_mut(), eq.as_ref()) {
+            new.carry_over(old);
+        }
+        inner.retired = std::mem::replace(eq, new);
+    }
+
+    /// the eq for the latest curve, built for an output that's (re)starting
+    /// at `rate` with `channels`. curves sent from now on are built for that
+    /// too. this mustn't be called from the output callback
+    pub fn rebuild(&self, rate: u32, channels: usize) -> Option<Equalizer> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rate = rate;
+        inner.channels = channels;
+        inner.pending = None;
+        self.changed.store(false, Ordering::Release);
+        Equalizer::new(&inner.curve, rate, channels)
+    }
+}
+
+/// runs a curve over the mix, with filters worked out for the rate and
+/// channel count the output is at
+pub struct Equalizer {
+    preamp: f32,
+    /// the preamp of the eq this took over from, it's ramped from that over
+    /// the first buffer
+    preamp_from: Option<f32>,
+    /// one filter per band, for every channel
+    filters: Vec<Vec<Biquad>>,
+}
+impl Equalizer {
+    /// `None` for a flat curve, there's nothing to do
+    fn new(curve: &EqCurve, rate: u32, channels: usize) -> Option<Self> {
+        if curve.is_flat() || rate == 0 {
+            return None;
+        }
+        let bands: Vec<_> = curve.bands.iter().map(|b| b.filter(rate)).collect();
+        Some(Self {
+            preamp: 10f32.powf(curve.preamp_db / 20.0),
+            preamp_from: None,
+            filters: vec![bands; channels],
+        })
+    }
+
+    /// carries on from where `old` got to, so changing the curve doesn't
+    /// restart the filters from silence and click
+    fn carry_over(&mut self, old: &Equalizer) {
+        for (new, old) in self.filters.iter_mut().zip(&old.filters) {
+            for (new, old) in new.iter_mut().zip(old) {
+                new.carry_over(old);
+            }
+        }
+        self.preamp_from = Some(old.preamp);
+    }
+
+    /// `buf` is interleaved, `channels` per frame
+    pub fn process(&mut self, buf: &mut [f32], channels: usize) {
+        let frames = (buf.len() / channels).max(1) as f32;
+        let from = self.preamp_from.take().unwrap_or(self.preamp);
+        for (i, frame) in buf.chunks_mut(channels).enumerate() {
+            let preamp = from + (self.preamp - from) * (i + 1) as f32 / frames;
+            for (s, filters) in frame.iter_mut().zip(&mut self.filters) {
+                let mut x = (*s * preamp) as f64;
+                for filter in filters.iter_mut() {
+                    x = filter.process(x);
+                }
+                *s = x as f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    fn curve(gain_db: f32) -> EqCurve {
+        EqCurve {
+            preamp_db: -gain_db.max(0.0),
+            bands: vec![
+                EqBand {
+                    kind: BandKind::LowShelf,
+                    frequency: 100.0,
+                    gain_db,
+                    q: 0.707,
+                },
+                EqBand {
+                    kind: BandKind::Peaking,
+                    frequency: 1000.0,
+                    gain_db: -gain_db,
+                    q: 1.4,
+                },
+            ],
+        }
+    }
+
+    /// a different sine on each channel, interleaved
+    fn stereo_sine(frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f32 / 48000.0;
+                [(TAU * 80.0 * t).sin() * 0.5, (TAU * 900.0 * t).sin() * 0.5]
+            })
+            .collect()
+    }
+
+    fn settings() -> EqualizerSettings {
+        EqualizerSettings {
+            enabled: true,
+            curve: curve(6.0),
+            presets: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn presets_are_saved_over_by_name() {
+        let mut settings = settings();
+        settings.save_preset("bass".into()).unwrap();
+        settings.curve = curve(3.0);
+        settings.save_preset("bass".into()).unwrap();
+        settings.save_preset("other".into()).unwrap();
+
+        assert_eq!(settings.presets.len(), 2);
+        assert!(settings.presets[0].name == "bass" && settings.presets[0].curve == curve(3.0));
+        assert!(matches!(
+            settings.save_preset(String::new()),
+            Err(Error::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn loading_a_preset_makes_it_the_curve() {
+        let mut settings = settings();
+        settings.save_preset("bass".into()).unwrap();
+        settings.curve = EqCurve::default();
+        settings.load_preset("bass").unwrap();
+
+        assert!(settings.curve == curve(6.0));
+        assert!(matches!(
+            settings.load_preset("nope"),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn presets_can_be_removed() {
+        let mut settings = settings();
+        settings.save_preset("bass".into()).unwrap();
+        settings.remove_preset("bass").unwrap();
+
+        assert!(settings.presets.is_empty());
+        assert!(matches!(
+            settings.remove_preset("bass"),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn flat_curves_turn_the_eq_off() {
+        let updates = EqUpdates::default();
+        let mut eq = updates.rebuild(48000, 2);
+        updates.send(curve(6.0));
+        updates.swap(&mut eq);
+        assert!(eq.is_some());
+        updates.send(curve(0.0));
+        updates.swap(&mut eq);
+        assert!(eq.is_none());
+    }
+
+    #[test]
+    fn filters_carry_on_across_curve_changes() {
+        let updates = EqUpdates::default();
+        updates.rebuild(48000, 2);
+        updates.send(curve(6.0));
+        let input = stereo_sine(4800);
+        let (first, second) = input.split_at(2400);
+
+        let mut whole = input.clone();
+        let mut eq = None;
+        updates.swap(&mut eq);
+        eq.as_mut().unwrap().process(&mut whole, 2);
+
+        // the same curve again partway through should come out exactly the
+        // same as if nothing had changed
+        let mut eq = None;
+        updates.send(curve(6.0));
+        updates.swap(&mut eq);
+        let mut changed = first.to_vec();
+        eq.as_mut().unwrap().process(&mut changed, 2);
+        updates.send(curve(6.0));
+        updates.swap(&mut eq);
+        let mut rest = second.to_vec();
+        eq.as_mut().unwrap().process(&mut rest, 2);
+        changed.extend(rest);
+
+        assert_eq!(changed, whole);
+    }
+
+    #[test]
+    fn small_changes_dont_click() {
+        let updates = EqUpdates::default();
+        updates.rebuild(48000, 2);
+        updates.send(curve(6.0));
+        let mut eq = None;
+        updates.swap(&mut eq);
+
+        let input = stereo_sine(4800);
+        let mut out = Vec::new();
+        for (i, chunk) in input.chunks(2 * 240).enumerate() {
+            // like dragging a slider, a little more every buffer
+            updates.send(curve(6.0 + i as f32 * 0.25));
+            updates.swap(&mut eq);
+            let mut chunk = chunk.to_vec();
+            eq.as_mut().unwrap().process(&mut chunk, 2);
+            out.extend(chunk);
+        }
+
+        // the loudest a 0.5 sine at 900Hz moves between samples, with
+        // plenty of room for the filters changing. a click would jump
+        // a lot further
+        for channel in 0..2 {
+            let samples: Vec<_> = out.iter().skip(channel).step_by(2).collect();
+            for pair in samples.windows(2) {
+                assert!((pair[1] - pair[0]).abs() < 0.15, "{pair:?}");
+            }
+        }
+    }
+}
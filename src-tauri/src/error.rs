@@ -0,0 +1,102 @@
+use serde::Serialize;
+use symphonia::core::errors::Error as SymphoniaError;
+use tauri_plugin_http::reqwest::{self, StatusCode};
+
+use crate::library_db::DbError;
+
+/// everything that can go wrong that the frontend cares about, this gets
+/// serialized as `{ kind, message }` so it can tell e.g. "server offline"
+/// apart from "that album doesn't exist"
+#[derive(Debug, Clone, Serialize, thiserror::Error)]
+#[serde(tag = "kind", content = "message")]
+pub enum Error {
+    /// couldn't reach the server at all
+    #[error("network error: {0}")]
+    Network(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// the server wants us to log in (again)
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("couldn't decode track: {0}")]
+    Decode(String),
+    #[error("audio device error: {0}")]
+    AudioDevice(String),
+    /// the server answered, but not with anything we understand
+    #[error("unexpected response from server: {0}")]
+    ServerProtocol(String),
+    /// couldn't read or write something in the app data dir
+    #[error("storage error: {0}")]
+    Storage(String),
+    #[error("invalid settings: {0}")]
+    InvalidSettings(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(StatusCode::NOT_FOUND) => Error::NotFound(e.to_string()),
+            Some(StatusCode::UNAUTHORIZED) => Error::Unauthorized(e.to_string()),
+            Some(_) => Error::ServerProtocol(e.to_string()),
+            None if e.is_decode() => Error::ServerProtocol(e.to_string()),
+            None => Error::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<SymphoniaError> for Error {
+    fn from(e: SymphoniaError) -> Self {
+        // the http source hands symphonia our own errors wrapped up as io
+        // errors, those come back out as they were
+        if let SymphoniaError::IoError(io) = &e {
+            if let Some(inner) = io.get_ref().and_then(|i| i.downcast_ref::<Error>()) {
+                return inner.clone();
+            }
+        }
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<cpal::BuildStreamError> for Error {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        Error::AudioDevice(e.to_string())
+    }
+}
+
+impl From<cpal::PlayStreamError> for Error {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        Error::AudioDevice(e.to_string())
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for Error {
+    fn from(e: cpal::DefaultStreamConfigError) -> Self {
+        Error::AudioDevice(e.to_string())
+    }
+}
+
+impl From<cpal::DevicesError> for Error {
+    fn from(e: cpal::DevicesError) -> Self {
+        Error::AudioDevice(e.to_string())
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for Error {
+    fn from(e: cpal::SupportedStreamConfigsError) -> Self {
+        Error::AudioDevice(e.to_string())
+    }
+}
+
+impl From<cpal::DeviceNameError> for Error {
+    fn from(e: cpal::DeviceNameError) -> Self {
+        Error::AudioDevice(e.to_string())
+    }
+}
+
+impl From<DbError> for Error {
+    fn from(e: DbError) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use bytes::{Buf, Bytes};
+use symphonia::core::io::MediaSource;
+use tauri::async_runtime::{block_on, spawn};
+use tauri_plugin_http::reqwest::{
+    header::{ACCEPT_RANGES, RANGE},
+    Response, StatusCode,
+};
+use tokio::{sync::mpsc, task::block_in_place};
+
+use crate::{
+    cache::Cache,
+    error::{self, Error},
+};
+
+/// how many body chunks the download task is allowed to get ahead of the
+/// decoder
+const CHUNK_BUFFER: usize = 64;
+
+/// a symphonia media source that reads from a streamed http response body,
+/// so decoding can start as soon as the first bytes come in instead of
+/// waiting for the whole file
+///
+/// seeking drops the current download and starts a new one from the target
+/// offset with a `Range` request, using whatever credentials `cache` has by
+/// then. a download that breaks off partway is picked back up the same way,
+/// the next track's download sits idle for as long as the current track
+/// plays, which is long enough for a server or proxy to hang up on it
+pub struct HttpSource {
+    cache: Arc<Cache>,
+    url: String,
+    chunks: mpsc::Receiver<Result<Bytes, io::Error>>,
+    current: Bytes,
+    pos: u64,
+    len: Option<u64>,
+    seekable: bool,
+    /// set when the download was picked back up after breaking off, until
+    /// it brings in something. if it breaks off again before then we give up
+    resumed: bool,
+}
+impl HttpSource {
+    pub async fn new(cache: Arc<Cache>, url: &str) -> error::Result<Self> {
+        let resp = cache.client().get(url).send().await?.error_for_status()?;
+        let len = resp.content_length();
+        let seekable = len.is_some()
+            && resp
+                .headers()
+                .get(ACCEPT_RANGES)
+                .is_some_and(|r| r.as_bytes() == b"bytes");
+
+        Ok(Self {
+            cache,
+            url: url.into(),
+            chunks: start_download(resp),
+            current: Bytes::new(),
+            pos: 0,
+            len,
+            seekable,
+            resumed: false,
+        })
+    }
+
+    /// blocks until the server starts answering a request for everything from
+    /// `start` onwards
+    fn request_from(&self, start: u64) -> io::Result<Response> {
+        let req = self
+            .cache
+            .client()
+            .get(&self.url)
+            .header(RANGE, format!("bytes={start}-"));
+        // our own error goes through symphonia inside the io error, so the
+        // player can still tell e.g. a revoked token from a broken file
+        let resp = block_in_place(|| block_on(req.send()))
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| io::Error::other(Error::from(e)))?;
+        match resp.status() {
+            StatusCode::PARTIAL_CONTENT => Ok(resp),
+            status => Err(io::Error::other(Error::ServerProtocol(format!(
+                "expected partial content for range request, got {status}"
+            )))),
+        }
+    }
+}
+
+fn start_download(resp: Response) -> mpsc::Receiver<Result<Bytes, io::Error>> {
+    let (send, chunks) = mpsc::channel(CHUNK_BUFFER);
+    spawn(download(resp, send));
+    chunks
+}
+
+/// pulls chunks off of the response body and hands them to the source, this
+/// stops on its own once the source is dropped or seeks somewhere else
+async fn download(mut resp: Response, send: mpsc::Sender<Result<Bytes, io::Error>>) {
+    loop {
+        let chunk = match resp.chunk().await {
+            Ok(Some(c)) => Ok(c),
+            Ok(None) => return,
+            Err(e) => Err(io::Error::other(Error::from(e))),
+        };
+        let failed = chunk.is_err();
+        if send.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.current.is_empty() {
+            // the decoder runs on the async runtime, so we have to let it
+            // know we're about to block while we wait for the network
+            match block_in_place(|| self.chunks.blocking_recv()) {
+                Some(Ok(c)) => {
+                    self.current = c;
+                    self.resumed = false;
+                }
+                Some(Err(e)) if self.resumed || !self.seekable => return Err(e),
+                Some(Err(e)) => {
+                    eprintln!("track download broke off, picking it back up: {e}");
+                    self.chunks = start_download(self.request_from(self.pos)?);
+                    self.resumed = true;
+                }
+                // download is done
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.current.len());
+        buf[..n].copy_from_slice(&self.current[..n]);
+        self.current.advance(n);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => match self.len {
+                Some(len) => len.checked_add_signed(n),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "can't seek from the end of a source with no length",
+                    ))
+                }
+            },
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+
+        if target == self.pos {
+            return Ok(target);
+        }
+
+        // short forward seeks can be served from what we've already got
+        if target > self.pos && target - self.pos <= self.current.len() as u64 {
+            self.current.advance((target - self.pos) as usize);
+            self.pos = target;
+            return Ok(target);
+        }
+
+        if !self.seekable {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "server does not support range requests",
+            ));
+        }
+
+        // this drops the old receiver, which stops the old download
+        self.chunks = match self.len {
+            Some(len) if target >= len => mpsc::channel(1).1,
+            _ => start_download(self.request_from(target)?),
+        };
+        self.current = Bytes::new();
+        self.pos = target;
+        self.resumed = false;
+        Ok(target)
+    }
+}
+
+impl MediaSource for HttpSource {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+    fn byte_len(&self) -> Option<u64> {
+        self.len
+    }
+}
@@ -1,93 +1,567 @@
+mod auth;
+mod biquad;
 pub mod cache;
+mod channel_mix;
+mod crossfade;
+mod discovery;
+mod equalizer;
+pub mod error;
+mod http_source;
+mod library_db;
+mod loudness;
 mod main_stream;
+mod meter;
+#[cfg(test)]
+mod mock_server;
+#[cfg(test)]
+mod null_output;
 pub mod player;
+mod replay_gain;
+mod resample;
+mod settings;
+mod stereo;
+mod volume;
 
+use auth::{build_client, notify_unauthorized, Credentials};
 use cache::{Cache, GetAlbumResp, LibraryData};
-use main_stream::{init_main_stream, MainStreamHandle};
+use crossfade::{CrossfadeSettings, FadeCurve};
+use discovery::{DiscoveredServer, Discovery};
+use equalizer::EqCurve;
+use error::{Error, Result};
+use main_stream::{init_main_stream, output_devices, MainStreamHandle, OutputDevice};
 use player::{Player, PlayerUpdateMsg};
+use replay_gain::ReplayGainSettings;
+use settings::{library_path, ServerProfile, Settings};
+use stereo::StereoSettings;
 
-use std::sync::{Arc, Mutex};
+use library_db::LibraryDb;
 
-use tauri::{ipc::Channel, Manager, State};
-use tauri_plugin_http::reqwest::Client;
+use std::{
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-// const SERVER_URL: &'static str = "http://192.168.50.68:8080";
-const SERVER_URL: &'static str = "http://localhost:8080";
+use tauri::{
+    async_runtime::{spawn, spawn_blocking},
+    ipc::{Channel, Response},
+    AppHandle, Emitter, Manager, State,
+};
 
 struct Systems {
-    cache: Arc<Cache>,
+    cache: Mutex<Arc<Cache>>,
     player: Mutex<Option<Player>>,
-    client: Client,
+    credentials: Credentials,
     handle: Mutex<Option<MainStreamHandle>>,
+    settings: Mutex<Settings>,
+    /// `None` if we couldn't start browsing the network
+    discovery: Option<Discovery>,
+    /// what went wrong while starting up that we worked around, so the
+    /// frontend can say so
+    startup_errors: Vec<Error>,
+    data_dir: PathBuf,
+    app: AppHandle,
 }
 impl Systems {
-    pub fn new(handle: MainStreamHandle) -> Self {
-        let client = Client::new();
-        let cache = Arc::new(Cache::new(client.clone()));
+    pub fn new(data_dir: &Path, app: AppHandle) -> Result<Self> {
+        let credentials = Credentials::load(&data_dir.join("credentials.json"));
+        let mut settings = Settings::load(&data_dir.join("settings.json"));
 
-        Self {
-            client,
-            cache,
+        // the output waits for a device if there isn't one, this only fails
+        // if its thread can't start. we can still browse the library without
+        // it, the player just won't set up
+        let handle = init_main_stream(settings.output_device.clone())
+            .inspect_err(|e| eprintln!("couldn't open audio output: {e}"))
+            .ok();
+
+        let mut startup_errors = Vec::new();
+        let url = match settings.active_server() {
+            Ok(server) => server.url.clone(),
+            Err(e) => {
+                eprintln!("couldn't find the active server, using the first one: {e}");
+                startup_errors.push(e);
+                settings.reset_active_server().url.clone()
+            }
+        };
+        // e.g. another copy of the app has the library open
+        let cache = match open_cache(&credentials, data_dir, url.clone()) {
+            Ok(cache) => cache,
+            Err(e) => {
+                eprintln!("couldn't open saved library, starting without it: {e}");
+                startup_errors.push(e);
+                let client = build_client(credentials.token(&url).as_deref())?;
+                Arc::new(Cache::new(client, LibraryDb::in_memory()?, url))
+            }
+        };
+        refresh_in_background(cache.clone(), app.clone());
+
+        let discovery = Discovery::start(app.clone())
+            .inspect_err(|e| eprintln!("couldn't start server discovery: {e}"))
+            .ok();
+
+        Ok(Self {
+            credentials,
+            cache: Mutex::new(cache),
             player: Mutex::new(None),
-            handle: Mutex::new(Some(handle)),
+            handle: Mutex::new(handle),
+            settings: Mutex::new(settings),
+            discovery,
+            startup_errors,
+            data_dir: data_dir.into(),
+            app,
+        })
+    }
+
+    fn cache(&self) -> Arc<Cache> {
+        self.cache.lock().unwrap().clone()
+    }
+
+    fn player(&self) -> Result<Player> {
+        self.player
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| Error::AudioDevice("the player hasn't been set up".into()))
+    }
+
+    /// for when there's no player yet: moves the output that's waiting for
+    /// one over to `device`, or has another go at starting it if it never
+    /// did. the frontend gets an "output-ready" event when there's one it
+    /// can set a player up with
+    async fn start_output(&self, device: Option<String>) -> Result<()> {
+        let handle = self.handle.lock().unwrap().clone();
+        match handle {
+            Some(handle) => spawn_blocking(move || handle.switch_device(device))
+                .await
+                .map_err(|e| Error::AudioDevice(e.to_string()))??,
+            None => {
+                *self.handle.lock().unwrap() = Some(init_main_stream(device)?);
+            }
         }
+        if let Err(e) = self.app.emit("output-ready", ()) {
+            eprintln!("couldn't announce audio output: {e}");
+        }
+        Ok(())
+    }
+
+    /// passes `result` through, asking the frontend to log in again if the
+    /// server turned us down
+    fn check_auth<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            notify_unauthorized(&self.app, e);
+        }
+        result
+    }
+
+    /// saves the token for the server we're connected to and starts using it
+    fn set_token(&self, token: Option<String>) -> Result<()> {
+        let cache = self.cache();
+        let client = build_client(token.as_deref())?;
+        self.credentials.set(cache.server_url(), token)?;
+        cache.set_client(client);
+        if let Err(e) = self.app.emit("credentials-changed", cache.server_url()) {
+            eprintln!("couldn't announce new credentials: {e}");
+        }
+        refresh_in_background(cache, self.app.clone());
+        Ok(())
+    }
+
+    fn save_settings(&self, settings: &Settings) -> Result<()> {
+        settings.save(&self.data_dir.join("settings.json"))
     }
+
+    /// rebuilds the cache and player against the server at `url`, whatever
+    /// was playing is stopped
+    async fn connect(&self, url: String) -> Result<()> {
+        if self.cache().server_url() == url {
+            return Ok(());
+        }
+
+        let cache = open_cache(&self.credentials, &self.data_dir, url)?;
+        *self.cache.lock().unwrap() = cache.clone();
+
+        let player = self.player.lock().unwrap().clone();
+        if let Some(player) = player {
+            let player = player.switch_cache(cache.clone()).await;
+            *self.player.lock().unwrap() = Some(player);
+        }
+
+        if let Err(e) = self.app.emit("server-changed", cache.server_url()) {
+            eprintln!("couldn't announce server change: {e}");
+        }
+        refresh_in_background(cache, self.app.clone());
+        Ok(())
+    }
+}
+
+fn open_cache(credentials: &Credentials, data_dir: &Path, url: String) -> Result<Arc<Cache>> {
+    let client = build_client(credentials.token(&url).as_deref())?;
+    let db = LibraryDb::open(&library_path(data_dir, &url))?;
+    Ok(Arc::new(Cache::new(client, db, url)))
+}
+
+/// start up with what we have on disk and catch up with the server in the
+/// background
+fn refresh_in_background(cache: Arc<Cache>, app: AppHandle) {
+    spawn(async move {
+        match cache.refresh().await {
+            Ok(changes) if !changes.is_empty() => {
+                if let Err(e) = app.emit("library-changed", changes) {
+                    eprintln!("couldn't announce library changes: {e}");
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("couldn't refresh library: {e}");
+                notify_unauthorized(&app, &e);
+            }
+        }
+    });
 }
 
 #[tauri::command]
-fn get_library(systems: State<'_, Systems>) -> Result<LibraryData, ()> {
-    systems.cache.get_library()
+fn get_library(systems: State<'_, Systems>) -> Result<LibraryData> {
+    systems.cache().get_library()
 }
 
 #[tauri::command]
-fn get_album(id: i64, systems: State<'_, Systems>) -> Result<GetAlbumResp, ()> {
-    systems.cache.get_album(id)
+async fn get_album(id: i64, systems: State<'_, Systems>) -> Result<GetAlbumResp> {
+    systems.check_auth(systems.cache().get_album(id).await)
 }
 
 #[tauri::command]
-async fn play_track(id: i64, systems: State<'_, Systems>) -> Result<(), ()> {
-    let player = { systems.player.lock().unwrap().as_ref().unwrap().clone() };
-    player.play_track(id).await;
-    Ok(())
+async fn get_image(id: i64, systems: State<'_, Systems>) -> Result<Response> {
+    let image = systems.check_auth(systems.cache().get_image(id).await)?;
+    Ok(Response::new(image))
 }
 
 #[tauri::command]
-fn setup_player(systems: State<'_, Systems>, channel: Channel<PlayerUpdateMsg>) {
-    let mut player = systems.player.lock().unwrap();
-    let handle = systems.handle.lock().unwrap().take().unwrap();
-    *player = Some(Player::new(
-        systems.client.clone(),
-        systems.cache.clone(),
-        channel,
-        handle,
-    ))
+async fn play_track(id: i64, systems: State<'_, Systems>) -> Result<()> {
+    systems.check_auth(systems.player()?.play_track(id).await)
 }
 
 #[tauri::command]
-fn toggle_playing(systems: State<'_, Systems>) {
-    systems
-        .player
+fn setup_player(systems: State<'_, Systems>, channel: Channel<PlayerUpdateMsg>) -> Result<()> {
+    let mut player = systems.player.lock().unwrap();
+    // the webview reloaded, the player we've got carries on with the new one
+    if let Some(player) = &*player {
+        player.set_channel(channel);
+        return Ok(());
+    }
+    let handle = systems
+        .handle
         .lock()
         .unwrap()
+        .take()
+        .ok_or_else(|| Error::AudioDevice("no audio output available".into()))?;
+    let settings = systems.settings.lock().unwrap();
+    handle.set_native_rate(settings.native_sample_rate);
+    handle.set_volume(settings.volume as f32);
+    handle.set_muted(settings.muted);
+    let new_player = Player::new(systems.cache(), channel, handle, systems.app.clone());
+    new_player.set_replay_gain(settings.replay_gain);
+    new_player.set_eq(settings.equalizer.active_curve());
+    new_player.set_stereo(settings.stereo);
+    new_player.set_crossfade(settings.crossfade.duration, settings.crossfade.curve);
+    *player = Some(new_player);
+    Ok(())
+}
+
+#[tauri::command]
+fn toggle_playing(systems: State<'_, Systems>) -> Result<()> {
+    systems.player()?.toggle_playing();
+    Ok(())
+}
+
+/// `position` is in seconds into the current track, it's sent by the seek
+/// slider in the player once the user lets go of it
+#[tauri::command]
+async fn seek(position: f64, systems: State<'_, Systems>) -> Result<()> {
+    systems.player()?.seek(position).await;
+    Ok(())
+}
+
+/// `duration` is in seconds, 0 turns crossfading off
+#[tauri::command]
+fn set_crossfade(duration: f64, curve: FadeCurve, systems: State<'_, Systems>) -> Result<()> {
+    {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.crossfade = CrossfadeSettings {
+            duration: duration.max(0.0),
+            curve,
+        };
+        systems.save_settings(&settings)?;
+    }
+    if let Ok(player) = systems.player() {
+        player.set_crossfade(duration, curve);
+    }
+    Ok(())
+}
+
+/// `volume` goes from 0 to 1. it's only saved with `persist`, so dragging
+/// the slider doesn't write the settings out at every step
+#[tauri::command]
+fn set_volume(volume: f64, persist: bool, systems: State<'_, Systems>) -> Result<()> {
+    if persist {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.volume = volume.clamp(0.0, 1.0);
+        systems.save_settings(&settings)?;
+    }
+    if let Ok(player) = systems.player() {
+        player.set_volume(volume);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn set_muted(muted: bool, systems: State<'_, Systems>) -> Result<()> {
+    {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.muted = muted;
+        systems.save_settings(&settings)?;
+    }
+    if let Ok(player) = systems.player() {
+        player.set_muted(muted);
+    }
+    Ok(())
+}
+
+/// takes effect from the next track that starts
+#[tauri::command]
+fn set_replay_gain(settings: ReplayGainSettings, systems: State<'_, Systems>) -> Result<()> {
+    {
+        let mut saved = systems.settings.lock().unwrap();
+        saved.replay_gain = settings;
+        systems.save_settings(&saved)?;
+    }
+    if let Ok(player) = systems.player() {
+        player.set_replay_gain(settings);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn set_equalizer(enabled: bool, curve: EqCurve, systems: State<'_, Systems>) -> Result<()> {
+    curve.validate()?;
+    let active = {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.equalizer.enabled = enabled;
+        settings.equalizer.curve = curve;
+        systems.save_settings(&settings)?;
+        settings.equalizer.active_curve()
+    };
+    if let Ok(player) = systems.player() {
+        player.set_eq(active);
+    }
+    Ok(())
+}
+
+/// saves the curve that's in use as a preset
+#[tauri::command]
+fn save_eq_preset(name: String, systems: State<'_, Systems>) -> Result<()> {
+    let mut settings = systems.settings.lock().unwrap();
+    settings.equalizer.save_preset(name)?;
+    systems.save_settings(&settings)
+}
+
+#[tauri::command]
+fn remove_eq_preset(name: String, systems: State<'_, Systems>) -> Result<()> {
+    let mut settings = systems.settings.lock().unwrap();
+    settings.equalizer.remove_preset(&name)?;
+    systems.save_settings(&settings)
+}
+
+/// switches the eq over to a saved preset
+#[tauri::command]
+fn load_eq_preset(name: String, systems: State<'_, Systems>) -> Result<()> {
+    let active = {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.equalizer.load_preset(&name)?;
+        systems.save_settings(&settings)?;
+        settings.equalizer.active_curve()
+    };
+    if let Ok(player) = systems.player() {
+        player.set_eq(active);
+    }
+    Ok(())
+}
+
+/// crossfeed, mono and balance, these apply straight away
+#[tauri::command]
+fn set_stereo(stereo: StereoSettings, systems: State<'_, Systems>) -> Result<()> {
+    let stereo = StereoSettings {
+        balance: stereo.balance.clamp(-1.0, 1.0),
+        ..stereo
+    };
+    {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.stereo = stereo;
+        systems.save_settings(&settings)?;
+    }
+    if let Ok(player) = systems.player() {
+        player.set_stereo(stereo);
+    }
+    Ok(())
+}
+
+/// levels get sent over the player channel while this is on
+#[tauri::command]
+fn set_metering(enabled: bool, systems: State<'_, Systems>) -> Result<()> {
+    systems.player()?.set_metering(enabled);
+    Ok(())
+}
+
+#[tauri::command]
+async fn skip(systems: State<'_, Systems>) -> Result<()> {
+    systems.player()?.skip().await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn skip_back(systems: State<'_, Systems>) -> Result<()> {
+    systems.player()?.skip_back().await;
+    Ok(())
+}
+
+/// plays tracks at their own sample rate when the output device supports
+/// it, instead of resampling them to the device's rate
+#[tauri::command]
+fn set_native_sample_rate(enabled: bool, systems: State<'_, Systems>) -> Result<()> {
+    {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.native_sample_rate = enabled;
+        systems.save_settings(&settings)?;
+    }
+    if let Ok(player) = systems.player() {
+        player.set_native_rate(enabled);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn get_output_devices() -> Result<Vec<OutputDevice>> {
+    output_devices()
+}
+
+/// plays through the output device called `name` from now on, or the
+/// default one if it's `None`. whatever is playing carries on from where it
+/// was
+#[tauri::command]
+async fn set_output_device(name: Option<String>, systems: State<'_, Systems>) -> Result<()> {
+    let Ok(player) = systems.player() else {
+        // it's saved first so it's still picked next time if the output
+        // doesn't come up now
+        {
+            let mut settings = systems.settings.lock().unwrap();
+            settings.output_device = name.clone();
+            systems.save_settings(&settings)?;
+        }
+        return systems.start_output(name).await;
+    };
+    player.switch_device(name.clone()).await?;
+    let mut settings = systems.settings.lock().unwrap();
+    settings.output_device = name;
+    systems.save_settings(&settings)
+}
+
+#[tauri::command]
+fn get_settings(systems: State<'_, Systems>) -> Settings {
+    systems.settings.lock().unwrap().clone()
+}
+
+/// adds a server profile, or changes the url of an existing one
+#[tauri::command]
+async fn save_server(name: String, url: String, systems: State<'_, Systems>) -> Result<()> {
+    let active_url = {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.save_server(ServerProfile { name, url })?;
+        systems.save_settings(&settings)?;
+        settings.active_server()?.url.clone()
+    };
+    // in case that was the server we're using
+    systems.connect(active_url).await
+}
+
+#[tauri::command]
+fn remove_server(name: String, systems: State<'_, Systems>) -> Result<()> {
+    let mut settings = systems.settings.lock().unwrap();
+    settings.remove_server(&name)?;
+    systems.save_settings(&settings)
+}
+
+#[tauri::command]
+async fn set_active_server(name: String, systems: State<'_, Systems>) -> Result<()> {
+    let url = {
+        let mut settings = systems.settings.lock().unwrap();
+        let url = settings.server(&name)?.url.clone();
+        settings.active_server = name;
+        systems.save_settings(&settings)?;
+        url
+    };
+    systems.connect(url).await
+}
+
+#[tauri::command]
+fn get_startup_errors(systems: State<'_, Systems>) -> Vec<Error> {
+    systems.startup_errors.clone()
+}
+
+#[tauri::command]
+fn get_discovered_servers(systems: State<'_, Systems>) -> Vec<DiscoveredServer> {
+    match &systems.discovery {
+        Some(d) => d.servers(),
+        None => Vec::new(),
+    }
+}
+
+/// saves a server we found on the network as a profile and switches to it
+#[tauri::command]
+async fn use_discovered_server(name: String, systems: State<'_, Systems>) -> Result<()> {
+    let server = systems
+        .discovery
         .as_ref()
-        .unwrap()
-        .toggle_playing();
+        .and_then(|d| d.server(&name))
+        .ok_or_else(|| Error::NotFound(format!("server \"{name}\"")))?;
+    let url = {
+        let mut settings = systems.settings.lock().unwrap();
+        settings.use_discovered_server(ServerProfile {
+            name: server.name,
+            url: server.url,
+        })?;
+        systems.save_settings(&settings)?;
+        settings.active_server()?.url.clone()
+    };
+    systems.connect(url).await
+}
+
+/// logs in to the server we're connected to
+#[tauri::command]
+async fn login(username: String, password: String, systems: State<'_, Systems>) -> Result<()> {
+    let url = systems.cache().server_url().to_string();
+    let token = auth::login(&url, &username, &password).await?;
+    systems.set_token(Some(token))
+}
+
+/// uses an api token from the server instead of logging in
+#[tauri::command]
+fn set_api_token(token: String, systems: State<'_, Systems>) -> Result<()> {
+    systems.set_token(Some(token))
 }
 
 #[tauri::command]
-fn skip(systems: State<'_, Systems>) {
-    systems.player.lock().unwrap().as_ref().unwrap().skip();
+fn logout(systems: State<'_, Systems>) -> Result<()> {
+    systems.set_token(None)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let (_stream, handle) = init_main_stream();
-    println!("made stream");
     tauri::Builder::default()
         .plugin(tauri_plugin_http::init())
         .plugin(tauri_plugin_opener::init())
         .setup(|app| {
-            app.manage(Systems::new(handle));
+            let data_dir = app.path().app_data_dir()?;
+            create_dir_all(&data_dir)?;
+            app.manage(Systems::new(&data_dir, app.handle().clone())?);
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -96,7 +570,33 @@ pub fn run() {
             play_track,
             setup_player,
             toggle_playing,
+            seek,
+            set_crossfade,
+            set_volume,
+            set_muted,
+            set_replay_gain,
+            set_equalizer,
+            save_eq_preset,
+            remove_eq_preset,
+            load_eq_preset,
+            set_stereo,
+            set_metering,
+            set_native_sample_rate,
+            get_output_devices,
+            set_output_device,
             skip,
+            skip_back,
+            get_settings,
+            save_server,
+            remove_server,
+            set_active_server,
+            get_discovered_servers,
+            get_startup_errors,
+            use_discovered_server,
+            get_image,
+            login,
+            set_api_token,
+            logout,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,132 @@
+use std::{collections::BTreeMap, path::Path};
+
+use redb::{
+    backends::InMemoryBackend, Database, ReadableTable, TableDefinition, TableError,
+    WriteTransaction,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub const ALBUMS: TableDefinition<i64, &[u8]> = TableDefinition::new("albums");
+pub const ARTISTS: TableDefinition<i64, &[u8]> = TableDefinition::new("artists");
+pub const TRACKS: TableDefinition<i64, &[u8]> = TableDefinition::new("tracks");
+/// loudness we measured ourselves, keyed by track id
+pub const LOUDNESS: TableDefinition<i64, &[u8]> = TableDefinition::new("loudness");
+const META: TableDefinition<&str, &str> = TableDefinition::new("meta");
+
+const REVISION_KEY: &str = "revision";
+
+/// redb's error is pretty big so we keep it boxed
+pub type DbError = Box<redb::Error>;
+
+fn db_err(e: impl Into<redb::Error>) -> DbError {
+    Box::new(e.into())
+}
+
+/// on-disk copy of the library so we can start up without the server,
+/// rows are stored as json keyed by their id
+pub struct LibraryDb(Database);
+impl LibraryDb {
+    pub fn open(path: &Path) -> Result<Self, DbError> {
+        Ok(Self(Database::create(path).map_err(db_err)?))
+    }
+
+    /// a library that's gone when we quit, for when the one on disk can't
+    /// be opened
+    pub fn in_memory() -> Result<Self, DbError> {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .map_err(db_err)?;
+        Ok(Self(db))
+    }
+
+    pub fn load<V: DeserializeOwned>(
+        &self,
+        table: TableDefinition<i64, &[u8]>,
+    ) -> Result<BTreeMap<i64, V>, DbError> {
+        let txn = self.0.begin_read().map_err(db_err)?;
+        let table = match txn.open_table(table) {
+            Ok(t) => t,
+            // nothing has been saved yet
+            Err(TableError::TableDoesNotExist(_)) => return Ok(BTreeMap::new()),
+            Err(e) => return Err(db_err(e)),
+        };
+
+        let mut rows = BTreeMap::new();
+        for row in table.iter().map_err(db_err)? {
+            let (id, value) = row.map_err(db_err)?;
+            match serde_json::from_slice(value.value()) {
+                Ok(v) => {
+                    rows.insert(id.value(), v);
+                }
+                Err(e) => eprintln!("skipping bad library row {}: {e}", id.value()),
+            }
+        }
+        Ok(rows)
+    }
+
+    /// the server revision the saved library is from
+    pub fn load_revision(&self) -> Result<Option<String>, DbError> {
+        let txn = self.0.begin_read().map_err(db_err)?;
+        let table = match txn.open_table(META) {
+            Ok(t) => t,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(db_err(e)),
+        };
+        let revision = table.get(REVISION_KEY).map_err(db_err)?;
+        Ok(revision.map(|r| r.value().to_string()))
+    }
+
+    /// runs `f` in a single write transaction, nothing is saved unless it
+    /// succeeds
+    pub fn write(
+        &self,
+        f: impl FnOnce(&LibraryWriter) -> Result<(), DbError>,
+    ) -> Result<(), DbError> {
+        let txn = self.0.begin_write().map_err(db_err)?;
+        f(&LibraryWriter(&txn))?;
+        txn.commit().map_err(db_err)?;
+        Ok(())
+    }
+}
+
+pub struct LibraryWriter<'a>(&'a WriteTransaction);
+impl LibraryWriter<'_> {
+    pub fn clear(&self, table: TableDefinition<i64, &[u8]>) -> Result<(), DbError> {
+        self.0.delete_table(table).map_err(db_err)?;
+        Ok(())
+    }
+
+    pub fn upsert<'v, V: Serialize + 'v>(
+        &self,
+        table: TableDefinition<i64, &[u8]>,
+        rows: impl IntoIterator<Item = (&'v i64, &'v V)>,
+    ) -> Result<(), DbError> {
+        let mut table = self.0.open_table(table).map_err(db_err)?;
+        for (id, row) in rows {
+            let value = serde_json::to_vec(row).unwrap();
+            table.insert(id, value.as_slice()).map_err(db_err)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete<'i>(
+        &self,
+        table: TableDefinition<i64, &[u8]>,
+        ids: impl IntoIterator<Item = &'i i64>,
+    ) -> Result<(), DbError> {
+        let mut table = self.0.open_table(table).map_err(db_err)?;
+        for id in ids {
+            table.remove(id).map_err(db_err)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_revision(&self, revision: Option<&str>) -> Result<(), DbError> {
+        let mut table = self.0.open_table(META).map_err(db_err)?;
+        match revision {
+            Some(r) => table.insert(REVISION_KEY, r).map_err(db_err)?,
+            None => table.remove(REVISION_KEY).map_err(db_err)?,
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::Channels;
+
+use crate::biquad::Biquad;
+
+/// blocks below this are never counted, it's roughly where silence is
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// blocks this far below the average are left out too, so quiet passages
+/// don't drag the result down
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// how loud a whole track is, worked out by the client when the track
+/// doesn't have replaygain tags
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct TrackLoudness {
+    /// EBU R128 integrated loudness
+    pub integrated_lufs: f64,
+    /// the biggest sample, 1.0 is full scale
+    pub peak: f32,
+}
+
+/// measures integrated loudness as laid out in ITU-R BS.1770: each channel
+/// is k-weighted, the mean square power is taken over 400ms blocks that
+/// overlap by 75%, and blocks are gated before being averaged
+pub struct LoudnessMeter {
+    /// one pair of k-weighting filters per channel
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    /// frames in a 100ms step
+    step_len: usize,
+    /// weighted power summed up over the step we're in
+    step_power: f64,
+    step_frames: usize,
+    /// the last four steps, which make up a block
+    steps: [f64; 4],
+    steps_seen: usize,
+    /// mean square power of every block so far
+    blocks: Vec<f64>,
+    peak: f32,
+}
+impl LoudnessMeter {
+    pub fn new(rate: u32, layout: Channels) -> Self {
+        let weights = layout.iter().map(channel_weight).collect::<Vec<_>>();
+        Self {
+            filters: vec![k_weighting(rate as f64); weights.len()],
+            weights,
+            step_len: (rate / 10) as usize,
+            step_power: 0.0,
+            step_frames: 0,
+            steps: [0.0; 4],
+            steps_seen: 0,
+            blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    /// `planes` is one slice per channel, all the same length
+    pub fn add<P: AsRef<[f32]>>(&mut self, planes: &[P]) {
+        let frames = planes.first().map_or(0, |p| p.as_ref().len());
+        for f in 0..frames {
+            for (c, plane) in planes.iter().enumerate() {
+                let s = plane.as_ref()[f];
+                self.peak = self.peak.max(s.abs());
+                let [shelf, high_pass] = &mut self.filters[c];
+                let y = high_pass.process(shelf.process(s as f64));
+                self.step_power += self.weights[c] * y * y;
+            }
+
+            self.step_frames += 1;
+            if self.step_frames == self.step_len {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        self.steps.rotate_left(1);
+        self.steps[3] = self.step_power;
+        self.steps_seen += 1;
+        self.step_power = 0.0;
+        self.step_frames = 0;
+        if self.steps_seen >= 4 {
+            let block_len = (self.step_len * 4) as f64;
+            self.blocks.push(self.steps.iter().sum::<f64>() / block_len);
+        }
+    }
+
+    /// `None` if there wasn't enough (non-silent) audio to go on
+    pub fn finish(self) -> Option<TrackLoudness> {
+        let absolute = power(ABSOLUTE_GATE_LUFS);
+        let loud: Vec<_> = self.blocks.into_iter().filter(|b| *b > absolute).collect();
+        if loud.is_empty() {
+            return None;
+        }
+        let relative = power(lufs(mean(&loud)) + RELATIVE_GATE_LU);
+        let gated: Vec<_> = loud.into_iter().filter(|b| *b > relative).collect();
+
+        Some(TrackLoudness {
+            integrated_lufs: lufs(mean(&gated)),
+            peak: self.peak,
+        })
+    }
+}
+
+fn mean(blocks: &[f64]) -> f64 {
+    blocks.iter().sum::<f64>() / blocks.len() as f64
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn power(lufs: f64) -> f64 {
+    10f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// surround channels count for a bit more, the sub doesn't count at all
+fn channel_weight(channel: Channels) -> f64 {
+    use Channels as C;
+    match channel {
+        C::LFE1 | C::LFE2 => 0.0,
+        C::SIDE_LEFT | C::SIDE_RIGHT | C::REAR_LEFT | C::REAR_RIGHT => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// the high shelf and high pass filters that make up k-weighting, worked
+/// out for `rate` (the coefficients in the spec are only for 48kHz)
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let shelf = Biquad::new(
+        [
+            vh + vb * k / q + k * k,
+            2.0 * (k * k - vh),
+            vh - vb * k / q + k * k,
+        ],
+        [
+            1.0 + k / q + k * k,
+            2.0 * (k * k - 1.0),
+            1.0 - k / q + k * k,
+        ],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    // unlike the shelf only the denominator gets normalized here, that's
+    // how the reference implementation does it
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `seconds` of a full scale 997Hz sine on the left channel, with the
+    /// right one silent
+    fn measure_sine(rate: u32, seconds: usize) -> TrackLoudness {
+        let frames = rate as usize * seconds;
+        let left: Vec<f32> = (0..frames)
+            .map(|i| (2.0 * PI * 997.0 * i as f64 / rate as f64).sin() as f32)
+            .collect();
+        let right = vec![0.0; frames];
+        let mut meter = LoudnessMeter::new(rate, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        // in packets, like a decoder hands them over
+        for start in (0..frames).step_by(4096) {
+            let end = (start + 4096).min(frames);
+            meter.add(&[&left[start..end], &right[start..end]]);
+        }
+        meter.finish().unwrap()
+    }
+
+    #[test]
+    fn full_scale_sine_on_one_channel_is_minus_3_lufs() {
+        // the reference level from BS.1770
+        for rate in [44100, 48000] {
+            let loudness = measure_sine(rate, 5);
+            assert!(
+                (loudness.integrated_lufs - -3.01).abs() < 0.05,
+                "{} LUFS at {rate}",
+                loudness.integrated_lufs
+            );
+            assert!((loudness.peak - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn quieter_is_quieter_by_the_same_amount() {
+        let rate = 48000;
+        let frames = rate as usize * 5;
+        let sine: Vec<f32> = (0..frames)
+            .map(|i| (2.0 * PI * 997.0 * i as f64 / rate as f64).sin() as f32 * 0.1)
+            .collect();
+        let mut meter = LoudnessMeter::new(rate, Channels::FRONT_LEFT);
+        meter.add(&[&sine]);
+        let loudness = meter.finish().unwrap();
+        // 0.1 is 20 dB down
+        assert!((loudness.integrated_lufs - -23.01).abs() < 0.05);
+    }
+
+    #[test]
+    fn silence_and_short_tracks_cant_be_measured() {
+        let mut meter = LoudnessMeter::new(48000, Channels::FRONT_LEFT);
+        meter.add(&[vec![0.0; 48000 * 2]]);
+        assert!(meter.finish().is_none());
+
+        // not even one 400ms block
+        let mut meter = LoudnessMeter::new(48000, Channels::FRONT_LEFT);
+        meter.add(&[vec![0.5; 48000 / 4]]);
+        assert!(meter.finish().is_none());
+    }
+
+    #[test]
+    fn the_sub_doesnt_count() {
+        let mut meter = LoudnessMeter::new(48000, Channels::LFE1);
+        meter.add(&[vec![0.5; 48000]]);
+        assert!(meter.finish().is_none());
+    }
+}
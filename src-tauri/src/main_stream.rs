@@ -1,240 +1,1154 @@
 use std::{
     future::Future,
+    ops::RangeInclusive,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
         Arc, Mutex,
     },
     task::{Poll, Waker},
+    thread,
+    time::Duration,
 };
 
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, FromSample, Sample, SizedSample, Stream, StreamConfig,
+    Device, FromSample, Sample, SampleRate, SizedSample, Stream, StreamConfig, StreamError,
+    SupportedStreamConfig, SupportedStreamConfigRange,
 };
 use rtrb::{chunks::ChunkError, Consumer, Producer, RingBuffer};
-use rubato::{FftFixedIn, Resampler};
+use serde::Serialize;
+use symphonia::core::audio::Channels;
+
+use crate::{
+    channel_mix::ChannelMixer,
+    crossfade::{CrossfadeState, FadeCurve},
+    equalizer::{EqCurve, EqUpdates, Equalizer},
+    error::{Error, Result},
+    meter::{meter_tap, Levels, MeterReader, MeterTap},
+    resample::ResampleStage,
+    stereo::{StereoSettings, StereoStage, StereoState},
+    volume::{GainRamp, Volume},
+};
+
+/// how much a track stream can buffer, in frames
+const TRACK_BUFFER_FRAMES: usize = 2048;
+/// how much of the next track we look at to decide if it starts silent
+const GAPLESS_PROBE_SAMPLES: usize = 1024;
+/// about -50 dBFS
+const SILENCE_THRESHOLD: f32 = 0.003;
+/// how often the output thread checks whether the device changed
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
 
 /// this is basically a specialized handle to the main audio thread that
 /// understands the context of a streamed music player
+#[derive(Clone)]
 pub struct MainStreamHandle {
-    playing: Arc<AtomicBool>,
+    state: Arc<MainStreamState>,
     queue: Arc<Mutex<Producer<TrackStream>>>,
-    clear: Arc<AtomicBool>,
-    out_rate: u32,
+    /// what the output device can play, this changes when we switch devices
+    format: Arc<Mutex<OutputFormat>>,
+    /// whether tracks should be played at their own sample rate when the
+    /// device can do it
+    native_rate: Arc<AtomicBool>,
+    /// requests for the output thread
+    commands: SyncSender<OutputCommand>,
+    /// set when the output moves to another device on its own
+    device_change: Arc<Mutex<Option<DeviceChange>>>,
+    meter: Arc<Mutex<MeterReader>>,
+}
+
+/// state shared between the main stream and its handle
+#[derive(Default)]
+struct MainStreamState {
+    playing: AtomicBool,
+    /// goes up every time the main stream is cleared. tracks remember what
+    /// it was when they were spawned, so the ones queued before a clear can
+    /// be told apart from ones queued right after it
+    clears: AtomicU64,
+    crossfade: CrossfadeState,
+    volume: Volume,
+    eq: EqUpdates,
+    stereo: StereoState,
+    /// whether the output gets copied into the meter tap
+    metering: AtomicBool,
+    /// set when the main stream threw away what was queued because it
+    /// couldn't be played, the player has to queue it again
+    requeue: AtomicBool,
+}
+
+/// what the output device can play, track streams are spawned to fit it
+pub struct OutputFormat {
+    /// the device's own sample rate, tracks get resampled to this unless
+    /// they can be played at their own rate
+    default_rate: u32,
+    /// the sample rates the output can be reopened at
+    native_rates: Vec<RangeInclusive<u32>>,
+    /// rates in `native_rates` that reopening at didn't work out after all
+    unavailable_rates: Vec<u32>,
+    channels: usize,
+}
+impl OutputFormat {
+    pub fn with_rates(
+        default_rate: u32,
+        native_rates: Vec<RangeInclusive<u32>>,
+        channels: usize,
+    ) -> Self {
+        Self {
+            default_rate,
+            native_rates,
+            unavailable_rates: Vec::new(),
+            channels,
+        }
+    }
+
+    /// whether the output can be reopened at `rate`, as far as we know
+    fn is_native(&self, rate: u32) -> bool {
+        self.native_rates.iter().any(|r| r.contains(&rate))
+            && !self.unavailable_rates.contains(&rate)
+    }
+
+    fn new(device: &Device, config: &SupportedStreamConfig) -> Self {
+        let native_rates = match device.supported_output_configs() {
+            Ok(configs) => configs
+                .filter(|c| same_format(c, config))
+                .map(|c| c.min_sample_rate().0..=c.max_sample_rate().0)
+                .collect(),
+            Err(e) => {
+                eprintln!("couldn't get supported output configs: {e}");
+                Vec::new()
+            }
+        };
+        Self::with_rates(
+            config.sample_rate().0,
+            native_rates,
+            config.channels() as usize,
+        )
+    }
+}
+
+/// things the output can be asked to do
+pub enum OutputCommand {
+    /// reopen the output at a new sample rate, the main stream sends these
+    /// when the next track needs it
+    Reopen(u32),
+    /// move over to the device with this name (or the default one), the
+    /// result is sent back once it's done
+    SwitchDevice(Option<String>, mpsc::Sender<Result<()>>),
+    /// see if the stream broke or the device we should be using changed,
+    /// this also happens every `DEVICE_CHECK_INTERVAL` on its own
+    CheckDevice,
+}
+
+/// something the main stream plays through. whatever is driving it (the
+/// output thread, for a real device) passes on the commands the main stream
+/// and its handle send
+pub trait OutputBackend {
+    /// start playing at `rate`. if that can't be done the main stream gets
+    /// told the rate is unavailable and the output carries on at the old one
+    fn reopen(&mut self, rate: u32);
+    /// move over to the device with this name (or the default one)
+    fn switch_device(&mut self, name: Option<String>) -> Result<()>;
+    /// see if the output needs rebuilding or moving
+    fn check_device(&mut self);
+
+    fn handle(&mut self, command: OutputCommand) {
+        match command {
+            OutputCommand::Reopen(rate) => self.reopen(rate),
+            OutputCommand::SwitchDevice(name, done) => {
+                let _ = done.send(self.switch_device(name));
+            }
+            OutputCommand::CheckDevice => self.check_device(),
+        }
+    }
 }
 
-pub fn init_main_stream() -> (Stream, MainStreamHandle) {
+/// makes a main stream for an output that starts out at `format`'s default
+/// rate, along with the handle the rest of the app uses to talk to it.
+/// `commands` is where the backend playing it gets its requests from
+pub fn new_main_stream(
+    format: OutputFormat,
+    commands: SyncSender<OutputCommand>,
+) -> (Arc<Mutex<MainStream>>, MainStreamHandle) {
     let (queue, recv) = RingBuffer::new(256);
+    let state = Arc::new(MainStreamState::default());
+    let (tap, meter) = meter_tap();
+    let (rate, channels) = (format.default_rate, format.channels);
+    let format = Arc::new(Mutex::new(format));
+    let main_stream = Arc::new(Mutex::new(MainStream::new(
+        recv,
+        state.clone(),
+        rate,
+        channels,
+        format.clone(),
+        commands.clone(),
+        tap,
+    )));
+    let handle = MainStreamHandle::new(
+        state,
+        Arc::new(Mutex::new(queue)),
+        format,
+        commands,
+        Arc::new(Mutex::new(None)),
+        meter,
+    );
+    (main_stream, handle)
+}
+
+/// lets the output thread know when a stream stops working
+#[derive(Clone)]
+struct ErrorReporter {
+    failed: Arc<AtomicBool>,
+    commands: SyncSender<OutputCommand>,
+}
+impl ErrorReporter {
+    fn report(&self, e: StreamError) {
+        eprintln!("audio output error: {e}");
+        self.failed.store(true, Ordering::Release);
+        // if there's no room the output thread has something else to do,
+        // it'll notice when it next checks the device
+        let _ = self.commands.try_send(OutputCommand::CheckDevice);
+    }
+}
+
+/// the output changed without being asked to, because the device it was
+/// using went away (or the one it should be using came back, or its stream
+/// broke and came back up different)
+pub struct DeviceChange {
+    /// the device the output moved to, `None` if it's still on the same one
+    pub device: Option<String>,
+    /// whether the new device runs at a different rate or has a different
+    /// number of channels, everything that was queued got cleared if so
+    pub format_changed: bool,
+}
+
+/// an output device, and what it can be set up to play
+#[derive(Serialize)]
+pub struct OutputDevice {
+    pub name: String,
+    pub default: bool,
+    pub configs: Vec<OutputConfig>,
+}
+#[derive(Serialize)]
+pub struct OutputConfig {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
 
+/// every output device the default host knows about
+pub fn output_devices() -> Result<Vec<OutputDevice>> {
     let host = cpal::default_host();
-    let device = host.default_output_device().unwrap();
-    let config = device.default_output_config().unwrap();
-    let sample_rate = config.sample_rate().0;
+    let default = host.default_output_device().and_then(|d| d.name().ok());
+    let mut devices = Vec::new();
+    for device in host.output_devices()? {
+        let name = device.name()?;
+        // some devices show up but can't actually be opened, there's no
+        // point in offering those
+        let configs = match device.supported_output_configs() {
+            Ok(configs) => configs
+                .map(|c| OutputConfig {
+                    channels: c.channels(),
+                    min_sample_rate: c.min_sample_rate().0,
+                    max_sample_rate: c.max_sample_rate().0,
+                    sample_format: c.sample_format().to_string(),
+                })
+                .collect(),
+            Err(e) => {
+                eprintln!("skipping output device {name}: {e}");
+                continue;
+            }
+        };
+        devices.push(OutputDevice {
+            default: default.as_ref() == Some(&name),
+            name,
+            configs,
+        });
+    }
+    Ok(devices)
+}
 
-    let playing = Arc::new(AtomicBool::new(false));
-    let clear = Arc::new(AtomicBool::new(false));
+/// the output device called `name`, or the default one
+fn find_device(name: Option<&str>) -> Result<Device> {
+    let host = cpal::default_host();
+    match name {
+        Some(name) => host
+            .output_devices()?
+            .find(|d| d.name().is_ok_and(|n| n == name))
+            .ok_or_else(|| Error::NotFound(format!("output device \"{name}\""))),
+        None => host
+            .default_output_device()
+            .ok_or_else(|| Error::AudioDevice("no output device".into())),
+    }
+}
 
-    let stream = match config.sample_format() {
-        cpal::SampleFormat::I8 => build_main_stream::<i8>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::I16 => build_main_stream::<i16>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::I32 => build_main_stream::<i32>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::I64 => build_main_stream::<i64>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::U8 => build_main_stream::<u8>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::U16 => build_main_stream::<u16>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::U32 => build_main_stream::<u32>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::U64 => build_main_stream::<u64>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::F32 => build_main_stream::<f32>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
-        )
-        .unwrap(),
-        cpal::SampleFormat::F64 => build_main_stream::<f64>(
-            &device,
-            &config.into(),
-            recv,
-            playing.clone(),
-            clear.clone(),
+/// opens the output device called `device` (or the default one) on a thread
+/// of its own. that thread holds on to the stream (cpal streams can't be
+/// moved between threads) and reopens it whenever the main stream needs a
+/// different sample rate, we switch devices, or the device goes away. if
+/// there's no device at all it keeps checking for one to turn up
+pub fn init_main_stream(device: Option<String>) -> Result<MainStreamHandle> {
+    let (opened_send, opened_recv) = mpsc::channel();
+    thread::Builder::new()
+        .name("audio output".into())
+        .spawn(move || {
+            let (command_send, command_recv) = mpsc::sync_channel(1);
+            let (mut output, handle) = Output::new(device, command_send);
+            output.open();
+            opened_send.send(handle).unwrap();
+            output.run(command_recv);
+        })
+        .map_err(|e| Error::AudioDevice(e.to_string()))?;
+    opened_recv
+        .recv()
+        .map_err(|_| Error::AudioDevice("audio output thread died".into()))
+}
+
+/// the output device and the stream that's playing to it
+struct Output {
+    /// `None` while there's no device to play through
+    device: Option<OpenDevice>,
+    /// the device the user picked, `None` to follow the default
+    wanted: Option<String>,
+    main_stream: Arc<Mutex<MainStream>>,
+    format: Arc<Mutex<OutputFormat>>,
+    errors: ErrorReporter,
+    /// where the player hears about device changes it didn't ask for
+    device_change: Arc<Mutex<Option<DeviceChange>>>,
+}
+struct OpenDevice {
+    device: Device,
+    name: String,
+    /// what the device wants to run at, everything else is a variation on
+    /// this with a different sample rate
+    default_config: SupportedStreamConfig,
+    config: SupportedStreamConfig,
+    stream: Option<Stream>,
+}
+impl Output {
+    /// an output that isn't playing through anything yet. until it is, the
+    /// main stream is set up for a stereo device at 48kHz
+    fn new(
+        wanted: Option<String>,
+        commands: SyncSender<OutputCommand>,
+    ) -> (Self, MainStreamHandle) {
+        let errors = ErrorReporter {
+            failed: Arc::new(AtomicBool::new(false)),
+            commands: commands.clone(),
+        };
+        let format = OutputFormat::with_rates(48000, Vec::new(), 2);
+        let (main_stream, handle) = new_main_stream(format, commands);
+        (
+            Self {
+                device: None,
+                wanted,
+                main_stream,
+                format: handle.format.clone(),
+                errors,
+                device_change: handle.device_change.clone(),
+            },
+            handle,
         )
-        .unwrap(),
-        sample_format => panic!("Unsupported sample format '{sample_format}'"),
-    };
+    }
 
-    (
-        stream,
-        MainStreamHandle::new(clear, playing, Arc::new(Mutex::new(queue)), sample_rate),
-    )
+    /// opens the device we want, or the default one if it isn't there.
+    /// if neither works out we'll keep trying every time the device gets
+    /// checked
+    fn open(&mut self) {
+        let wanted = self.wanted.clone();
+        let opened = self.move_to(wanted.as_deref()).or_else(|e| {
+            // the device might just not be plugged in right now
+            eprintln!("couldn't open output device, using the default: {e}");
+            self.move_to(None)
+        });
+        if let Err(e) = opened {
+            eprintln!("couldn't open audio output, waiting for a device: {e}");
+            self.errors.failed.store(true, Ordering::Release);
+        }
+    }
+
+    /// does whatever it gets asked to until the app closes, keeping an eye
+    /// on the device in between
+    fn run(mut self, commands: Receiver<OutputCommand>) {
+        loop {
+            match commands.recv_timeout(DEVICE_CHECK_INTERVAL) {
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.check_device();
+                    self.drop_stale_if_idle();
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// while there's no stream running the callback never gets to throw
+    /// away what was cleared, so it's done from here instead
+    fn drop_stale_if_idle(&self) {
+        if self.device.as_ref().is_none_or(|d| d.stream.is_none()) {
+            self.main_stream.lock().unwrap().drop_stale();
+        }
+    }
+}
+impl OutputBackend for Output {
+    fn reopen(&mut self, rate: u32) {
+        let Some(open) = self.device.as_mut() else {
+            return;
+        };
+        // the old stream has to go first, some devices can only have one
+        // stream open at a time
+        open.stream = None;
+        let previous = open.config.clone();
+        let opened = match open.config_for(rate) {
+            Some(config) => self.start(config),
+            None => Err(Error::AudioDevice(format!(
+                "device can't play at {rate} Hz"
+            ))),
+        };
+        if let Err(e) = opened {
+            eprintln!("couldn't reopen audio output at {rate} Hz: {e}");
+            self.main_stream.lock().unwrap().rate_unavailable(rate);
+            if let Err(e) = self.start(previous) {
+                eprintln!("couldn't reopen audio output: {e}");
+            }
+        }
+    }
+
+    /// moves the main stream over to another device, going back to the one
+    /// we had if that doesn't work out. anything that was already sent to
+    /// the main stream was made for the old device, so it should be cleared
+    /// first
+    fn switch_device(&mut self, name: Option<String>) -> Result<()> {
+        let previous = self.current().map(String::from);
+        if let Err(e) = self.move_to(name.as_deref()) {
+            if let Some(previous) = previous {
+                if let Err(e) = self.move_to(Some(&previous)) {
+                    eprintln!("couldn't reopen audio output: {e}");
+                }
+            }
+            return Err(e);
+        }
+        self.wanted = name;
+        Ok(())
+    }
+
+    /// rebuilds the stream if it broke, or moves it if the device we should
+    /// be playing through has changed (or there wasn't one before). the
+    /// player gets told if it needs to catch up
+    fn check_device(&mut self) {
+        let failed = self.errors.failed.swap(false, Ordering::AcqRel);
+        if !failed && !self.should_move() {
+            return;
+        }
+
+        // the device we want if it's there, otherwise whatever the default is
+        let previous = self.current().map(String::from);
+        let wanted = self.wanted.clone();
+        let moved = self
+            .move_to(wanted.as_deref())
+            .or_else(|_| self.move_to(None));
+        match moved {
+            Ok(format_changed) => {
+                // a stream that broke and came back up on the same device
+                // isn't worth mentioning, unless it came back different
+                let device = self.current().filter(|c| previous.as_deref() != Some(*c));
+                if device.is_some() || format_changed {
+                    *self.device_change.lock().unwrap() = Some(DeviceChange {
+                        device: device.map(String::from),
+                        format_changed,
+                    });
+                }
+            }
+            Err(e) => {
+                // if there wasn't a device before either, this was already
+                // said the first time around
+                if previous.is_some() {
+                    eprintln!("couldn't reopen audio output: {e}");
+                }
+                // try again next time around, maybe something gets plugged in
+                self.errors.failed.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+impl Output {
+    /// the name of the device we're playing through
+    fn current(&self) -> Option<&str> {
+        self.device.as_ref().map(|d| d.name.as_str())
+    }
+
+    /// whether the device we should be playing through isn't the one we are
+    fn should_move(&self) -> bool {
+        let Some(current) = self.current() else {
+            // there's nothing to play through, see if something turned up
+            return true;
+        };
+        match &self.wanted {
+            // we fell back to the default, see if ours is back
+            Some(name) if name != current => find_device(Some(name)).is_ok(),
+            Some(_) => false,
+            None => cpal::default_host()
+                .default_output_device()
+                .and_then(|d| d.name().ok())
+                .is_some_and(|name| name != current),
+        }
+    }
+
+    /// opens the device called `name` (or the default one) in place of the
+    /// one we're using, returns whether the format changed. if it did, the
+    /// main stream gets cleared since nothing in it fits the new device
+    fn move_to(&mut self, name: Option<&str>) -> Result<bool> {
+        let device = find_device(name)?;
+        let current = device.name()?;
+        let config = device.default_output_config()?;
+        let format = OutputFormat::new(&device, &config);
+        let (rate, channels) = {
+            let ms = self.main_stream.lock().unwrap();
+            (ms.out_rate, ms.out_channels)
+        };
+        let format_changed =
+            config.sample_rate().0 != rate || config.channels() as usize != channels;
+
+        // the old stream has to go first, some devices can only have one
+        // stream open at a time
+        self.device = None;
+        self.main_stream
+            .lock()
+            .unwrap()
+            .device_changed(format_changed);
+        self.device = Some(OpenDevice {
+            device,
+            name: current,
+            default_config: config.clone(),
+            config: config.clone(),
+            stream: None,
+        });
+        *self.format.lock().unwrap() = format;
+        self.start(config)?;
+        Ok(format_changed)
+    }
+
+    /// starts a new stream with `config`, there mustn't be one running
+    fn start(&mut self, config: SupportedStreamConfig) -> Result<()> {
+        let Some(open) = self.device.as_mut() else {
+            return Err(Error::AudioDevice("no output device".into()));
+        };
+        self.main_stream
+            .lock()
+            .unwrap()
+            .output_started(config.sample_rate().0, config.channels() as usize);
+        open.stream = Some(open_stream(
+            &open.device,
+            &config,
+            self.main_stream.clone(),
+            self.errors.clone(),
+        )?);
+        open.config = config;
+        Ok(())
+    }
+}
+
+impl OpenDevice {
+    /// the device's default config, but running at `rate`
+    fn config_for(&self, rate: u32) -> Option<SupportedStreamConfig> {
+        if rate == self.default_config.sample_rate().0 {
+            return Some(self.default_config.clone());
+        }
+        self.device
+            .supported_output_configs()
+            .ok()?
+            .filter(|c| same_format(c, &self.default_config))
+            .find(|c| (c.min_sample_rate().0..=c.max_sample_rate().0).contains(&rate))
+            .map(|c| c.with_sample_rate(SampleRate(rate)))
+    }
+}
+
+/// whether `range` has the same channels and sample format as `config`, so
+/// only the sample rate changes if we switch to it
+fn same_format(range: &SupportedStreamConfigRange, config: &SupportedStreamConfig) -> bool {
+    range.channels() == config.channels() && range.sample_format() == config.sample_format()
+}
+
+/// starts a stream on `device` that plays whatever the main stream puts out
+fn open_stream(
+    device: &Device,
+    config: &SupportedStreamConfig,
+    main_stream: Arc<Mutex<MainStream>>,
+    errors: ErrorReporter,
+) -> Result<Stream> {
+    let stream_config = config.config();
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::I8 => {
+            build_main_stream::<i8>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::I16 => {
+            build_main_stream::<i16>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::I32 => {
+            build_main_stream::<i32>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::I64 => {
+            build_main_stream::<i64>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::U8 => {
+            build_main_stream::<u8>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::U16 => {
+            build_main_stream::<u16>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::U32 => {
+            build_main_stream::<u32>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::U64 => {
+            build_main_stream::<u64>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::F32 => {
+            build_main_stream::<f32>(device, &stream_config, main_stream, errors)?
+        }
+        cpal::SampleFormat::F64 => {
+            build_main_stream::<f64>(device, &stream_config, main_stream, errors)?
+        }
+        sample_format => {
+            return Err(Error::AudioDevice(format!(
+                "unsupported sample format '{sample_format}'"
+            )))
+        }
+    };
+    Ok(stream)
 }
 
 impl MainStreamHandle {
-    pub fn new(
-        clear: Arc<AtomicBool>,
-        playing: Arc<AtomicBool>,
+    fn new(
+        state: Arc<MainStreamState>,
         queue: Arc<Mutex<Producer<TrackStream>>>,
-        out_rate: u32,
+        format: Arc<Mutex<OutputFormat>>,
+        commands: SyncSender<OutputCommand>,
+        device_change: Arc<Mutex<Option<DeviceChange>>>,
+        meter: MeterReader,
     ) -> Self {
         Self {
-            clear,
-            playing,
+            state,
             queue,
-            out_rate,
+            format,
+            native_rate: Arc::new(AtomicBool::new(false)),
+            commands,
+            device_change,
+            meter: Arc::new(Mutex::new(meter)),
         }
     }
     pub fn toggle_playing(&self) -> bool {
-        !self.playing.fetch_not(Ordering::AcqRel)
+        !self.state.playing.fetch_not(Ordering::AcqRel)
     }
     pub fn clear(&self) {
-        self.clear.store(true, Ordering::Release);
+        self.state.clears.fetch_add(1, Ordering::AcqRel);
     }
-    pub fn queue(&self, track: TrackStream) {
+    /// fails if the main stream has fallen too far behind to take it, which
+    /// only happens while there's no output running to work through what
+    /// was cleared
+    pub fn queue(&self, track: TrackStream) -> Result<()> {
         let mut queue = self.queue.lock().unwrap();
-        queue.push(track).unwrap();
+        queue
+            .push(track)
+            .map_err(|_| Error::AudioDevice("too many tracks waiting for the output".into()))
     }
     pub fn pause(&self) {
-        self.playing.store(false, Ordering::Release);
+        self.state.playing.store(false, Ordering::Release);
     }
     pub fn play(&self) {
-        self.playing.store(true, Ordering::Release);
+        self.state.playing.store(true, Ordering::Release);
+    }
+    pub fn is_playing(&self) -> bool {
+        self.state.playing.load(Ordering::Acquire)
+    }
+    /// a duration of 0 turns crossfading off
+    pub fn set_crossfade(&self, duration_ms: u32, curve: FadeCurve) {
+        self.state.crossfade.set(duration_ms, curve);
+    }
+    /// `level` goes from 0 to 1
+    pub fn set_volume(&self, level: f32) {
+        self.state.volume.set_level(level);
+    }
+    pub fn volume(&self) -> f32 {
+        self.state.volume.level()
+    }
+    pub fn set_muted(&self, muted: bool) {
+        self.state.volume.set_muted(muted);
     }
+    pub fn muted(&self) -> bool {
+        self.state.volume.muted()
+    }
+    /// a flat curve turns the eq off
+    pub fn set_eq(&self, curve: EqCurve) {
+        self.state.eq.send(curve);
+    }
+    pub fn set_stereo(&self, settings: StereoSettings) {
+        self.state.stereo.set(settings);
+    }
+    /// metering is off until something wants to show the levels
+    pub fn set_metering(&self, on: bool) {
+        if on && !self.metering() {
+            // whatever is still in the tap was played before it was turned
+            // off, and the output won't add to it until it's back on
+            self.meter.lock().unwrap().clear();
+        }
+        self.state.metering.store(on, Ordering::Release);
+    }
+    pub fn metering(&self) -> bool {
+        self.state.metering.load(Ordering::Acquire)
+    }
+    /// the levels of what's been played since the last call, `None` if
+    /// metering is off or nothing was played
+    pub fn read_levels(&self) -> Option<Levels> {
+        if !self.metering() {
+            return None;
+        }
+        self.meter.lock().unwrap().read()
+    }
+    /// with this on, tracks the device can play at their own sample rate
+    /// aren't resampled, the output gets reopened at their rate instead.
+    /// only tracks spawned after this is changed are affected
+    pub fn set_native_rate(&self, native: bool) {
+        self.native_rate.store(native, Ordering::Release);
+    }
+    /// whether the main stream threw away what was queued since the last
+    /// time this was called, because a track was spawned at a rate the
+    /// output couldn't be reopened at. tracks spawned from now on get
+    /// resampled instead, so whatever was playing should be queued again
+    pub fn take_requeue(&self) -> bool {
+        self.state.requeue.swap(false, Ordering::AcqRel)
+    }
+    /// whether the output moved to another device on its own since the last
+    /// time this was called
+    pub fn take_device_change(&self) -> Option<DeviceChange> {
+        self.device_change.lock().unwrap().take()
+    }
+    /// moves the output over to the device called `name`, or the default
+    /// one. this blocks until the new device is up and running. anything
+    /// that's queued was made for the old device, so clear first
+    pub fn switch_device(&self, name: Option<String>) -> Result<()> {
+        let (done_send, done_recv) = mpsc::channel();
+        self.commands
+            .send(OutputCommand::SwitchDevice(name, done_send))
+            .map_err(|_| Error::AudioDevice("audio output thread died".into()))?;
+        done_recv
+            .recv()
+            .map_err(|_| Error::AudioDevice("audio output thread died".into()))?
+    }
+
+    /// `channels` is the layout the track gets decoded to, it's mixed to fit
+    /// the output device before it goes in the track stream
+    pub fn spawn_track_stream(
+        &self,
+        in_rate: u32,
+        channels: Channels,
+    ) -> (TrackStream, TrackStreamHandle) {
+        let (out_rate, out_channels) = {
+            let format = self.format.lock().unwrap();
+            let native = self.native_rate.load(Ordering::Acquire) && format.is_native(in_rate);
+            match native {
+                true => (in_rate, format.channels),
+                false => (format.default_rate, format.channels),
+            }
+        };
 
-    pub fn spawn_track_stream(&self, in_rate: u32) -> (TrackStream, TrackStreamHandle) {
-        let (sample_send, sample_recv) = RingBuffer::new(4096);
+        let (sample_send, sample_recv) = RingBuffer::new(TRACK_BUFFER_FRAMES * out_channels);
         let (wake_send, wake_rec) = RingBuffer::new(1);
+        let state = Arc::new(TrackStreamState::default());
+        let clears = self.state.clears.load(Ordering::Acquire);
         (
-            TrackStream::new(sample_recv, wake_rec),
-            TrackStreamHandle::new(sample_send, wake_send, in_rate, self.out_rate),
+            TrackStream::new(
+                sample_recv,
+                wake_rec,
+                state.clone(),
+                out_rate,
+                out_channels,
+                clears,
+            ),
+            TrackStreamHandle::new(
+                sample_send,
+                wake_send,
+                state,
+                in_rate,
+                out_rate,
+                ChannelMixer::new(channels, out_channels),
+            ),
         )
     }
 }
 
-struct MainStream {
+pub struct MainStream {
     current_track: Option<TrackStream>,
+    /// the track after the current one, this only gets taken off the queue
+    /// early when we're getting ready to crossfade into it
+    next_track: Option<TrackStream>,
     queue: Consumer<TrackStream>,
-    playing: Arc<AtomicBool>,
-    clear: Arc<AtomicBool>,
+    state: Arc<MainStreamState>,
+    /// length in samples of the crossfade that's in progress, if there is one
+    fade_len: Option<u64>,
+    fade_out_buf: Vec<f32>,
+    fade_in_buf: Vec<f32>,
+    /// where everything gets mixed before it's converted to the device's
+    /// sample format
+    out_buf: Vec<f32>,
+    /// `None` while the eq is off, so it doesn't cost anything
+    eq: Option<Equalizer>,
+    stereo: StereoStage,
+    gain: GainRamp,
+    /// where the output gets copied for metering
+    tap: MeterTap,
+    out_rate: u32,
+    out_channels: usize,
+    /// asks the output thread to reopen the output at a new rate
+    reopen: SyncSender<OutputCommand>,
+    /// set while we're waiting for the output to be reopened
+    reopening: bool,
+    /// what the output can play, shared with the handle
+    format: Arc<Mutex<OutputFormat>>,
+    /// the last clear we dealt with
+    clears: u64,
 }
 impl MainStream {
-    pub fn new(
+    fn new(
         queue: Consumer<TrackStream>,
-        playing: Arc<AtomicBool>,
-        clear: Arc<AtomicBool>,
+        state: Arc<MainStreamState>,
+        out_rate: u32,
+        out_channels: usize,
+        format: Arc<Mutex<OutputFormat>>,
+        reopen: SyncSender<OutputCommand>,
+        tap: MeterTap,
     ) -> Self {
         Self {
             queue,
             current_track: None,
-            playing,
-            clear,
+            next_track: None,
+            gain: GainRamp::new(&state.volume),
+            eq: state.eq.rebuild(out_rate, out_channels),
+            state,
+            fade_len: None,
+            fade_out_buf: Vec::new(),
+            fade_in_buf: Vec::new(),
+            out_buf: Vec::new(),
+            stereo: StereoStage::default(),
+            tap,
+            out_rate,
+            out_channels,
+            reopen,
+            reopening: false,
+            format,
+            clears: 0,
         }
     }
 
-    pub fn cb<S: Sample + Silence + FromSample<f32>>(&mut self, buf: &mut [S]) {
-        // output silence by default
-        buf.fill(S::silence());
+    /// the output is (re)starting at `rate` with `channels`
+    pub fn output_started(&mut self, rate: u32, channels: usize) {
+        self.out_rate = rate;
+        self.out_channels = channels;
+        self.eq = self.state.eq.rebuild(rate, channels);
+        self.reopening = false;
+    }
 
-        if self.clear.load(Ordering::Acquire) {
-            let c = self.queue.read_chunk(self.queue.slots()).unwrap();
-            c.commit_all();
-            self.current_track = None;
-            self.clear.store(false, Ordering::Release);
+    /// the output couldn't be reopened at `rate`. a track only gets spawned
+    /// at a rate that isn't the output's own when the output should be able
+    /// to reopen at it, so there's nothing that can be done with what's
+    /// queued. it's all thrown away for the player to queue again, and
+    /// tracks spawned from now on get resampled
+    pub fn rate_unavailable(&mut self, rate: u32) {
+        self.format.lock().unwrap().unavailable_rates.push(rate);
+        self.state.clears.fetch_add(1, Ordering::AcqRel);
+        self.state.requeue.store(true, Ordering::Release);
+    }
+
+    /// the output moved to another device. if its format is different
+    /// nothing that's queued fits it anymore
+    fn device_changed(&mut self, format_changed: bool) {
+        if format_changed {
+            self.state.clears.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    pub fn cb<S: Sample + FromSample<f32>>(&mut self, buf: &mut [S]) {
+        // this only allocates the first time around (or if the device starts
+        // asking for bigger buffers)
+        let mut out = std::mem::take(&mut self.out_buf);
+        out.clear();
+        out.resize(buf.len(), 0.0);
+
+        self.render(&mut out);
+        self.state.eq.swap(&mut self.eq);
+        if let Some(eq) = self.eq.as_mut() {
+            eq.process(&mut out, self.out_channels);
         }
+        self.stereo.process(
+            &self.state.stereo,
+            &mut out,
+            self.out_channels,
+            self.out_rate,
+        );
+        self.gain.apply(
+            &self.state.volume,
+            &mut out,
+            self.out_channels,
+            self.out_rate,
+        );
+        if self.state.metering.load(Ordering::Acquire) {
+            self.tap.push(&out, self.out_channels, self.out_rate);
+        }
+
+        for (b, s) in buf.iter_mut().zip(&out) {
+            *b = S::from_sample(*s);
+        }
+        self.out_buf = out;
+    }
+
+    /// fills `buf` with whatever should be playing, it starts out silent
+    fn render(&mut self, buf: &mut [f32]) {
+        self.drop_stale();
 
-        if self.playing.load(Ordering::Acquire) {
+        // flushes have to go through even while we're paused, otherwise a
+        // seek would have to wait for playback to resume. that goes for a
+        // track that's queued up to play next too, which gets taken off the
+        // queue early so it can be flushed
+        if self.current_track.is_none()
+            && self.next_track.is_none()
+            && self.queue_front().is_some_and(|t| t.flush_requested())
+        {
+            self.next_track = self.pop_queue();
+        }
+        for t in self
+            .current_track
+            .iter_mut()
+            .chain(self.next_track.iter_mut())
+        {
+            t.flush_if_requested();
+        }
+
+        if self.state.playing.load(Ordering::Acquire) {
             // set up current track if needed
             if self.current_track.is_none() {
-                match self.queue.pop() {
-                    Ok(t) => {
-                        self.current_track = Some(t);
-                    }
-                    Err(_) => return,
+                match self.next_rate() {
+                    // silence until the output is running at the track's rate
+                    Some(rate) if self.needs_reopen(rate) => return,
+                    Some(_) => self.current_track = self.pop_next(),
+                    None => return,
                 }
             }
 
+            self.start_crossfade_if_due();
+            if let Some(fade_len) = self.fade_len {
+                self.mix_crossfade(buf, fade_len);
+                return;
+            }
+
             // ask current track to fill up samples
             if let ReadSamplesResult::Done(n) =
                 self.current_track.as_mut().unwrap().read_samples(buf)
             {
-                if let Ok(mut t) = self.queue.pop() {
-                    t.read_samples(&mut buf[n..]);
-                    self.current_track = Some(t);
+                match self.next_rate() {
+                    // the next track starts once the output has been
+                    // reopened, there's no running straight on across that
+                    Some(rate) if self.needs_reopen(rate) => self.current_track = None,
+                    Some(_) => {
+                        let mut t = self.pop_next().unwrap();
+                        t.read_samples(&mut buf[n..]);
+                        self.current_track = Some(t);
+                    }
+                    None => {}
                 }
             }
         }
     }
+
+    /// throws away everything that was queued before the last clear. this
+    /// happens whether we're playing or not, otherwise the queue would fill
+    /// up with cleared tracks while we're paused
+    fn drop_stale(&mut self) {
+        let clears = self.state.clears.load(Ordering::Acquire);
+        if clears != self.clears {
+            self.current_track = None;
+            self.next_track = None;
+            self.fade_len = None;
+            self.clears = clears;
+        }
+        self.queue_front();
+    }
+
+    fn pop_next(&mut self) -> Option<TrackStream> {
+        self.next_track.take().or_else(|| self.pop_queue())
+    }
+
+    fn pop_queue(&mut self) -> Option<TrackStream> {
+        self.queue_front()?;
+        self.queue.pop().ok()
+    }
+
+    /// the track at the front of the queue, after throwing away any that
+    /// were made before the last clear. those can still turn up behind newer
+    /// tracks if whatever made them was still running when the clear
+    /// happened. a track made after a clear we haven't dealt with yet has to
+    /// wait for the next callback
+    fn queue_front(&mut self) -> Option<&TrackStream> {
+        while self.queue.peek().is_ok_and(|t| t.clears < self.clears) {
+            let _ = self.queue.pop();
+        }
+        self.queue.peek().ok().filter(|t| t.clears == self.clears)
+    }
+
+    /// the sample rate of the track that plays next, without taking it off
+    /// the queue
+    fn next_rate(&mut self) -> Option<u32> {
+        if let Some(t) = &self.next_track {
+            return Some(t.rate);
+        }
+        self.queue_front().map(|t| t.rate)
+    }
+
+    /// whether the output has to be reopened before a track at `rate` can
+    /// play, asks for it if it hasn't already
+    fn needs_reopen(&mut self, rate: u32) -> bool {
+        if rate == self.out_rate {
+            return false;
+        }
+        if !self.reopening {
+            // if this doesn't go through there's a request in already, and
+            // we'll ask again once that's been dealt with
+            self.reopening = self.reopen.try_send(OutputCommand::Reopen(rate)).is_ok();
+        }
+        true
+    }
+
+    /// starts fading into the next track once the current one is within the
+    /// crossfade duration of its end, unless the two run into each other
+    fn start_crossfade_if_due(&mut self) {
+        if self.fade_len.is_some() {
+            return;
+        }
+        let fade_samples = self
+            .state
+            .crossfade
+            .samples(self.out_rate, self.out_channels);
+        if fade_samples == 0 {
+            return;
+        }
+        let Some(remaining) = self.current_track.as_ref().unwrap().remaining() else {
+            return;
+        };
+        if remaining > fade_samples {
+            return;
+        }
+
+        if self.next_track.is_none() {
+            self.next_track = self.pop_queue();
+        }
+        let Some(next) = self.next_track.as_mut() else {
+            return;
+        };
+        // the two can't be mixed if they're at different rates
+        if next.rate != self.out_rate {
+            return;
+        }
+        // if we can't tell yet, we'll check again next time around
+        if next.continues_gaplessly() == Some(false) {
+            self.fade_len = Some(remaining.max(1));
+        }
+    }
+
+    fn mix_crossfade(&mut self, buf: &mut [f32], fade_len: u64) {
+        // these only allocate the first time around (or if the device starts
+        // asking for bigger buffers)
+        self.fade_out_buf.clear();
+        self.fade_out_buf.resize(buf.len(), 0.0);
+        self.fade_in_buf.clear();
+        self.fade_in_buf.resize(buf.len(), 0.0);
+
+        let current = self.current_track.as_mut().unwrap();
+        let faded = fade_len - current.remaining().unwrap_or(0).min(fade_len);
+        let current_done = matches!(
+            current.read_samples(&mut self.fade_out_buf[..]),
+            ReadSamplesResult::Done(_)
+        );
+        self.next_track
+            .as_mut()
+            .unwrap()
+            .read_samples(&mut self.fade_in_buf[..]);
+
+        let curve = self.state.crossfade.curve();
+        for (i, b) in buf.iter_mut().enumerate() {
+            let t = ((faded + i as u64) as f32 / fade_len as f32).min(1.0);
+            *b = self.fade_out_buf[i] * curve.gain(1.0 - t) + self.fade_in_buf[i] * curve.gain(t);
+        }
+
+        if current_done {
+            self.current_track = self.next_track.take();
+            self.fade_len = None;
+        }
+    }
+
+    /// how many tracks are still to play, counting the one that's playing
+    #[cfg(test)]
+    pub fn tracks_left(&mut self) -> usize {
+        let clears = self.state.clears.load(Ordering::Acquire);
+        let queued = self.queue.read_chunk(self.queue.slots()).unwrap();
+        let (q1, q2) = queued.as_slices();
+        self.current_track
+            .iter()
+            .chain(&self.next_track)
+            .chain(q1)
+            .chain(q2)
+            .filter(|t| t.clears == clears && !t.state.finished.load(Ordering::Acquire))
+            .count()
+    }
+
+    /// whether playing `samples` more would run dry partway through a track
+    /// that's still being sent
+    #[cfg(test)]
+    pub fn starved(&mut self, samples: usize) -> bool {
+        let clears = self.state.clears.load(Ordering::Acquire);
+        let queued = self.queue.read_chunk(self.queue.slots()).unwrap();
+        let (q1, q2) = queued.as_slices();
+        let mut needed = samples;
+        for t in self
+            .current_track
+            .iter()
+            .chain(&self.next_track)
+            .chain(q1)
+            .chain(q2)
+            .filter(|t| t.clears == clears)
+        {
+            let buffered = t.recv.slots();
+            if buffered >= needed {
+                return false;
+            }
+            if !t.recv.is_abandoned() {
+                return true;
+            }
+            needed -= buffered;
+        }
+        false
+    }
 }
 
 fn build_main_stream<S>(
     device: &Device,
     config: &StreamConfig,
-    recv: Consumer<TrackStream>,
-    playing: Arc<AtomicBool>,
-    clear: Arc<AtomicBool>,
-) -> Result<Stream, ()>
+    main_stream: Arc<Mutex<MainStream>>,
+    errors: ErrorReporter,
+) -> Result<Stream>
 where
     S: SizedSample + FromSample<f32> + Silence + Send + 'static,
 {
-    let mut ms = MainStream::new(recv, playing, clear);
-    let stream = device
-        .build_output_stream(config, move |buf: &mut [S], _| ms.cb(buf), |_| {}, None)
-        .unwrap();
-    stream.play().unwrap();
+    let stream = device.build_output_stream(
+        config,
+        move |buf: &mut [S], _| match main_stream.try_lock() {
+            Ok(mut ms) => ms.cb(buf),
+            // the output is being reopened, this stream is on its way out
+            Err(_) => buf.fill(S::silence()),
+        },
+        move |e| errors.report(e),
+        None,
+    )?;
+    stream.play()?;
     Ok(stream)
 }
 
@@ -244,16 +1158,116 @@ enum ReadSamplesResult {
     Waiting,
 }
 
+/// state shared between a track stream and its handle
+pub struct TrackStreamState {
+    flush: AtomicBool,
+    /// where `played` should be after a flush
+    flush_to: AtomicU64,
+    /// how many samples the main stream has actually played
+    played: AtomicU64,
+    /// set once the main stream starts playing this track
+    started: AtomicBool,
+    /// set once everything has been sent and played
+    finished: AtomicBool,
+    /// how many samples the whole track comes out to, `u64::MAX` if we don't
+    /// know
+    length: AtomicU64,
+    /// set when this track picks up straight where the last one left off on
+    /// the same album
+    continues_album: AtomicBool,
+}
+impl Default for TrackStreamState {
+    fn default() -> Self {
+        Self {
+            flush: AtomicBool::new(false),
+            flush_to: AtomicU64::new(0),
+            played: AtomicU64::new(0),
+            started: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            length: AtomicU64::new(u64::MAX),
+            continues_album: AtomicBool::new(false),
+        }
+    }
+}
+
 pub struct TrackStream {
     recv: Consumer<f32>,
     wakers: Consumer<Waker>,
+    state: Arc<TrackStreamState>,
+    gapless: Option<bool>,
+    /// the sample rate the output has to run at to play this
+    rate: u32,
+    /// samples per frame
+    channels: usize,
+    /// how many times the main stream had been cleared when this was made
+    clears: u64,
 }
 impl TrackStream {
-    // TODO: channels (right now we assume everything is stereo)
-    pub fn new(recv: Consumer<f32>, wakers: Consumer<Waker>) -> Self {
-        Self { recv, wakers }
+    pub fn new(
+        recv: Consumer<f32>,
+        wakers: Consumer<Waker>,
+        state: Arc<TrackStreamState>,
+        rate: u32,
+        channels: usize,
+        clears: u64,
+    ) -> Self {
+        Self {
+            recv,
+            wakers,
+            state,
+            gapless: None,
+            rate,
+            channels,
+            clears,
+        }
+    }
+    /// how many samples are left to play, if we know how long the track is
+    fn remaining(&self) -> Option<u64> {
+        match self.state.length.load(Ordering::Acquire) {
+            u64::MAX => None,
+            length => Some(length.saturating_sub(self.state.played.load(Ordering::Acquire))),
+        }
+    }
+    /// whether this track runs straight on from the one before it, i.e. it's
+    /// the next track on the same album and it doesn't start with silence.
+    /// `None` means not enough of the track is buffered to tell yet
+    fn continues_gaplessly(&mut self) -> Option<bool> {
+        if !self.state.continues_album.load(Ordering::Acquire) {
+            return Some(false);
+        }
+        if self.gapless.is_none() {
+            let slots = self.recv.slots();
+            if slots < GAPLESS_PROBE_SAMPLES && !self.recv.is_abandoned() {
+                return None;
+            }
+            // this just peeks, nothing gets consumed unless we commit
+            let c = self.recv.read_chunk(slots).unwrap();
+            let (s1, s2) = c.as_slices();
+            self.gapless = Some(s1.iter().chain(s2).any(|s| s.abs() > SILENCE_THRESHOLD));
+        }
+        self.gapless
+    }
+    fn flush_requested(&self) -> bool {
+        self.state.flush.load(Ordering::Acquire)
+    }
+    /// throws away everything that's buffered if the handle asked us to
+    fn flush_if_requested(&mut self) {
+        if self.flush_requested() {
+            let c = self.recv.read_chunk(self.recv.slots()).unwrap();
+            c.commit_all();
+            self.state.played.store(
+                self.state.flush_to.load(Ordering::Acquire),
+                Ordering::Release,
+            );
+            self.state.flush.store(false, Ordering::Release);
+            if let Ok(w) = self.wakers.pop() {
+                w.wake();
+            }
+        }
     }
     fn read_samples<S: Sample + FromSample<f32>>(&mut self, buf: &mut [S]) -> ReadSamplesResult {
+        self.state.started.store(true, Ordering::Release);
+        self.flush_if_requested();
         match self.recv.read_chunk(buf.len()) {
             Ok(c) => {
                 let (s1, s2) = c.as_slices();
@@ -261,6 +1275,9 @@ impl TrackStream {
                     *b = S::from_sample(*s);
                 }
                 c.commit_all();
+                self.state
+                    .played
+                    .fetch_add(buf.len() as u64, Ordering::AcqRel);
                 if let Ok(w) = self.wakers.pop() {
                     w.wake();
                 }
@@ -269,14 +1286,18 @@ impl TrackStream {
             Err(e) => {
                 let n = {
                     match e {
-                        ChunkError::TooFewSlots(0) => 0,
+                        // only whole frames, or every channel would end up
+                        // shifted over for the rest of the track
+                        ChunkError::TooFewSlots(n) if n < self.channels => 0,
                         ChunkError::TooFewSlots(n) => {
+                            let n = n - n % self.channels;
                             let c = self.recv.read_chunk(n).unwrap();
                             let (s1, s2) = c.as_slices();
                             for (s, b) in s1.iter().chain(s2.iter()).zip(buf.iter_mut()) {
                                 *b = S::from_sample(*s);
                             }
                             c.commit_all();
+                            self.state.played.fetch_add(n as u64, Ordering::AcqRel);
                             if let Ok(w) = self.wakers.pop() {
                                 w.wake();
                             }
@@ -285,6 +1306,7 @@ impl TrackStream {
                     }
                 };
                 if self.recv.is_abandoned() {
+                    self.state.finished.store(true, Ordering::Release);
                     ReadSamplesResult::Done(n)
                 } else {
                     ReadSamplesResult::Waiting
@@ -296,37 +1318,116 @@ impl TrackStream {
 pub struct TrackStreamHandle {
     send: Producer<f32>,
     waker: Producer<Waker>,
-    sample_rate_converter: FftFixedIn<f32>,
+    state: Arc<TrackStreamState>,
+    in_rate: u32,
+    out_rate: u32,
+    /// `None` when the track is already at the output rate
+    resampler: Option<ResampleStage>,
+    mixer: ChannelMixer,
+    /// replaygain, applied to everything that gets sent
+    gain: f32,
 }
 impl TrackStreamHandle {
-    pub fn new(send: Producer<f32>, waker: Producer<Waker>, in_rate: u32, out_rate: u32) -> Self {
+    pub fn new(
+        send: Producer<f32>,
+        waker: Producer<Waker>,
+        state: Arc<TrackStreamState>,
+        in_rate: u32,
+        out_rate: u32,
+        mixer: ChannelMixer,
+    ) -> Self {
         Self {
             send,
             waker,
-            sample_rate_converter: FftFixedIn::new(in_rate as usize, out_rate as usize, 256, 2, 2)
-                .unwrap(),
+            state,
+            in_rate,
+            out_rate,
+            resampler: (in_rate != out_rate)
+                .then(|| ResampleStage::new(in_rate, out_rate, mixer.in_channels())),
+            mixer,
+            gain: 1.0,
+        }
+    }
+    /// samples per frame in the track stream, i.e. the output device's
+    /// channel count
+    fn out_channels(&self) -> u64 {
+        self.mixer.out_channels() as u64
+    }
+    /// lets the main stream know how long the track is, `frames` is at the
+    /// track's own sample rate
+    pub fn set_length(&self, frames: u64) {
+        let samples = frames * self.out_rate as u64 / self.in_rate as u64 * self.out_channels();
+        self.state.length.store(samples, Ordering::Release);
+    }
+    /// scales everything sent from now on by `gain`. if that would push
+    /// samples past full scale they're clipped, so the gain should already
+    /// have been pulled back to fit the track's peak
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+    /// marks this track as the one following the last track queued on the
+    /// same album, so a crossfade can be skipped if the two run together
+    pub fn set_continues_album(&self, continues: bool) {
+        self.state
+            .continues_album
+            .store(continues, Ordering::Release);
+    }
+    pub fn progress(&self) -> TrackProgress {
+        TrackProgress {
+            state: self.state.clone(),
+            out_rate: self.out_rate,
+            out_channels: self.out_channels(),
+        }
+    }
+    /// drops everything that has been sent but not played yet, `position` is
+    /// where in the track (in seconds) the next samples sent will be from.
+    /// this waits until the main stream has actually thrown the samples away
+    pub async fn flush(&mut self, position: f64) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+        let samples = (position * self.out_rate as f64) as u64 * self.out_channels();
+        self.state.flush_to.store(samples, Ordering::Release);
+        self.state.flush.store(true, Ordering::Release);
+        FlushFut {
+            flush: &self.state.flush,
+            waker: &mut self.waker,
         }
+        .await;
     }
+    /// `buf` is planar, one channel after the other
     pub async fn send(&mut self, buf: &[f32]) {
-        let left = &buf[0..buf.len() / 2];
-        let right = &buf[buf.len() / 2..];
+        if buf.is_empty() {
+            return;
+        }
+        let planes: Vec<_> = buf
+            .chunks_exact(buf.len() / self.mixer.in_channels())
+            .collect();
 
         let mut interleaved = Vec::new();
-        let left_chunks = left.chunks_exact(256);
-        let right_chunks = right.chunks_exact(256);
-        let left_rem = left_chunks.remainder();
-        let right_rem = right_chunks.remainder();
-        println!("{}", left_rem.len());
-        println!("{}", right_rem.len());
-        for (left_chunk, right_chunk) in left_chunks.zip(right_chunks) {
-            let resampled = self
-                .sample_rate_converter
-                .process(&[left_chunk, right_chunk], None)
-                .unwrap();
-            for i in 0..resampled[0].len() {
-                for ch in &resampled {
-                    interleaved.push(ch[i]);
-                }
+        match self.resampler.as_mut() {
+            Some(resampler) => self
+                .mixer
+                .mix_into(&resampler.process(&planes), &mut interleaved),
+            None => self.mixer.mix_into(&planes, &mut interleaved),
+        }
+        self.push(&mut interleaved).await;
+    }
+    /// sends whatever the resampler was still holding on to, call this once
+    /// everything else has been sent
+    pub async fn finish(&mut self) {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return;
+        };
+        let resampled = resampler.finish();
+        let mut interleaved = Vec::new();
+        self.mixer.mix_into(&resampled, &mut interleaved);
+        self.push(&mut interleaved).await;
+    }
+    async fn push(&mut self, interleaved: &mut [f32]) {
+        if self.gain != 1.0 {
+            for s in interleaved.iter_mut() {
+                *s = (*s * self.gain).clamp(-1.0, 1.0);
             }
         }
 
@@ -336,6 +1437,7 @@ impl TrackStreamHandle {
             let slots = SendFut {
                 send: &mut self.send,
                 waker: &mut self.waker,
+                frame: self.mixer.out_channels(),
             }
             .await;
 
@@ -350,9 +1452,34 @@ impl TrackStreamHandle {
         }
     }
 }
+/// how far into its track a track stream has been played
+#[derive(Clone)]
+pub struct TrackProgress {
+    state: Arc<TrackStreamState>,
+    out_rate: u32,
+    out_channels: u64,
+}
+impl TrackProgress {
+    /// in seconds
+    pub fn position(&self) -> f64 {
+        let frames = self.state.played.load(Ordering::Acquire) / self.out_channels;
+        frames as f64 / self.out_rate as f64
+    }
+    pub fn started(&self) -> bool {
+        self.state.started.load(Ordering::Acquire)
+    }
+    pub fn finished(&self) -> bool {
+        self.state.finished.load(Ordering::Acquire)
+    }
+}
+
+/// waits for room for at least one frame, and gives how many samples of
+/// whole frames there's room for
 pub struct SendFut<'a> {
     send: &'a mut Producer<f32>,
     waker: &'a mut Producer<Waker>,
+    /// samples per frame
+    frame: usize,
 }
 impl Future for SendFut<'_> {
     type Output = usize;
@@ -362,11 +1489,35 @@ impl Future for SendFut<'_> {
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
         match self.send.slots() {
-            0 => {
-                self.waker.push(cx.waker().clone()).unwrap();
+            n if n < self.frame => {
+                // a waker might still be in there from a send that got
+                // cancelled, it belongs to this same task so that's fine
+                let _ = self.waker.push(cx.waker().clone());
                 Poll::Pending
             }
-            n => Poll::Ready(n),
+            n => Poll::Ready(n - n % self.frame),
+        }
+    }
+}
+pub struct FlushFut<'a> {
+    flush: &'a AtomicBool,
+    waker: &'a mut Producer<Waker>,
+}
+impl Future for FlushFut<'_> {
+    type Output = ();
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        if !self.flush.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        let _ = self.waker.push(cx.waker().clone());
+        // check again in case the flush happened before the waker got there
+        match self.flush.load(Ordering::Acquire) {
+            true => Poll::Pending,
+            false => Poll::Ready(()),
         }
     }
 }
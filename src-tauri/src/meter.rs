@@ -0,0 +1,289 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::Serialize;
+
+/// left and right samples, enough for a few reads at high sample rates. if
+/// the reader falls behind the newest samples get dropped
+const TAP_CAPACITY: usize = 1 << 16;
+const FFT_SIZE: usize = 2048;
+/// how many bars the spectrum is split into, spaced evenly in pitch
+const SPECTRUM_BANDS: usize = 32;
+const LOWEST_FREQUENCY: f32 = 20.0;
+const HIGHEST_FREQUENCY: f32 = 20_000.0;
+/// anything quieter than this is reported as this, so silence doesn't come
+/// out as -inf
+const FLOOR_DB: f32 = -90.0;
+
+/// how loud the output is, all in dBFS. levels are for left and right
+/// (both the same for mono), the spectrum goes from low to high
+#[derive(Serialize, Clone)]
+pub struct Levels {
+    pub peak: [f32; 2],
+    pub rms: [f32; 2],
+    pub spectrum: Vec<f32>,
+}
+
+/// makes a tap for the main stream to copy its output into, and the reader
+/// that works out the levels from it
+pub fn meter_tap() -> (MeterTap, MeterReader) {
+    let (send, recv) = RingBuffer::new(TAP_CAPACITY);
+    let rate = Arc::new(AtomicU32::new(48000));
+    let fft = RealFftPlanner::new().plan_fft_forward(FFT_SIZE);
+    (
+        MeterTap {
+            send,
+            rate: rate.clone(),
+        },
+        MeterReader {
+            recv,
+            rate,
+            window: (0..FFT_SIZE)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_SIZE as f32).cos())
+                .collect(),
+            history: VecDeque::with_capacity(FFT_SIZE),
+            input: fft.make_input_vec(),
+            output: fft.make_output_vec(),
+            fft,
+        },
+    )
+}
+
+/// the main stream's end of the tap, this never blocks or allocates
+pub struct MeterTap {
+    send: Producer<f32>,
+    rate: Arc<AtomicU32>,
+}
+impl MeterTap {
+    /// copies the front left and right of `buf` (interleaved, `channels`
+    /// per frame) into the tap, as much as fits
+    pub fn push(&mut self, buf: &[f32], channels: usize, rate: u32) {
+        self.rate.store(rate, Ordering::Release);
+        let frames = (buf.len() / channels).min(self.send.slots() / 2);
+        let Ok(chunk) = self.send.write_chunk_uninit(frames * 2) else {
+            return;
+        };
+        let pairs = buf
+            .chunks(channels)
+            .flat_map(|f| [f[0], f[channels.min(2) - 1]]);
+        chunk.fill_from_iter(pairs);
+    }
+}
+
+/// works out levels from whatever came through the tap since the last read
+pub struct MeterReader {
+    recv: Consumer<f32>,
+    rate: Arc<AtomicU32>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    /// hann window
+    window: Vec<f32>,
+    /// the last `FFT_SIZE` samples of the left and right mixed together
+    history: VecDeque<f32>,
+    input: Vec<f32>,
+    output: Vec<Complex<f32>>,
+}
+impl MeterReader {
+    /// throws away everything in the tap, and what's left of the spectrum
+    pub fn clear(&mut self) {
+        if let Ok(chunk) = self.recv.read_chunk(self.recv.slots()) {
+            chunk.commit_all();
+        }
+        self.history.clear();
+    }
+
+    /// `None` if nothing was played since the last read
+    pub fn read(&mut self) -> Option<Levels> {
+        let chunk = self.recv.read_chunk(self.recv.slots() & !1).ok()?;
+        if chunk.is_empty() {
+            return None;
+        }
+
+        let mut peak = [0f32; 2];
+        let mut power = [0f64; 2];
+        let mut frames = 0;
+        let mut samples = chunk.into_iter();
+        while let (Some(l), Some(r)) = (samples.next(), samples.next()) {
+            for (c, s) in [l, r].into_iter().enumerate() {
+                peak[c] = peak[c].max(s.abs());
+                power[c] += (s * s) as f64;
+            }
+            if self.history.len() == FFT_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back((l + r) * 0.5);
+            frames += 1;
+        }
+        drop(samples);
+
+        Some(Levels {
+            peak: peak.map(db),
+            rms: power.map(|p| db((p / frames as f64).sqrt() as f32)),
+            spectrum: self.spectrum(),
+        })
+    }
+
+    fn spectrum(&mut self) -> Vec<f32> {
+        // start off zero padded until there's a full window
+        let pad = FFT_SIZE - self.history.len();
+        self.input[..pad].fill(0.0);
+        for ((i, s), w) in self.input[pad..]
+            .iter_mut()
+            .zip(&self.history)
+            .zip(&self.window[pad..])
+        {
+            *i = s * w;
+        }
+        self.fft
+            .process(&mut self.input, &mut self.output)
+            .expect("fft buffers are the wrong size");
+
+        // so a full scale sine comes out at 0dB
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        let rate = self.rate.load(Ordering::Acquire) as f32;
+        let bin_width = rate / FFT_SIZE as f32;
+        let highest = HIGHEST_FREQUENCY.min(rate / 2.0);
+        let step = (highest / LOWEST_FREQUENCY).powf(1.0 / SPECTRUM_BANDS as f32);
+
+        (0..SPECTRUM_BANDS)
+            .map(|band| {
+                let low = LOWEST_FREQUENCY * step.powi(band as i32);
+                let first = (low / bin_width).round() as usize;
+                // low bands can be narrower than a bin, they get at least one
+                let last = ((low * step / bin_width).round() as usize).max(first + 1);
+                let magnitude = self.output[first..last.min(self.output.len())]
+                    .iter()
+                    .map(|c| c.norm())
+                    .fold(0.0, f32::max);
+                db(magnitude * scale)
+            })
+            .collect()
+    }
+}
+
+fn db(level: f32) -> f32 {
+    (20.0 * level.log10()).max(FLOOR_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `frames` of a full scale sine at `frequency`, on `channels` channels
+    fn sine(frequency: f32, frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * PI * frequency * i as f32 / 48000.0).sin();
+                vec![s; channels]
+            })
+            .collect()
+    }
+
+    fn measure(buf: &[f32], channels: usize) -> Levels {
+        let (mut tap, mut reader) = meter_tap();
+        tap.push(buf, channels, 48000);
+        reader.read().unwrap()
+    }
+
+    /// how much higher each band starts than the one before it
+    fn band_step() -> f32 {
+        (HIGHEST_FREQUENCY / LOWEST_FREQUENCY).powf(1.0 / SPECTRUM_BANDS as f32)
+    }
+
+    /// the band `frequency` should land in
+    fn band_for(frequency: f32) -> usize {
+        ((frequency / LOWEST_FREQUENCY).ln() / band_step().ln()) as usize
+    }
+
+    fn loudest(spectrum: &[f32]) -> usize {
+        (0..spectrum.len())
+            .max_by(|a, b| spectrum[*a].total_cmp(&spectrum[*b]))
+            .unwrap()
+    }
+
+    #[test]
+    fn full_scale_sine_is_0db_in_its_band() {
+        // right in the middle of an fft bin, so none of it leaks out
+        let frequency = 43.0 * 48000.0 / FFT_SIZE as f32;
+        let levels = measure(&sine(frequency, FFT_SIZE, 2), 2);
+
+        let band = band_for(frequency);
+        assert_eq!(loudest(&levels.spectrum), band);
+        assert!(
+            levels.spectrum[band].abs() < 0.1,
+            "{} dB",
+            levels.spectrum[band]
+        );
+        // the window keeps it from smearing over the far away bands
+        assert!(levels.spectrum[band - 4] < -60.0);
+        assert!(levels.spectrum[band + 4] < -60.0);
+    }
+
+    #[test]
+    fn sines_land_in_their_bands() {
+        for band in [6, 12, 18, 24, 30] {
+            // the middle of the band, the low ones are only a few fft bins
+            // wide so anything near the edge can end up next door
+            let frequency = LOWEST_FREQUENCY * band_step().powf(band as f32 + 0.5);
+            let levels = measure(&sine(frequency, FFT_SIZE, 2), 2);
+            assert_eq!(loudest(&levels.spectrum), band, "{frequency}Hz");
+            // off the middle of a bin the window loses up to 1.5dB
+            assert!(levels.spectrum[band] > -1.5, "{frequency}Hz");
+        }
+    }
+
+    #[test]
+    fn sine_peaks_at_0db_with_rms_3db_down() {
+        let levels = measure(&sine(1000.0, 4800, 2), 2);
+        for c in 0..2 {
+            assert!(levels.peak[c].abs() < 0.01);
+            assert!((levels.rms[c] - -3.01).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn left_and_right_are_the_front_pair() {
+        // 5.1 with something only on the right, and loud centre and lfe
+        let buf: Vec<f32> = (0..1000)
+            .flat_map(|_| [0.0, 0.5, 1.0, 1.0, 0.0, 0.0])
+            .collect();
+        let levels = measure(&buf, 6);
+        assert_eq!(levels.peak[0], FLOOR_DB);
+        assert!((levels.peak[1] - db(0.5)).abs() < 1e-4);
+
+        // mono comes out on both
+        let levels = measure(&vec![0.5; 1000], 1);
+        assert_eq!(levels.peak[0], levels.peak[1]);
+    }
+
+    #[test]
+    fn silence_is_the_floor() {
+        let levels = measure(&vec![0.0; 4096], 2);
+        assert!(levels
+            .peak
+            .iter()
+            .chain(&levels.rms)
+            .all(|l| *l == FLOOR_DB));
+        assert!(levels.spectrum.iter().all(|l| *l == FLOOR_DB));
+    }
+
+    #[test]
+    fn nothing_is_left_after_clearing() {
+        let (mut tap, mut reader) = meter_tap();
+        tap.push(&sine(1000.0, 4800, 2), 2, 48000);
+        reader.clear();
+        assert!(reader.read().is_none());
+
+        tap.push(&vec![0.0; 4096], 2, 48000);
+        let levels = reader.read().unwrap();
+        // the sine from before doesn't show up in the spectrum either
+        assert!(levels.spectrum.iter().all(|l| *l == FLOOR_DB));
+    }
+}
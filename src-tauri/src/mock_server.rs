@@ -0,0 +1,824 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use serde_json::{json, Value};
+
+/// a pi-fi server for tests to talk to. it serves the library in
+/// `tests/fixtures/library.json`, and tracks and cover art from the files
+/// next to it named after their ids. it runs on threads of its own until the
+/// test binary exits
+pub struct MockServer {
+    url: String,
+    state: Arc<ServerState>,
+}
+
+struct ServerState {
+    fixtures: PathBuf,
+    /// tracks that get cut off after this many bytes, the response still
+    /// claims to be the full length
+    truncated: Mutex<HashMap<i64, usize>>,
+    /// tracks whose next full download gets cut off after this many bytes,
+    /// like a connection that sat idle for too long
+    hang_ups: Mutex<HashMap<i64, usize>>,
+    /// tracks that always get sent whole, whatever range was asked for
+    ignores_ranges: Mutex<HashSet<i64>>,
+    /// set once every request should be turned away, like after the token
+    /// we're using got revoked
+    unauthorized: AtomicBool,
+    /// what `/get-library-changes` sends for each revision it's asked for
+    /// changes since, any other revision isn't found
+    changes: Mutex<HashMap<String, Value>>,
+    requests: Mutex<Vec<Request>>,
+}
+
+/// a request the server got
+#[derive(Clone)]
+pub struct Request {
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub range: Option<String>,
+    pub authorization: Option<String>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(ServerState {
+            fixtures: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"),
+            truncated: Mutex::new(HashMap::new()),
+            hang_ups: Mutex::new(HashMap::new()),
+            ignores_ranges: Mutex::new(HashSet::new()),
+            unauthorized: AtomicBool::new(false),
+            changes: Mutex::new(HashMap::new()),
+            requests: Mutex::new(Vec::new()),
+        });
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let state = server_state.clone();
+                thread::spawn(move || state.handle(stream));
+            }
+        });
+        Self { url, state }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn fixture(&self, name: &str) -> Vec<u8> {
+        fs::read(self.state.fixtures.join(name)).unwrap()
+    }
+
+    /// makes the server hang up `bytes` into track `id` from now on
+    pub fn truncate(&self, id: i64, bytes: usize) {
+        self.state.truncated.lock().unwrap().insert(id, bytes);
+    }
+
+    /// makes the server hang up `bytes` into the next request for all of
+    /// track `id`, just the once
+    pub fn hang_up_once(&self, id: i64, bytes: usize) {
+        self.state.hang_ups.lock().unwrap().insert(id, bytes);
+    }
+
+    /// makes the server ignore range requests for track `id` from now on
+    pub fn ignore_ranges(&self, id: i64) {
+        self.state.ignores_ranges.lock().unwrap().insert(id);
+    }
+
+    /// makes the server turn every request away as unauthorized from now on
+    pub fn revoke_tokens(&self) {
+        self.state.unauthorized.store(true, Ordering::Release);
+    }
+
+    /// has `/get-library-changes?since={since}` send `changes` from now on
+    pub fn add_changes(&self, since: &str, changes: Value) {
+        self.state
+            .changes
+            .lock()
+            .unwrap()
+            .insert(since.into(), changes);
+    }
+
+    /// every request so far, oldest first
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.requests.lock().unwrap().clone()
+    }
+}
+
+impl ServerState {
+    /// answers a single request and hangs up, which keeps us from having to
+    /// deal with keep-alive
+    fn handle(&self, mut stream: TcpStream) {
+        let Some(request) = read_request(&stream) else {
+            return;
+        };
+        self.requests.lock().unwrap().push(request.clone());
+        let id = request
+            .query
+            .get("id")
+            .and_then(|id| id.parse::<i64>().ok());
+
+        if self.unauthorized.load(Ordering::Acquire) {
+            Response::unauthorized().write(&mut stream);
+            return;
+        }
+
+        let response = match (request.path.as_str(), id) {
+            ("/get-library", _) => Response::ok(self.library().to_string().into_bytes()),
+            ("/get-library-changes", _) => {
+                let since = request.query.get("since");
+                match since.and_then(|s| self.changes.lock().unwrap().get(s).cloned()) {
+                    Some(changes) => Response::ok(changes.to_string().into_bytes()),
+                    None => Response::not_found(),
+                }
+            }
+            ("/get-album", Some(id)) => match self.album(id) {
+                Some(album) => Response::ok(album.to_string().into_bytes()),
+                None => Response::not_found(),
+            },
+            ("/get-track", Some(id)) => match fs::read(self.fixtures.join(format!("{id}.flac"))) {
+                Ok(track) => {
+                    let range = match self.ignores_ranges.lock().unwrap().contains(&id) {
+                        true => None,
+                        false => request.range.as_deref(),
+                    };
+                    let hang_up = match range {
+                        None => self.hang_ups.lock().unwrap().remove(&id),
+                        Some(_) => None,
+                    };
+                    let truncated = self.truncated.lock().unwrap().get(&id).copied();
+                    let truncated = truncated.or(hang_up);
+                    Response::track(track, range, truncated)
+                }
+                Err(_) => Response::not_found(),
+            },
+            ("/get-image", Some(id)) => match fs::read(self.fixtures.join(format!("{id}.png"))) {
+                Ok(image) => Response::ok(image),
+                Err(_) => Response::not_found(),
+            },
+            _ => Response::not_found(),
+        };
+        response.write(&mut stream);
+    }
+
+    fn library(&self) -> Value {
+        let library = fs::read(self.fixtures.join("library.json")).unwrap();
+        serde_json::from_slice(&library).unwrap()
+    }
+
+    /// the album with its artist and tracks, the way `/get-album` sends it
+    fn album(&self, id: i64) -> Option<Value> {
+        let library = self.library();
+        let with_id = |list: &str, id: i64| {
+            library[list]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|v| v["id"] == id)
+                .cloned()
+        };
+        let album = with_id("albums", id)?;
+        let artist = with_id("artists", album["artist_id"].as_i64()?)?;
+        let tracks: Vec<_> = library["tracks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|t| t["album_id"] == id)
+            .cloned()
+            .collect();
+        Some(json!({ "album": album, "artist": artist, "tracks": tracks }))
+    }
+}
+
+/// reads the request line and headers, there's never a body
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let target = line.split_whitespace().nth(1)?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect();
+
+    let mut range = None;
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Some(Request {
+        path: path.into(),
+        query,
+        range,
+        authorization,
+    })
+}
+
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    /// how much of the body actually gets sent
+    cut_off_at: Option<usize>,
+}
+
+impl Response {
+    fn ok(body: Vec<u8>) -> Self {
+        Self {
+            status: "200 OK",
+            headers: Vec::new(),
+            body,
+            cut_off_at: None,
+        }
+    }
+
+    fn not_found() -> Self {
+        Self {
+            status: "404 Not Found",
+            headers: Vec::new(),
+            body: Vec::new(),
+            cut_off_at: None,
+        }
+    }
+
+    fn unauthorized() -> Self {
+        Self {
+            status: "401 Unauthorized",
+            headers: Vec::new(),
+            body: Vec::new(),
+            cut_off_at: None,
+        }
+    }
+
+    /// all of `track`, or from where `range` asks for. only `bytes=N-`
+    /// ranges are supported, that's all the player sends
+    fn track(track: Vec<u8>, range: Option<&str>, truncated: Option<usize>) -> Self {
+        let len = track.len();
+        let start = range
+            .and_then(|r| r.strip_prefix("bytes="))
+            .and_then(|r| r.strip_suffix('-'))
+            .and_then(|start| start.parse::<usize>().ok());
+        let mut response = match (range, start) {
+            (None, _) => Self::ok(track),
+            (Some(_), Some(start)) if start < len => Self {
+                status: "206 Partial Content",
+                headers: vec![("Content-Range", format!("bytes {start}-{}/{len}", len - 1))],
+                body: track[start..].to_vec(),
+                cut_off_at: None,
+            },
+            _ => Self {
+                status: "416 Range Not Satisfiable",
+                headers: vec![("Content-Range", format!("bytes */{len}"))],
+                body: Vec::new(),
+                cut_off_at: None,
+            },
+        };
+        response.headers.push(("Accept-Ranges", "bytes".into()));
+        // the cut off is in terms of the whole file
+        let start = start.unwrap_or(0);
+        response.cut_off_at = truncated.map(|t| t.saturating_sub(start));
+        response
+    }
+
+    fn write(self, stream: &mut TcpStream) {
+        let mut head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        head += "\r\n";
+
+        let sent = self.cut_off_at.unwrap_or(usize::MAX).min(self.body.len());
+        // the client hanging up early isn't our problem
+        let _ = stream
+            .write_all(head.as_bytes())
+            .and_then(|_| stream.write_all(&self.body[..sent]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        io::{Cursor, Read, Seek, SeekFrom},
+        process,
+        sync::atomic::AtomicUsize,
+        time::Duration,
+    };
+
+    use futures_util::{future::join_all, FutureExt};
+    use symphonia::core::{audio::SampleBuffer, io::MediaSourceStream, probe::Hint};
+    use tauri::{
+        ipc::{Channel, InvokeResponseBody},
+        test::{mock_app, MockRuntime},
+        App, Listener,
+    };
+    use tauri_plugin_http::reqwest::Client;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::{
+        auth::build_client,
+        cache::Cache,
+        error::Error,
+        http_source::HttpSource,
+        library_db::LibraryDb,
+        null_output::NullOutput,
+        player::{Player, PlayerUpdateMsg},
+    };
+
+    /// somewhere for a test to keep a library db of its own
+    fn db_path() -> PathBuf {
+        static DBS: AtomicUsize = AtomicUsize::new(0);
+        let n = DBS.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("pi-fi-mock-server-{}-{n}.redb", process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// a cache for `server` with a library db of its own, that hasn't been
+    /// refreshed yet
+    fn open_cache(server: &MockServer) -> Arc<Cache> {
+        let path = db_path();
+        let cache = open_cache_at(server, &path);
+        // the db keeps working off the open file, this just saves cleaning
+        // up after the test
+        let _ = fs::remove_file(&path);
+        cache
+    }
+
+    /// a cache for `server` that keeps its library in `path`, picking up
+    /// whatever was saved there before
+    fn open_cache_at(server: &MockServer, path: &Path) -> Arc<Cache> {
+        let db = LibraryDb::open(path).unwrap();
+        Arc::new(Cache::new(Client::new(), db, server.url().into()))
+    }
+
+    fn album_titles(cache: &Cache) -> Vec<String> {
+        let library = serde_json::to_value(cache.get_library().unwrap()).unwrap();
+        library["albums"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|a| a["title"].as_str().unwrap().into())
+            .collect()
+    }
+
+    /// decodes a fixture straight from the file, interleaved
+    fn decode_fixture(server: &MockServer, name: &str) -> Vec<f32> {
+        let file = Cursor::new(server.fixture(name));
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut reader = symphonia::default::get_probe()
+            .format(
+                Hint::new().with_extension("flac"),
+                mss,
+                &Default::default(),
+                &Default::default(),
+            )
+            .unwrap()
+            .format;
+        let track = reader.default_track().unwrap();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &Default::default())
+            .unwrap();
+
+        let mut samples = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            let buf = decoder.decode(&packet).unwrap();
+            let mut interleaved = SampleBuffer::new(buf.capacity() as u64, *buf.spec());
+            interleaved.copy_interleaved_ref(buf);
+            samples.extend_from_slice(interleaved.samples());
+        }
+        samples
+    }
+
+    /// everything the player sent to the frontend, as json
+    fn player_channel() -> (Channel<PlayerUpdateMsg>, Arc<Mutex<Vec<Value>>>) {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let sent = messages.clone();
+        let channel = Channel::new(move |body| {
+            if let InvokeResponseBody::Json(json) = body {
+                sent.lock()
+                    .unwrap()
+                    .push(serde_json::from_str(&json).unwrap());
+            }
+            Ok(())
+        });
+        (channel, messages)
+    }
+
+    /// a player for `server`'s library that plays into a null output, and
+    /// everything it sends to the frontend. the player only works for as
+    /// long as the app is kept around
+    async fn start_player(
+        server: &MockServer,
+    ) -> (
+        NullOutput,
+        Player<MockRuntime>,
+        Arc<Mutex<Vec<Value>>>,
+        App<MockRuntime>,
+    ) {
+        let cache = open_cache(server);
+        cache.refresh().await.unwrap();
+        let (output, handle) = NullOutput::new(44100, Vec::new(), 2, 512);
+        let (channel, messages) = player_channel();
+        let app = mock_app();
+        let player = Player::new(cache, channel, handle, app.handle().clone());
+        (output, player, messages, app)
+    }
+
+    fn playback_errors(messages: &Mutex<Vec<Value>>) -> Vec<Value> {
+        messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m["event"] == "PlaybackError")
+            .map(|m| m["data"]["error"].clone())
+            .collect()
+    }
+
+    /// the output with leading and trailing silence cut off
+    fn trimmed(played: &[f32]) -> &[f32] {
+        let start = played.iter().position(|s| *s != 0.0).unwrap_or(0);
+        let end = played.iter().rposition(|s| *s != 0.0).map_or(0, |e| e + 1);
+        &played[start..end]
+    }
+
+    /// waits for `done` without playing anything. the player does its work
+    /// on the tauri runtime, so all there is to do is check back
+    async fn wait_for(mut done: impl FnMut() -> bool) {
+        for _ in 0..10_000 {
+            if done() {
+                return;
+            }
+            sleep(Duration::from_millis(1)).await;
+        }
+        panic!("gave up waiting");
+    }
+
+    /// waits for the player to queue `tracks` tracks, then plays them out.
+    /// every period waits until the decoders have got far enough to fill it,
+    /// so the output never runs dry and comes out the same however fast
+    /// anything ran
+    async fn play_tracks(output: &mut NullOutput, tracks: usize) {
+        wait_for(|| output.tracks_left() == tracks).await;
+        while output.tracks_left() > 0 {
+            wait_for(|| !output.starved()).await;
+            output.advance(512);
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_library() {
+        let server = MockServer::start();
+        let cache = open_cache(&server);
+        cache.refresh().await.unwrap();
+
+        assert_eq!(album_titles(&cache), ["Test Album", "Missing Album"]);
+        assert_eq!(cache.get_track(2).unwrap().title, "Second");
+        // in track number order, not the order the server listed them in
+        assert_eq!(cache.get_album_track_ids(2).unwrap(), [1, 2]);
+
+        // the server can't sync from where we are, so it's everything again,
+        // but none of it is different
+        assert!(cache.refresh().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn syncs_only_what_changed() {
+        let server = MockServer::start();
+        let path = db_path();
+        let cache = open_cache_at(&server, &path);
+        cache.refresh().await.unwrap();
+
+        server.add_changes(
+            "1",
+            json!({
+                "revision": "2",
+                "albums": [{ "id": 2, "title": "Found Album", "artist_id": 2, "track_ids": [4] }],
+                "artists": [{ "id": 2, "name": "New Artist" }],
+                "tracks": [
+                    { "id": 4, "title": "Found", "artist_id": 2, "album_id": 2, "track_number": 1 }
+                ],
+                "deleted_tracks": [3],
+            }),
+        );
+        let changes = serde_json::to_value(cache.refresh().await.unwrap()).unwrap();
+        let synced = |cache: &Cache| {
+            assert_eq!(album_titles(cache), ["Test Album", "Found Album"]);
+            assert_eq!(cache.get_track(4).unwrap().artist_name, "New Artist");
+            assert!(matches!(cache.get_track(3), Err(Error::NotFound(_))));
+        };
+        assert_eq!(
+            changes,
+            json!({
+                "albums": { "upserted": [2], "deleted": [] },
+                "artists": { "upserted": [2], "deleted": [] },
+                "tracks": { "upserted": [4], "deleted": [3] },
+            })
+        );
+
+        synced(&cache);
+
+        // it carries on from the new revision next time
+        server.add_changes("2", json!({ "revision": "2" }));
+        assert!(cache.refresh().await.unwrap().is_empty());
+        let since: Vec<_> = server
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/get-library-changes")
+            .map(|r| r.query["since"].clone())
+            .collect();
+        assert_eq!(since, ["1", "2"]);
+
+        // the changes are in the db too
+        drop(cache);
+        let cache = open_cache_at(&server, &path);
+        let _ = fs::remove_file(&path);
+        synced(&cache);
+    }
+
+    #[tokio::test]
+    async fn albums_left_without_an_artist_are_skipped() {
+        let server = MockServer::start();
+        let cache = open_cache(&server);
+        cache.refresh().await.unwrap();
+
+        server.add_changes(
+            "1",
+            json!({
+                "revision": "2",
+                "albums": [{ "id": 3, "title": "Other Album", "artist_id": 2, "track_ids": [] }],
+                "artists": [{ "id": 2, "name": "Other Artist" }],
+                "deleted_artists": [1],
+            }),
+        );
+        cache.refresh().await.unwrap();
+        assert_eq!(album_titles(&cache), ["Other Album"]);
+    }
+
+    #[tokio::test]
+    async fn fetches_albums_and_cover_art() {
+        let server = MockServer::start();
+        let cache = open_cache(&server);
+
+        let album = serde_json::to_value(cache.get_album(1).await.unwrap()).unwrap();
+        assert_eq!(album["artist_name"], "Test Artist");
+        assert_eq!(album["tracks"][0]["title"], "First");
+        assert_eq!(album["tracks"][1]["title"], "Second");
+        // it's cached now
+        cache.get_album(1).await.unwrap();
+        let fetches = server
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/get-album")
+            .count();
+        assert_eq!(fetches, 1);
+
+        assert_eq!(cache.get_image(1).await.unwrap(), server.fixture("1.png"));
+    }
+
+    #[tokio::test]
+    async fn albums_are_fetched_once_at_a_time() {
+        let server = MockServer::start();
+        let cache = open_cache(&server);
+
+        // given up on before it got anywhere
+        assert!(cache.get_album(1).now_or_never().is_none());
+        assert_eq!(cache.album_fetches(), 0);
+
+        for album in join_all((0..4).map(|_| cache.get_album(2))).await {
+            album.unwrap();
+        }
+        let fetches = server
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/get-album" && r.query["id"] == "2")
+            .count();
+        assert_eq!(fetches, 1);
+        assert_eq!(cache.album_fetches(), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_things_are_not_found() {
+        let server = MockServer::start();
+        let cache = open_cache(&server);
+
+        assert!(matches!(cache.get_album(9).await, Err(Error::NotFound(_))));
+        assert!(matches!(cache.get_image(9).await, Err(Error::NotFound(_))));
+    }
+
+    // reading blocks the runtime thread, which needs a multi threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tracks_are_read_from_where_they_were_seeked_to() {
+        let server = MockServer::start();
+        let url = format!("{}/get-track?id=1", server.url());
+        let cache = open_cache(&server);
+        let mut source = HttpSource::new(cache.clone(), &url).await.unwrap();
+        // logged in again partway through
+        cache.set_client(build_client(Some("new-token")).unwrap());
+
+        source.seek(SeekFrom::Start(10_000)).unwrap();
+        let mut rest = Vec::new();
+        source.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, server.fixture("1.flac")[10_000..]);
+        let request = server.requests().last().unwrap().clone();
+        assert_eq!(request.range.as_deref(), Some("bytes=10000-"));
+        assert_eq!(request.authorization.as_deref(), Some("Bearer new-token"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn broken_off_downloads_pick_up_where_they_left_off() {
+        let server = MockServer::start();
+        server.hang_up_once(1, 10_000);
+        let url = format!("{}/get-track?id=1", server.url());
+        let mut source = HttpSource::new(open_cache(&server), &url).await.unwrap();
+
+        let mut track = Vec::new();
+        source.read_to_end(&mut track).unwrap();
+        assert_eq!(track, server.fixture("1.flac"));
+        let request = server.requests().last().unwrap().clone();
+        assert_eq!(request.range.as_deref(), Some("bytes=10000-"));
+    }
+
+    #[tokio::test]
+    async fn plays_an_album_through() {
+        let server = MockServer::start();
+        let (mut output, player, messages, _app) = start_player(&server).await;
+
+        player.play_track(1).await.unwrap();
+        let album = [
+            decode_fixture(&server, "1.flac"),
+            decode_fixture(&server, "2.flac"),
+        ]
+        .concat();
+        play_tracks(&mut output, 2).await;
+
+        assert_eq!(trimmed(output.played()), trimmed(&album));
+        assert!(playback_errors(&messages).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_seeks_are_reported_and_playback_carries_on() {
+        let server = MockServer::start();
+        server.ignore_ranges(1);
+        let (mut output, player, messages, _app) = start_player(&server).await;
+
+        player.play_track(1).await.unwrap();
+        let has = |event: &str| messages.lock().unwrap().iter().any(|m| m["event"] == event);
+        wait_for(|| has("UpdateDuration")).await;
+        player.seek(1.0).await;
+        // the position goes back to where playback really is
+        let after_error = || -> Vec<Value> {
+            let messages = messages.lock().unwrap();
+            messages
+                .iter()
+                .skip_while(|m| m["event"] != "PlaybackError")
+                .filter(|m| m["event"] == "UpdatePosition")
+                .map(|m| m["data"]["position"].clone())
+                .collect()
+        };
+        wait_for(|| !after_error().is_empty()).await;
+        assert_eq!(after_error()[0], 0.0);
+
+        // symphonia might have got partway before the seek failed, so the
+        // first track isn't necessarily all there, but it keeps going
+        play_tracks(&mut output, 2).await;
+        let second = decode_fixture(&server, "2.flac");
+        assert!(trimmed(output.played()).ends_with(&second));
+        assert_eq!(playback_errors(&messages).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_ask_for_a_login_when_seeking() {
+        let server = MockServer::start();
+        let (_output, player, messages, app) = start_player(&server).await;
+        let login_required = Arc::new(AtomicBool::new(false));
+        let asked = login_required.clone();
+        app.listen_any("login-required", move |_| {
+            asked.store(true, Ordering::Release)
+        });
+
+        player.play_track(1).await.unwrap();
+        let has = |event: &str| messages.lock().unwrap().iter().any(|m| m["event"] == event);
+        wait_for(|| has("UpdateDuration")).await;
+        server.revoke_tokens();
+        player.seek(1.0).await;
+        wait_for(|| !playback_errors(&messages).is_empty()).await;
+
+        assert_eq!(playback_errors(&messages)[0]["kind"], "Unauthorized");
+        assert!(login_required.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn a_new_frontend_is_caught_up() {
+        let server = MockServer::start();
+        let (_output, player, first, _app) = start_player(&server).await;
+        player.play_track(1).await.unwrap();
+        let opened = || {
+            first
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|m| m["event"] == "UpdateDuration")
+        };
+        wait_for(opened).await;
+
+        let (channel, messages) = player_channel();
+        player.set_channel(channel);
+        // positions keep coming in the background, so they could be anywhere
+        let events: Vec<_> = messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m["event"].clone())
+            .filter(|e| e != "UpdatePosition")
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "UpdateCurrentTrack",
+                "UpdateDuration",
+                "UpdatePlaying",
+                "UpdateVolume"
+            ]
+        );
+        let current = messages
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|m| m["event"] == "UpdateCurrentTrack")
+            .map(|m| m["data"]["current_track"]["track_title"].clone());
+        assert_eq!(current.unwrap(), "First");
+    }
+
+    #[tokio::test]
+    async fn missing_tracks_are_reported() {
+        let server = MockServer::start();
+        let (mut output, player, messages, _app) = start_player(&server).await;
+
+        player.play_track(3).await.unwrap();
+        wait_for(|| !playback_errors(&messages).is_empty()).await;
+        output.advance(512);
+
+        assert_eq!(playback_errors(&messages)[0]["kind"], "NotFound");
+        assert_eq!(output.tracks_left(), 0);
+        assert!(trimmed(output.played()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cut_off_tracks_are_reported_and_skipped() {
+        let server = MockServer::start();
+        server.truncate(1, 12_000);
+        let (mut output, player, messages, _app) = start_player(&server).await;
+
+        player.play_track(1).await.unwrap();
+        let first = decode_fixture(&server, "1.flac");
+        let second = decode_fixture(&server, "2.flac");
+        play_tracks(&mut output, 2).await;
+        wait_for(|| !playback_errors(&messages).is_empty()).await;
+
+        // the first track plays as far as it got, then the next one follows
+        // straight on from it
+        let played = trimmed(output.played());
+        assert!(played.ends_with(&second));
+        let cut_off = &played[..played.len() - second.len()];
+        assert!(!cut_off.is_empty() && cut_off.len() < first.len());
+        assert!(trimmed(&first).starts_with(cut_off));
+        assert_eq!(playback_errors(&messages).len(), 1);
+    }
+}
@@ -0,0 +1,528 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    ops::RangeInclusive,
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::{
+    error::{Error, Result},
+    main_stream::{
+        new_main_stream, MainStream, MainStreamHandle, OutputBackend, OutputCommand, OutputFormat,
+    },
+};
+
+/// an output that doesn't play anywhere. the main stream only gets pulled
+/// on when `advance` is called, so time moves exactly as fast as a test
+/// wants it to and the same input always comes out the same
+pub struct NullOutput {
+    main_stream: Arc<Mutex<MainStream>>,
+    commands: Receiver<OutputCommand>,
+    native_rates: Vec<RangeInclusive<u32>>,
+    /// rates in `native_rates` that reopening at fails anyway
+    broken_rates: Vec<u32>,
+    rate: u32,
+    channels: usize,
+    /// frames asked for per callback, like a device's buffer size
+    period: usize,
+    /// everything that's been played, interleaved
+    played: Vec<f32>,
+    wav: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl NullOutput {
+    /// an output running at `rate` with `channels`, that can be reopened at
+    /// any of `native_rates`
+    pub fn new(
+        rate: u32,
+        native_rates: Vec<RangeInclusive<u32>>,
+        channels: usize,
+        period: usize,
+    ) -> (Self, MainStreamHandle) {
+        let (command_send, commands) = mpsc::sync_channel(1);
+        let format = OutputFormat::with_rates(rate, native_rates.clone(), channels);
+        let (main_stream, handle) = new_main_stream(format, command_send);
+        (
+            Self {
+                main_stream,
+                commands,
+                native_rates,
+                broken_rates: Vec::new(),
+                rate,
+                channels,
+                period,
+                played: Vec::new(),
+                wav: None,
+            },
+            handle,
+        )
+    }
+
+    /// makes reopening at `rate` fail, like a device that claims it can do
+    /// a rate it can't
+    pub fn break_rate(&mut self, rate: u32) {
+        self.broken_rates.push(rate);
+    }
+
+    /// writes everything played from now on to a 32 bit float wav at
+    /// `path`. the wav is at the rate the output is at now, if the output
+    /// gets reopened at another rate the rest plays back at the wrong speed
+    pub fn write_wav(&mut self, path: &Path) -> Result<()> {
+        let spec = WavSpec {
+            channels: self.channels as u16,
+            sample_rate: self.rate,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let wav = WavWriter::create(path, spec).map_err(|e| Error::Storage(e.to_string()))?;
+        self.wav = Some(wav);
+        Ok(())
+    }
+
+    /// plays `frames` frames worth of the main stream, a period at a time.
+    /// whatever the main stream or its handle asked for is dealt with in
+    /// between periods, like the output thread would
+    pub fn advance(&mut self, frames: usize) {
+        let mut left = frames;
+        while left > 0 {
+            let frames = left.min(self.period);
+            let start = self.played.len();
+            self.played.resize(start + frames * self.channels, 0.0);
+            self.main_stream
+                .lock()
+                .unwrap()
+                .cb(&mut self.played[start..]);
+            if let Some(wav) = self.wav.as_mut() {
+                for s in &self.played[start..] {
+                    wav.write_sample(*s).unwrap();
+                }
+            }
+            left -= frames;
+
+            while let Ok(command) = self.commands.try_recv() {
+                self.handle(command);
+            }
+        }
+    }
+
+    /// how many tracks the main stream still has to play, counting the one
+    /// that's playing
+    pub fn tracks_left(&self) -> usize {
+        self.main_stream.lock().unwrap().tracks_left()
+    }
+
+    /// whether the next period would run dry partway through a track that's
+    /// still being decoded. waiting until it isn't before every `advance`
+    /// means nothing is left to how fast the decoders happened to run
+    pub fn starved(&self) -> bool {
+        self.main_stream
+            .lock()
+            .unwrap()
+            .starved(self.period * self.channels)
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// everything that's been played so far, interleaved
+    pub fn played(&self) -> &[f32] {
+        &self.played
+    }
+
+    /// finishes off the wav, if there is one
+    pub fn finish(self) -> Result<Vec<f32>> {
+        if let Some(wav) = self.wav {
+            wav.finalize().map_err(|e| Error::Storage(e.to_string()))?;
+        }
+        Ok(self.played)
+    }
+}
+impl OutputBackend for NullOutput {
+    fn reopen(&mut self, rate: u32) {
+        let mut ms = self.main_stream.lock().unwrap();
+        if self.native_rates.iter().any(|r| r.contains(&rate)) && !self.broken_rates.contains(&rate)
+        {
+            self.rate = rate;
+        } else {
+            ms.rate_unavailable(rate);
+        }
+        ms.output_started(self.rate, self.channels);
+    }
+
+    /// there's only the one device
+    fn switch_device(&mut self, _name: Option<String>) -> Result<()> {
+        Ok(())
+    }
+
+    fn check_device(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, f32::consts::TAU, process};
+
+    use symphonia::core::audio::Channels;
+    use tokio::task::yield_now;
+
+    use super::*;
+    use crate::{
+        crossfade::FadeCurve,
+        main_stream::{TrackProgress, TrackStreamHandle},
+    };
+
+    fn stereo() -> Channels {
+        Channels::FRONT_LEFT | Channels::FRONT_RIGHT
+    }
+
+    /// a different sine on each channel, planar
+    fn sines(rate: u32, frames: usize) -> Vec<Vec<f32>> {
+        [440.0, 660.0]
+            .iter()
+            .map(|freq| {
+                (0..frames)
+                    .map(|i| (TAU * freq * i as f32 / rate as f32).sin() * 0.5)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn interleave(planes: &[Vec<f32>]) -> Vec<f32> {
+        (0..planes[0].len())
+            .flat_map(|i| planes.iter().map(move |p| p[i]))
+            .collect()
+    }
+
+    /// queues a track at `rate` and sends `planes` to it in packets like a
+    /// decoder would, from a task of its own
+    fn queue_track(handle: &MainStreamHandle, rate: u32, planes: Vec<Vec<f32>>) -> TrackProgress {
+        let (track, track_handle) = handle.spawn_track_stream(rate, stereo());
+        handle.queue(track).unwrap();
+        send_track(track_handle, planes)
+    }
+
+    /// like `queue_track`, but the main stream knows how long the track is
+    /// and whether it's the next one on the same album, which is what
+    /// crossfading goes by
+    fn queue_album_track(
+        handle: &MainStreamHandle,
+        planes: Vec<Vec<f32>>,
+        continues_album: bool,
+    ) -> TrackProgress {
+        let (track, track_handle) = handle.spawn_track_stream(48000, stereo());
+        track_handle.set_length(planes[0].len() as u64);
+        track_handle.set_continues_album(continues_album);
+        handle.queue(track).unwrap();
+        send_track(track_handle, planes)
+    }
+
+    fn send_track(mut track_handle: TrackStreamHandle, planes: Vec<Vec<f32>>) -> TrackProgress {
+        let progress = track_handle.progress();
+        tokio::spawn(async move {
+            for start in (0..planes[0].len()).step_by(1152) {
+                let end = (start + 1152).min(planes[0].len());
+                let packet: Vec<f32> = planes
+                    .iter()
+                    .flat_map(|p| &p[start..end])
+                    .copied()
+                    .collect();
+                track_handle.send(&packet).await;
+            }
+            track_handle.finish().await;
+        });
+        progress
+    }
+
+    /// plays until `progress` says the track is done. the sending task gets
+    /// to fill the track stream back up before every period, so the track
+    /// never runs dry halfway
+    async fn play_out(output: &mut NullOutput, progress: &TrackProgress) {
+        for _ in 0..10_000 {
+            yield_now().await;
+            if progress.finished() {
+                return;
+            }
+            output.advance(output.period);
+        }
+        panic!("track never finished");
+    }
+
+    /// the output with leading and trailing silence cut off
+    fn trimmed(played: &[f32]) -> &[f32] {
+        let start = played.iter().position(|s| *s != 0.0).unwrap_or(0);
+        let end = played.iter().rposition(|s| *s != 0.0).map_or(0, |e| e + 1);
+        &played[start..end]
+    }
+
+    #[tokio::test]
+    async fn plays_back_what_was_sent() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.play();
+        let planes = sines(48000, 10_000);
+        let progress = queue_track(&handle, 48000, planes.clone());
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(trimmed(output.played()), trimmed(&interleave(&planes)));
+        assert_eq!(progress.position(), 10_000.0 / 48000.0);
+    }
+
+    #[tokio::test]
+    async fn nothing_plays_while_paused() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        queue_track(&handle, 48000, sines(48000, 10_000));
+        for _ in 0..20 {
+            yield_now().await;
+            output.advance(512);
+        }
+        assert!(output.played().iter().all(|s| *s == 0.0));
+    }
+
+    #[tokio::test]
+    async fn queued_tracks_play_back_to_back() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.play();
+        // an odd length so the first track ends partway through a period
+        let planes = sines(48000, 12_345);
+        let (first, second) = (
+            planes.iter().map(|p| p[..5_000].to_vec()).collect(),
+            planes.iter().map(|p| p[5_000..].to_vec()).collect(),
+        );
+        queue_track(&handle, 48000, first);
+        let progress = queue_track(&handle, 48000, second);
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(trimmed(output.played()), trimmed(&interleave(&planes)));
+    }
+
+    /// `frames` of the same `level` on both channels
+    fn constant(level: f32, frames: usize) -> Vec<Vec<f32>> {
+        vec![vec![level; frames]; 2]
+    }
+
+    #[tokio::test]
+    async fn tracks_are_crossfaded() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.set_crossfade(100, FadeCurve::Linear);
+        handle.play();
+        queue_album_track(&handle, constant(0.5, 24_000), false);
+        let progress = queue_album_track(&handle, constant(0.25, 24_000), false);
+        play_out(&mut output, &progress).await;
+
+        // the two overlap for the 4800 frames of the fade, going from one
+        // level to the other in between
+        let played = trimmed(output.played());
+        assert_eq!(played.len(), (48_000 - 4800) * 2);
+        let fade = &played[(24_000 - 4800) * 2..24_000 * 2];
+        assert!(fade.windows(2).all(|w| w[1] <= w[0]));
+        assert!(fade.iter().any(|s| *s < 0.45 && *s > 0.3));
+    }
+
+    #[tokio::test]
+    async fn gapless_album_tracks_arent_crossfaded() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.set_crossfade(100, FadeCurve::Linear);
+        handle.play();
+        let (first, second) = (constant(0.5, 24_000), constant(0.25, 24_000));
+        queue_album_track(&handle, first.clone(), false);
+        let progress = queue_album_track(&handle, second.clone(), true);
+        play_out(&mut output, &progress).await;
+
+        let album = [interleave(&first), interleave(&second)].concat();
+        assert_eq!(trimmed(output.played()), album);
+    }
+
+    #[tokio::test]
+    async fn channels_stay_in_place_when_a_track_runs_dry() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 6, 500);
+        handle.play();
+        let planes = vec![vec![0.25; 10_000], vec![-0.5; 10_000]];
+        let progress = queue_track(&handle, 48000, planes);
+        // several periods go by between each chance the track gets to catch
+        // up, so it keeps running dry partway through one
+        for _ in 0..100 {
+            yield_now().await;
+            if progress.finished() {
+                break;
+            }
+            output.advance(5 * 500);
+        }
+
+        assert!(progress.finished());
+        for frame in output.played().chunks(6) {
+            assert!(
+                frame == [0.0; 6] || frame == [0.25, -0.5, 0.0, 0.0, 0.0, 0.0],
+                "{frame:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn tracks_queued_right_after_a_clear_still_play() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.play();
+        queue_track(&handle, 48000, sines(48000, 48000));
+        for _ in 0..4 {
+            yield_now().await;
+            output.advance(512);
+        }
+        // the output doesn't get to the clear before the next track is
+        // queued, like after a skip with a fast server
+        handle.clear();
+        let planes = sines(48000, 4800);
+        let progress = queue_track(&handle, 48000, planes.clone());
+        let before = output.played().len();
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(
+            trimmed(&output.played()[before..]),
+            trimmed(&interleave(&planes))
+        );
+    }
+
+    #[tokio::test]
+    async fn tracks_made_before_a_clear_are_dropped() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.play();
+        let (stale, mut stale_handle) = handle.spawn_track_stream(48000, stereo());
+        let stale_progress = stale_handle.progress();
+        handle.clear();
+        // queued behind a track that's meant to play, as if whatever made
+        // it was still running when the clear happened
+        let progress = queue_track(&handle, 48000, sines(48000, 4800));
+        handle.queue(stale).unwrap();
+        tokio::spawn(async move {
+            stale_handle.send(&vec![0.5; 2 * 4800]).await;
+            stale_handle.finish().await;
+        });
+        play_out(&mut output, &progress).await;
+        let end = output.played().len();
+        for _ in 0..20 {
+            yield_now().await;
+            output.advance(512);
+        }
+
+        assert!(!stale_progress.started());
+        assert!(output.played()[end..].iter().all(|s| *s == 0.0));
+    }
+
+    #[tokio::test]
+    async fn queued_tracks_are_flushed_while_paused() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        let (track, mut track_handle) = handle.spawn_track_stream(48000, stereo());
+        handle.queue(track).unwrap();
+        let progress = track_handle.progress();
+        // like a track queued again from partway through while paused
+        let flushed = tokio::spawn(async move { track_handle.flush(1.0).await });
+        for _ in 0..20 {
+            yield_now().await;
+            output.advance(512);
+        }
+
+        assert!(flushed.is_finished());
+        assert_eq!(progress.position(), 1.0);
+        assert!(!progress.started());
+    }
+
+    #[test]
+    fn cleared_tracks_make_room_while_paused() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        // nothing takes tracks off the queue until the output runs again
+        let filled = (0..1000).any(|_| {
+            let (track, _) = handle.spawn_track_stream(48000, stereo());
+            handle.clear();
+            handle.queue(track).is_err()
+        });
+        assert!(filled);
+
+        output.advance(512);
+        let (track, _) = handle.spawn_track_stream(48000, stereo());
+        assert!(handle.queue(track).is_ok());
+    }
+
+    #[tokio::test]
+    async fn tracks_are_resampled_to_the_output_rate() {
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        handle.play();
+        let progress = queue_track(&handle, 44100, sines(44100, 44100));
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(output.rate(), 48000);
+        // a second at 44.1kHz is a second at 48kHz
+        let frames = output.played().len() / 2;
+        assert!((48000..48000 + 1024).contains(&frames), "{frames} frames");
+    }
+
+    #[tokio::test]
+    async fn output_is_reopened_at_a_native_rate() {
+        let (mut output, handle) = NullOutput::new(48000, vec![44100..=96000], 2, 512);
+        handle.set_native_rate(true);
+        handle.play();
+        let planes = sines(44100, 10_000);
+        let progress = queue_track(&handle, 44100, planes.clone());
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(output.rate(), 44100);
+        // not resampled, so it comes out exactly the same
+        assert_eq!(trimmed(output.played()), trimmed(&interleave(&planes)));
+    }
+
+    #[tokio::test]
+    async fn unsupported_rates_are_resampled_instead() {
+        let (mut output, handle) = NullOutput::new(48000, vec![48000..=48000], 2, 512);
+        handle.set_native_rate(true);
+        handle.play();
+        let progress = queue_track(&handle, 44100, sines(44100, 4410));
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(output.rate(), 48000);
+        assert!(output.played().iter().any(|s| *s != 0.0));
+    }
+
+    #[tokio::test]
+    async fn tracks_are_requeued_if_the_output_cant_reopen_after_all() {
+        let (mut output, handle) = NullOutput::new(48000, vec![44100..=96000], 2, 512);
+        output.break_rate(44100);
+        handle.set_native_rate(true);
+        handle.play();
+        let dropped = queue_track(&handle, 44100, sines(44100, 4410));
+        for _ in 0..4 {
+            yield_now().await;
+            output.advance(512);
+        }
+
+        assert!(!dropped.started());
+        assert!(handle.take_requeue());
+        // queued again like the player would, it gets resampled this time
+        let planes = sines(44100, 4410);
+        let progress = queue_track(&handle, 44100, planes.clone());
+        play_out(&mut output, &progress).await;
+
+        assert_eq!(output.rate(), 48000);
+        let frames = trimmed(output.played()).len() / 2;
+        assert!((4800 - 64..4800 + 64).contains(&frames), "{frames} frames");
+    }
+
+    #[tokio::test]
+    async fn writes_a_wav() {
+        let path = env::temp_dir().join(format!("pi-fi-null-output-{}.wav", process::id()));
+        let (mut output, handle) = NullOutput::new(48000, Vec::new(), 2, 512);
+        output.write_wav(&path).unwrap();
+        handle.play();
+        let progress = queue_track(&handle, 48000, sines(48000, 4800));
+        play_out(&mut output, &progress).await;
+        let played = output.finish().unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().sample_rate, 48000);
+        assert_eq!(reader.spec().channels, 2);
+        let written: Vec<f32> = reader.samples().map(|s| s.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, played);
+    }
+}
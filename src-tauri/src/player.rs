@@ -1,128 +1,843 @@
-use std::{collections::VecDeque, io::Cursor, sync::Arc};
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
 use symphonia::{
     core::{
         audio::SampleBuffer,
-        conv::IntoSample,
+        codecs::Decoder,
+        errors::Error as SymphoniaError,
+        formats::{FormatOptions, SeekMode, SeekTo},
         io::{MediaSourceStream, MediaSourceStreamOptions},
-        probe::Hint,
+        probe::{Hint, ProbeResult},
+        units::{Time, TimeBase},
     },
     default,
 };
 
-use crate::{main_stream::MainStreamHandle, SERVER_URL};
+use crate::{
+    auth::notify_unauthorized,
+    crossfade::FadeCurve,
+    equalizer::EqCurve,
+    error::{Error, Result},
+    http_source::HttpSource,
+    loudness::LoudnessMeter,
+    main_stream::{DeviceChange, MainStreamHandle, TrackProgress, TrackStreamHandle},
+    meter::Levels,
+    replay_gain::{track_gain, ReplayGainMode, ReplayGainSettings, ReplayGainTags},
+    stereo::StereoSettings,
+};
 
 use serde::Serialize;
-use tauri::{async_runtime::spawn, ipc::Channel};
-use tauri_plugin_http::reqwest::Client;
+use tauri::{
+    async_runtime::{spawn, spawn_blocking, JoinHandle},
+    ipc::Channel,
+    AppHandle, Runtime, Wry,
+};
+use tokio::{
+    select,
+    sync::Notify,
+    task::JoinSet,
+    time::{interval, MissedTickBehavior},
+};
 
 use crate::cache::Cache;
 
-#[derive(Clone)]
-pub struct Player(Arc<PlayerInner>);
-struct PlayerInner {
-    channel: Channel<PlayerUpdateMsg>,
-    client: Client,
+const POSITION_REPORT_INTERVAL: Duration = Duration::from_millis(250);
+const LEVELS_REPORT_INTERVAL: Duration = Duration::from_millis(33);
+
+pub struct Player<R: Runtime = Wry>(Arc<PlayerInner<R>>);
+struct PlayerInner<R: Runtime> {
+    /// swapped out when the webview reloads
+    channel: Mutex<Channel<PlayerUpdateMsg>>,
+    app: AppHandle<R>,
     cache: Arc<Cache>,
     main_stream_handle: MainStreamHandle,
+    queue: Mutex<PlayQueue>,
+    playback_task: Mutex<Option<JoinHandle<()>>>,
+    replay_gain: Mutex<ReplayGainSettings>,
+    /// the tracks that have been handed to the main stream, in the order
+    /// they'll be played, the front one is what's playing right now
+    tracks: Mutex<VecDeque<QueuedTrack>>,
+    /// wakes `report_levels` when metering gets turned on, or the player
+    /// goes away
+    metering: Arc<Notify>,
+}
+impl<R: Runtime> Drop for PlayerInner<R> {
+    fn drop(&mut self) {
+        self.metering.notify_one();
+    }
+}
+
+/// a track that's been queued on the main stream
+struct QueuedTrack {
+    info: CurrentTrack,
+    duration: Option<f64>,
+    progress: TrackProgress,
+    seek: Arc<SeekRequest>,
 }
 
-impl Player {
+/// a track that's been opened and is ready to be decoded
+struct OpenTrack {
+    reader: ProbeResult,
+    decoder: Box<dyn Decoder>,
+    handle: TrackStreamHandle,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    seek: Arc<SeekRequest>,
+    measurement: Option<Measurement>,
+}
+
+/// measures a track's loudness as it's decoded, for tracks that don't have
+/// replaygain tags. it only counts if the whole track gets decoded in one go
+struct Measurement {
+    id: i64,
+    meter: LoudnessMeter,
+    cache: Arc<Cache>,
+}
+
+#[derive(Default)]
+struct SeekRequest {
+    position: Mutex<Option<f64>>,
+    notify: Notify,
+    /// set once the decoder is done with the track, there's nothing to
+    /// handle seeks after that. this only changes while `position` is
+    /// locked, so a seek can't slip in just as the decoder stops
+    decoded: AtomicBool,
+}
+impl SeekRequest {
+    /// hands `position` to the decoder, returns false if it's already done
+    /// with the track
+    fn request(&self, position: f64) -> bool {
+        let mut pending = self.position.lock().unwrap();
+        if self.decoded.load(Ordering::Acquire) {
+            return false;
+        }
+        *pending = Some(position);
+        drop(pending);
+        self.notify.notify_waiters();
+        true
+    }
+    /// the decoder got to the end of the track. returns whether that's it,
+    /// if a seek came in first the decoder has to go back and handle it
+    fn finish(&self) -> bool {
+        let pending = self.position.lock().unwrap();
+        if pending.is_none() {
+            self.decoded.store(true, Ordering::Release);
+        }
+        pending.is_none()
+    }
+    /// the decoder stopped before the end, any seek still waiting won't
+    /// be handled
+    fn close(&self) {
+        let _pending = self.position.lock().unwrap();
+        self.decoded.store(true, Ordering::Release);
+    }
+}
+
+// derived clone would want `R: Clone`, which runtimes aren't
+impl<R: Runtime> Clone for Player<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<R: Runtime> Player<R> {
     pub fn new(
-        client: Client,
         cache: Arc<Cache>,
         channel: Channel<PlayerUpdateMsg>,
         main_stream_handle: MainStreamHandle,
+        app: AppHandle<R>,
     ) -> Self {
-        channel
-            .send(PlayerUpdateMsg::UpdateCurrentTrack {
+        send_update(
+            &channel,
+            PlayerUpdateMsg::UpdateCurrentTrack {
                 current_track: CurrentTrack {
                     track_title: "Crusades".into(),
                     artist_title: "Geese".into(),
                     cover_art_id: 1,
                 },
-            })
-            .unwrap();
-        Self(Arc::new(PlayerInner {
-            channel,
-            client,
+            },
+        );
+        let player = Self::start(cache, channel, main_stream_handle, app);
+        player.0.announce_volume();
+        player
+    }
+    fn start(
+        cache: Arc<Cache>,
+        channel: Channel<PlayerUpdateMsg>,
+        main_stream_handle: MainStreamHandle,
+        app: AppHandle<R>,
+    ) -> Self {
+        let inner = Arc::new(PlayerInner {
+            channel: Mutex::new(channel),
+            app,
             cache,
             main_stream_handle,
-        }))
+            queue: Mutex::new(PlayQueue::new()),
+            playback_task: Mutex::new(None),
+            replay_gain: Mutex::new(ReplayGainSettings::default()),
+            tracks: Mutex::new(VecDeque::new()),
+            metering: Arc::new(Notify::new()),
+        });
+        spawn(monitor_playback(Arc::downgrade(&inner)));
+        spawn(report_levels(
+            Arc::downgrade(&inner),
+            inner.metering.clone(),
+        ));
+        Self(inner)
+    }
+    /// stops this player and makes a new one (with an empty queue) that
+    /// plays from `cache`'s server instead
+    pub async fn switch_cache(&self, cache: Arc<Cache>) -> Self {
+        self.stop_playback().await;
+        self.0
+            .send(PlayerUpdateMsg::UpdatePlaying { playing: false });
+        let player = Self::start(
+            cache,
+            self.0.channel.lock().unwrap().clone(),
+            self.0.main_stream_handle.clone(),
+            self.0.app.clone(),
+        );
+        player.set_replay_gain(*self.0.replay_gain.lock().unwrap());
+        player
+    }
+    /// sends everything from now on to `channel` instead, and catches it up
+    /// on what's playing. for when the webview reloads
+    pub fn set_channel(&self, channel: Channel<PlayerUpdateMsg>) {
+        *self.0.channel.lock().unwrap() = channel;
+        self.0.announce_current();
+        let playing = self.0.main_stream_handle.is_playing();
+        self.0.send(PlayerUpdateMsg::UpdatePlaying { playing });
+        self.0.announce_volume();
+    }
+    /// plays `id` and queues up the rest of its album after it
+    pub async fn play_track(&self, id: i64) -> Result<()> {
+        let album = self.0.cache.get_album_track_ids(id)?;
+        let pos = album
+            .iter()
+            .position(|t| *t == id)
+            .ok_or_else(|| Error::NotFound(format!("track {id}")))?;
+        self.0
+            .queue
+            .lock()
+            .unwrap()
+            .replace(id, album[pos + 1..].iter().copied());
+        self.restart_playback().await;
+        Ok(())
+    }
+    pub fn toggle_playing(&self) {
+        let playing = self.0.main_stream_handle.toggle_playing();
+        self.0.send(PlayerUpdateMsg::UpdatePlaying { playing });
+    }
+    /// jumps to `position` (in seconds) in the current track. if the whole
+    /// track has already been decoded there's no decoder left to seek, so
+    /// the track gets queued again from there
+    pub async fn seek(&self, position: f64) {
+        let position = position.max(0.0);
+        let handled = match self.0.tracks.lock().unwrap().front() {
+            Some(track) => track.seek.request(position),
+            None => return,
+        };
+        if !handled {
+            let playing = self.0.main_stream_handle.is_playing();
+            self.stop_playback().await;
+            self.start_playback(position, playing);
+        }
+    }
+    /// `duration` is in seconds, 0 turns crossfading off
+    pub fn set_crossfade(&self, duration: f64, curve: FadeCurve) {
+        self.0
+            .main_stream_handle
+            .set_crossfade((duration.max(0.0) * 1000.0) as u32, curve);
+    }
+    /// `volume` goes from 0 to 1
+    pub fn set_volume(&self, volume: f64) {
+        self.0.main_stream_handle.set_volume(volume as f32);
+        self.0.announce_volume();
+    }
+    pub fn set_muted(&self, muted: bool) {
+        self.0.main_stream_handle.set_muted(muted);
+        self.0.announce_volume();
+    }
+    pub fn set_eq(&self, curve: EqCurve) {
+        self.0.main_stream_handle.set_eq(curve);
+    }
+    pub fn set_stereo(&self, settings: StereoSettings) {
+        self.0.main_stream_handle.set_stereo(settings);
+    }
+    /// whether to send `UpdateLevels`, it's off to begin with
+    pub fn set_metering(&self, on: bool) {
+        self.0.main_stream_handle.set_metering(on);
+        if on {
+            self.0.metering.notify_one();
+        }
+    }
+    /// takes effect from the next track that gets opened
+    pub fn set_replay_gain(&self, settings: ReplayGainSettings) {
+        *self.0.replay_gain.lock().unwrap() = settings;
+    }
+    /// takes effect from the next track that gets opened
+    pub fn set_native_rate(&self, native: bool) {
+        self.0.main_stream_handle.set_native_rate(native);
+    }
+    pub async fn skip(&self) {
+        if self.0.queue.lock().unwrap().skip_forward().is_some() {
+            self.restart_playback().await;
+        }
+    }
+    pub async fn skip_back(&self) {
+        if self.0.queue.lock().unwrap().skip_back().is_some() {
+            self.restart_playback().await;
+        }
+    }
+
+    /// moves playback over to the output device called `name` (or the
+    /// default one), the current track picks up where it left off
+    pub async fn switch_device(&self, name: Option<String>) -> Result<()> {
+        let resume = self.0.resume_point();
+        // everything that's queued was made for the old device
+        self.stop_playback().await;
+
+        let handle = self.0.main_stream_handle.clone();
+        let switched = spawn_blocking(move || handle.switch_device(name))
+            .await
+            .map_err(|e| Error::AudioDevice(e.to_string()))
+            .and_then(|r| r);
+        // we carry on either way, if it didn't work we're still on the old
+        // device
+        if let Some((position, playing)) = resume {
+            self.start_playback(position, playing);
+        }
+        switched
+    }
+    /// catches up after the output moved to another device on its own
+    async fn device_changed(&self, change: DeviceChange) {
+        if change.format_changed {
+            // the main stream threw away everything it had, so the current
+            // track has to be redone for the new device
+            self.requeue().await;
+        }
+        if let Some(device) = change.device {
+            self.0.send(PlayerUpdateMsg::OutputDeviceChanged { device });
+        }
+    }
+
+    /// queues everything again from where playback got to, after the main
+    /// stream threw away what it had
+    async fn requeue(&self) {
+        // if the current track already played out, it was the one after it
+        // that got thrown away before it could start
+        let played_out = {
+            let tracks = self.0.tracks.lock().unwrap();
+            tracks.len() > 1 && tracks[0].progress.finished()
+        };
+        let resume = self.0.resume_point();
+        self.stop_playback().await;
+        let Some((position, playing)) = resume else {
+            return;
+        };
+        if played_out && self.0.queue.lock().unwrap().skip_forward().is_some() {
+            self.start_playback(0.0, playing);
+        } else {
+            self.start_playback(position, playing);
+        }
+    }
+
+    /// stops whatever is currently playing and starts a new playback task
+    /// from the current track in the queue
+    async fn restart_playback(&self) {
+        self.stop_playback().await;
+        self.start_playback(0.0, true);
     }
-    pub async fn play_track(&self, id: i64) {
-        let req = self
-            .0
-            .client
-            .get(format!("{SERVER_URL}/get-track?id={id}"))
-            .send();
+
+    /// starts a new playback task from `position` (in seconds) into the
+    /// current track in the queue, paused unless `play` is set
+    fn start_playback(&self, position: f64, play: bool) {
+        let player = self.clone();
+        let task = spawn(async move { player.playback_loop(position, play).await });
+        *self.0.playback_task.lock().unwrap() = Some(task);
+    }
+
+    /// stops the playback task and throws away everything it queued
+    async fn stop_playback(&self) {
+        let old_task = self.0.playback_task.lock().unwrap().take();
+        if let Some(task) = old_task {
+            // wait for the old task to actually stop so it can't queue
+            // anything after we clear
+            task.abort();
+            let _ = task.await;
+        }
 
         self.0.main_stream_handle.pause();
         self.0.main_stream_handle.clear();
+        self.0.tracks.lock().unwrap().clear();
+    }
 
-        let track = self.0.cache.get_track(id);
-        self.0
-            .channel
-            .send(PlayerUpdateMsg::UpdateCurrentTrack {
-                current_track: CurrentTrack {
-                    track_title: track.title.clone(),
-                    artist_title: track.artist_name.clone(),
-                    cover_art_id: track.cover_art_id,
-                },
-            })
-            .unwrap();
-        let bytes = req.await.unwrap().bytes().await.unwrap();
-        let src = Cursor::new(bytes);
+    /// streams tracks from the queue one after the other. the next track is
+    /// opened and decoded into its own track stream while the current one is
+    /// still playing, so the main stream can roll straight over to it
+    async fn playback_loop(self, position: f64, play: bool) {
+        let ids = self.0.queue.lock().unwrap().tracks();
+        // dropping this (when the playback task gets aborted) aborts all the
+        // decoding tasks too
+        let mut decoding = JoinSet::new();
+        let mut previous = None;
+        for (i, id) in ids.into_iter().enumerate() {
+            let track = match self.open_track(id, previous).await {
+                Ok(t) => t,
+                // whatever is already queued keeps playing, we just can't go
+                // any further than that
+                Err(e) => {
+                    self.0.report_error(e);
+                    break;
+                }
+            };
+            previous = Some(id);
+            if i == 0 {
+                if position > 0.0 {
+                    *track.seek.position.lock().unwrap() = Some(position);
+                }
+                self.0.announce_current();
+                if play {
+                    self.0.main_stream_handle.play();
+                    self.0
+                        .send(PlayerUpdateMsg::UpdatePlaying { playing: true });
+                }
+            }
+            let seek = track.seek.clone();
+            let player = Arc::downgrade(&self.0);
+            decoding.spawn(async move {
+                let decoded = decode_track(track, player).await;
+                seek.close();
+                decoded
+            });
+
+            // only work one track ahead of what's playing
+            if decoding.len() > 1 {
+                if let Some(Ok(Err(e))) = decoding.join_next().await {
+                    self.0.report_error(e);
+                }
+            }
+        }
+        while let Some(decoded) = decoding.join_next().await {
+            if let Ok(Err(e)) = decoded {
+                self.0.report_error(e);
+            }
+        }
+    }
+
+    /// fetches the track, sets up its decoder and queues its track stream on
+    /// the main stream right after `previous`
+    async fn open_track(&self, id: i64, previous: Option<i64>) -> Result<OpenTrack> {
+        let info = self.0.cache.get_track(id)?;
+        let continues_album = previous.is_some_and(|p| {
+            self.0
+                .cache
+                .get_track(p)
+                .is_ok_and(|p| p.album_id == info.album_id)
+        });
+        let url = format!("{}/get-track?id={id}", self.0.cache.server_url());
+        let src = HttpSource::new(self.0.cache.clone(), &url).await?;
         let src_stream = MediaSourceStream::new(Box::new(src), MediaSourceStreamOptions::default());
-        let mut reader = default::get_probe()
-            .format(
-                &Hint::new().with_extension("flac"),
-                src_stream,
-                &Default::default(),
-                &Default::default(),
-            )
-            .unwrap();
-        let track = reader.format.default_track().unwrap();
-        let mut decoder = default::get_codecs()
-            .make(&track.codec_params, &Default::default())
-            .unwrap();
-
-        let srate = decoder.codec_params().sample_rate.unwrap();
-        let (stream, mut handle) = self.0.main_stream_handle.spawn_track_stream(srate);
-        self.0.main_stream_handle.queue(stream);
-        self.0.main_stream_handle.play();
+        let mut reader = default::get_probe().format(
+            Hint::new().with_extension("flac"),
+            src_stream,
+            // this has the decoder strip encoder delay and padding
+            &FormatOptions {
+                enable_gapless: true,
+                ..Default::default()
+            },
+            &Default::default(),
+        )?;
+        let tags = ReplayGainTags::read(&mut reader);
+        let track = reader
+            .format
+            .default_track()
+            .ok_or_else(|| Error::Decode(format!("track {id} has no audio")))?;
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let n_frames = track.codec_params.n_frames;
+        let duration = match (time_base, n_frames) {
+            (Some(tb), Some(n_frames)) => Some(seconds(tb.calc_time(n_frames))),
+            _ => None,
+        };
+        let decoder = default::get_codecs().make(&track.codec_params, &Default::default())?;
 
-        self.0
-            .channel
-            .send(PlayerUpdateMsg::UpdatePlaying { playing: true })
-            .unwrap();
+        let srate = decoder
+            .codec_params()
+            .sample_rate
+            .ok_or_else(|| Error::Decode(format!("track {id} has no sample rate")))?;
+        let channels = decoder
+            .codec_params()
+            .channels
+            .ok_or_else(|| Error::Decode(format!("track {id} has no channel layout")))?;
+        let (stream, mut handle) = self
+            .0
+            .main_stream_handle
+            .spawn_track_stream(srate, channels);
+        let replay_gain = *self.0.replay_gain.lock().unwrap();
+        let measured = self.0.cache.track_loudness(id);
+        handle.set_gain(track_gain(replay_gain, &tags, measured));
+        let measure = replay_gain.compute_missing
+            && replay_gain.mode != ReplayGainMode::Off
+            && tags.is_empty()
+            && measured.is_none();
+        let measurement = measure.then(|| Measurement {
+            id,
+            meter: LoudnessMeter::new(srate, channels),
+            cache: self.0.cache.clone(),
+        });
+        if let Some(n_frames) = n_frames {
+            handle.set_length(n_frames);
+        }
+        handle.set_continues_album(continues_album);
+        let seek = Arc::new(SeekRequest::default());
+        self.0.tracks.lock().unwrap().push_back(QueuedTrack {
+            info: CurrentTrack {
+                track_title: info.title,
+                artist_title: info.artist_name,
+                cover_art_id: info.cover_art_id,
+            },
+            duration,
+            progress: handle.progress(),
+            seek: seek.clone(),
+        });
+        if let Err(e) = self.0.main_stream_handle.queue(stream) {
+            self.0.tracks.lock().unwrap().pop_back();
+            return Err(e);
+        }
 
-        spawn(async move {
-            while let Ok(packet) = reader.format.next_packet() {
-                let buf = decoder.decode(&packet).unwrap();
-                let mut samps = SampleBuffer::new(buf.capacity() as u64, *buf.spec());
-                samps.copy_planar_ref(buf);
+        Ok(OpenTrack {
+            reader,
+            decoder,
+            handle,
+            track_id,
+            time_base,
+            seek,
+            measurement,
+        })
+    }
+}
 
-                handle.send(samps.samples()).await;
-            }
+impl<R: Runtime> PlayerInner<R> {
+    fn send(&self, msg: PlayerUpdateMsg) {
+        send_update(&self.channel.lock().unwrap(), msg);
+    }
+    /// where we are in the current track and whether we're playing, for
+    /// picking up from after the main stream has been cleared
+    fn resume_point(&self) -> Option<(f64, bool)> {
+        let position = self.tracks.lock().unwrap().front()?.progress.position();
+        Some((position, self.main_stream_handle.is_playing()))
+    }
+
+    /// lets the frontend know something went wrong in the background
+    fn report_error(&self, error: Error) {
+        eprintln!("playback error: {error}");
+        notify_unauthorized(&self.app, &error);
+        self.send(PlayerUpdateMsg::PlaybackError { error });
+    }
+
+    fn announce_volume(&self) {
+        self.send(PlayerUpdateMsg::UpdateVolume {
+            volume: self.main_stream_handle.volume() as f64,
+            muted: self.main_stream_handle.muted(),
         });
     }
-    pub fn toggle_playing(&self) {
-        let playing = self.0.main_stream_handle.toggle_playing();
-        self.0
-            .channel
-            .send(PlayerUpdateMsg::UpdatePlaying { playing })
-            .unwrap();
+
+    /// tells the frontend about the track at the front of `tracks`
+    fn announce_current(&self) {
+        let tracks = self.tracks.lock().unwrap();
+        let Some(track) = tracks.front() else {
+            return;
+        };
+        self.send(PlayerUpdateMsg::UpdateCurrentTrack {
+            current_track: track.info.clone(),
+        });
+        if let Some(duration) = track.duration {
+            self.send(PlayerUpdateMsg::UpdateDuration { duration });
+        }
     }
-    pub fn skip(&self) {
-        todo!()
+
+    /// keeps `tracks` and the queue in step with what the main stream is
+    /// actually playing
+    fn follow_main_stream(&self) {
+        let mut rolled_over = false;
+        let finished = {
+            let mut tracks = self.tracks.lock().unwrap();
+            while tracks.len() > 1 && tracks[1].progress.started() {
+                tracks.pop_front();
+                self.queue.lock().unwrap().skip_forward();
+                rolled_over = true;
+            }
+            tracks.len() == 1 && tracks[0].progress.finished()
+        };
+
+        if rolled_over {
+            self.announce_current();
+        }
+        if finished && self.main_stream_handle.is_playing() {
+            self.main_stream_handle.pause();
+            self.send(PlayerUpdateMsg::UpdatePlaying { playing: false });
+        }
+    }
+}
+
+/// decodes a whole track into its track stream, handling seeks as they come
+/// in. `player` hears about seeks that didn't work
+async fn decode_track<R: Runtime>(track: OpenTrack, player: Weak<PlayerInner<R>>) -> Result<()> {
+    let OpenTrack {
+        mut reader,
+        mut decoder,
+        mut handle,
+        track_id,
+        time_base,
+        seek,
+        mut measurement,
+    } = track;
+
+    loop {
+        let position = seek.position.lock().unwrap().take();
+        if let Some(position) = position {
+            let seeked = reader.format.seek(
+                SeekMode::Coarse,
+                SeekTo::Time {
+                    time: Time::from(position),
+                    track_id: Some(track_id),
+                },
+            );
+            match seeked {
+                Ok(seeked) => {
+                    decoder.reset();
+                    let position = match time_base {
+                        Some(tb) => seconds(tb.calc_time(seeked.actual_ts)),
+                        None => position,
+                    };
+                    handle.flush(position).await;
+                    // we won't see all of the track now
+                    measurement = None;
+                }
+                // playback carries on from where it was, so the frontend
+                // has to be put back there too
+                Err(e) => {
+                    if let Some(player) = player.upgrade() {
+                        player.report_error(e.into());
+                        player.send(PlayerUpdateMsg::UpdatePosition {
+                            position: handle.progress().position(),
+                        });
+                    }
+                }
+            }
+        }
+
+        let packet = match reader.format.next_packet() {
+            Ok(p) => p,
+            // this is how the reader tells us the track is over. a seek
+            // can still come in right at the end, then we go back for it
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                match seek.finish() {
+                    true => break,
+                    false => continue,
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let buf = match decoder.decode(&packet) {
+            Ok(b) => b,
+            // a corrupt packet just gets skipped
+            Err(SymphoniaError::DecodeError(e)) => {
+                eprintln!("skipping bad packet: {e}");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let channels = buf.spec().channels.count();
+        let mut samps = SampleBuffer::new(buf.capacity() as u64, *buf.spec());
+        samps.copy_planar_ref(buf);
+        if let Some(m) = measurement.as_mut() {
+            let samples = samps.samples();
+            if !samples.is_empty() {
+                let planes: Vec<_> = samples.chunks_exact(samples.len() / channels).collect();
+                m.meter.add(&planes);
+            }
+        }
+
+        // a seek makes whatever we're sending stale, so don't wait around
+        // for room in the track stream
+        select! {
+            _ = handle.send(samps.samples()) => {}
+            _ = seek.notify.notified() => {}
+        }
+    }
+    handle.finish().await;
+    if let Some(m) = measurement {
+        if let Some(loudness) = m.meter.finish() {
+            m.cache.save_track_loudness(m.id, loudness);
+        }
+    }
+    Ok(())
+}
+
+/// follows the main stream as it moves through tracks and sends the current
+/// track's position to the frontend at a steady rate until the player goes
+/// away
+async fn monitor_playback<R: Runtime>(player: Weak<PlayerInner<R>>) {
+    let mut interval = interval(POSITION_REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(player) = player.upgrade() else {
+            return;
+        };
+        if player.main_stream_handle.take_requeue() {
+            Player(player.clone()).requeue().await;
+        }
+        if let Some(change) = player.main_stream_handle.take_device_change() {
+            Player(player.clone()).device_changed(change).await;
+        }
+        player.follow_main_stream();
+        if !player.main_stream_handle.is_playing() {
+            continue;
+        }
+        let position = player
+            .tracks
+            .lock()
+            .unwrap()
+            .front()
+            .map(|t| t.progress.position());
+        if let Some(position) = position {
+            player.send(PlayerUpdateMsg::UpdatePosition { position });
+        }
+    }
+}
+
+/// the levels are worked out here rather than in the output callback, which
+/// only copies the samples into the meter tap. while metering is off this
+/// waits on `metering` instead of checking in every tick
+async fn report_levels<R: Runtime>(player: Weak<PlayerInner<R>>, metering: Arc<Notify>) {
+    let mut interval = interval(LEVELS_REPORT_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let Some(player) = player.upgrade() else {
+            return;
+        };
+        if !player.main_stream_handle.metering() {
+            // the player can't be held on to while we wait, or it would
+            // never go away
+            drop(player);
+            metering.notified().await;
+            continue;
+        }
+        if let Some(levels) = player.main_stream_handle.read_levels() {
+            player.send(PlayerUpdateMsg::UpdateLevels { levels });
+        }
+    }
+}
+
+/// the webview can go away while we're still playing, which shouldn't take
+/// playback down with it
+fn send_update(channel: &Channel<PlayerUpdateMsg>, msg: PlayerUpdateMsg) {
+    if let Err(e) = channel.send(msg) {
+        eprintln!("couldn't send player update: {e}");
+    }
+}
+
+fn seconds(time: Time) -> f64 {
+    time.seconds as f64 + time.frac
+}
+
+/// the ordered list of tracks the player is working through
+struct PlayQueue {
+    previous: Vec<i64>,
+    current: Option<i64>,
+    upcoming: VecDeque<i64>,
+}
+impl PlayQueue {
+    pub fn new() -> Self {
+        Self {
+            previous: Vec::new(),
+            current: None,
+            upcoming: VecDeque::new(),
+        }
+    }
+    /// the current track followed by everything that's coming up
+    pub fn tracks(&self) -> Vec<i64> {
+        self.current
+            .iter()
+            .chain(self.upcoming.iter())
+            .copied()
+            .collect()
+    }
+    /// replaces the upcoming tracks, the current track is moved into the
+    /// history so skipping back still works
+    pub fn replace(&mut self, current: i64, upcoming: impl IntoIterator<Item = i64>) {
+        if let Some(c) = self.current.replace(current) {
+            self.previous.push(c);
+        }
+        self.upcoming = upcoming.into_iter().collect();
+    }
+    /// moves to the next track, returns `None` (and leaves the queue
+    /// untouched) if there is nothing left
+    pub fn skip_forward(&mut self) -> Option<i64> {
+        let next = self.upcoming.pop_front()?;
+        if let Some(c) = self.current.replace(next) {
+            self.previous.push(c);
+        }
+        Some(next)
+    }
+    /// moves back to the previous track, if there isn't one the current
+    /// track is started over
+    pub fn skip_back(&mut self) -> Option<i64> {
+        if let Some(prev) = self.previous.pop() {
+            if let Some(c) = self.current.replace(prev) {
+                self.upcoming.push_front(c);
+            }
+        }
+        self.current
     }
 }
 
 #[derive(Serialize, Clone)]
 #[serde(tag = "event", content = "data")]
 pub enum PlayerUpdateMsg {
-    UpdatePlaying { playing: bool },
-    UpdateCurrentTrack { current_track: CurrentTrack },
+    UpdatePlaying {
+        playing: bool,
+    },
+    UpdateCurrentTrack {
+        current_track: CurrentTrack,
+    },
+    /// seconds into the current track
+    UpdatePosition {
+        position: f64,
+    },
+    /// length of the current track in seconds
+    UpdateDuration {
+        duration: f64,
+    },
+    /// `volume` goes from 0 to 1
+    UpdateVolume {
+        volume: f64,
+        muted: bool,
+    },
+    /// something went wrong while opening or decoding a track
+    PlaybackError {
+        error: Error,
+    },
+    /// the output device went away (or came back) and we moved to another
+    /// one
+    OutputDeviceChanged {
+        device: String,
+    },
+    /// how loud the output is, sent about 30 times a second while metering
+    /// is on
+    UpdateLevels {
+        levels: Levels,
+    },
 }
 #[derive(Serialize, Clone)]
 pub struct CurrentTrack {
@@ -130,3 +845,61 @@ pub struct CurrentTrack {
     artist_title: String,
     cover_art_id: i64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_empty_queue_stays_empty() {
+        let mut queue = PlayQueue::new();
+        assert_eq!(queue.skip_forward(), None);
+        assert_eq!(queue.skip_back(), None);
+        assert!(queue.tracks().is_empty());
+    }
+
+    #[test]
+    fn skipping_forward_stops_at_the_end() {
+        let mut queue = PlayQueue::new();
+        queue.replace(1, [2, 3]);
+        assert_eq!(queue.skip_forward(), Some(2));
+        assert_eq!(queue.skip_forward(), Some(3));
+        assert_eq!(queue.skip_forward(), None);
+        assert_eq!(queue.tracks(), [3]);
+    }
+
+    #[test]
+    fn skipping_back_at_the_start_restarts_the_track() {
+        let mut queue = PlayQueue::new();
+        queue.replace(1, [2]);
+        assert_eq!(queue.skip_back(), Some(1));
+        assert_eq!(queue.tracks(), [1, 2]);
+    }
+
+    #[test]
+    fn skipping_back_goes_through_the_history() {
+        let mut queue = PlayQueue::new();
+        queue.replace(1, [2, 3]);
+        queue.skip_forward();
+        queue.skip_forward();
+        assert_eq!(queue.skip_back(), Some(2));
+        assert_eq!(queue.skip_back(), Some(1));
+        assert_eq!(queue.skip_back(), Some(1));
+        // and forward again the same way
+        assert_eq!(queue.tracks(), [1, 2, 3]);
+        assert_eq!(queue.skip_forward(), Some(2));
+    }
+
+    #[test]
+    fn replaced_tracks_can_be_skipped_back_to() {
+        let mut queue = PlayQueue::new();
+        queue.replace(1, [2]);
+        queue.skip_forward();
+        queue.replace(10, [11]);
+        assert_eq!(queue.tracks(), [10, 11]);
+        assert_eq!(queue.skip_back(), Some(2));
+        assert_eq!(queue.skip_back(), Some(1));
+        // the new album carries on after the track we went back to
+        assert_eq!(queue.tracks(), [1, 2, 10, 11]);
+    }
+}
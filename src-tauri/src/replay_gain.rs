@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    meta::{MetadataRevision, StandardTagKey},
+    probe::ProbeResult,
+};
+
+use crate::loudness::TrackLoudness;
+
+/// the loudness replaygain 2.0 brings everything to, tags are relative to
+/// this so we use it for the loudness we work out ourselves too
+const REFERENCE_LUFS: f64 = -18.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    /// the default, so nobody's playback level changes without them asking
+    #[default]
+    Off,
+    /// every track is brought to the same loudness
+    Track,
+    /// whole albums are brought to the same loudness, so the quiet tracks on
+    /// an album stay quieter than the loud ones
+    Album,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct ReplayGainSettings {
+    pub mode: ReplayGainMode,
+    /// measure tracks that don't have tags ourselves. the measurement is
+    /// taken the first time a track plays all the way through, so it only
+    /// gets normalized from the second time on
+    #[serde(default)]
+    pub compute_missing: bool,
+}
+
+/// replaygain tags read from a track, gains are in dB and peaks are linear
+#[derive(Default)]
+pub struct ReplayGainTags {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f32>,
+}
+impl ReplayGainTags {
+    /// looks through the container's metadata and anything that came before
+    /// it (like an id3 tag), the container wins if both have a tag
+    pub fn read(probed: &mut ProbeResult) -> Self {
+        let mut tags = Self::default();
+        if let Some(revision) = probed.format.metadata().current() {
+            tags.fill_from(revision);
+        }
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            tags.fill_from(revision);
+        }
+        tags
+    }
+
+    fn fill_from(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => {
+                    self.track_gain = self.track_gain.or(parse_gain(&value))
+                }
+                Some(StandardTagKey::ReplayGainTrackPeak) => {
+                    self.track_peak = self.track_peak.or(value.trim().parse().ok())
+                }
+                Some(StandardTagKey::ReplayGainAlbumGain) => {
+                    self.album_gain = self.album_gain.or(parse_gain(&value))
+                }
+                Some(StandardTagKey::ReplayGainAlbumPeak) => {
+                    self.album_peak = self.album_peak.or(value.trim().parse().ok())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+}
+
+/// tags look like "-6.54 dB"
+fn parse_gain(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    number.trim().parse().ok()
+}
+
+/// the linear gain to play a track at. tags win over anything we measured,
+/// album mode falls back to the track gain if there's no album gain (and
+/// the other way around), and the gain is pulled back if it would push the
+/// track's peak past full scale
+pub fn track_gain(
+    settings: ReplayGainSettings,
+    tags: &ReplayGainTags,
+    measured: Option<TrackLoudness>,
+) -> f32 {
+    let track = tags.track_gain.map(|g| (g, tags.track_peak));
+    let album = tags.album_gain.map(|g| (g, tags.album_peak));
+    let measured = measured.map(|m| (REFERENCE_LUFS - m.integrated_lufs, Some(m.peak)));
+    let chosen = match settings.mode {
+        ReplayGainMode::Off => return 1.0,
+        ReplayGainMode::Track => track.or(album),
+        ReplayGainMode::Album => album.or(track),
+    };
+    let Some((gain_db, peak)) = chosen.or(measured) else {
+        return 1.0;
+    };
+
+    let gain = 10f32.powf(gain_db as f32 / 20.0);
+    match peak {
+        Some(peak) if peak > 0.0 => gain.min(1.0 / peak),
+        _ => gain,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: ReplayGainMode) -> ReplayGainSettings {
+        ReplayGainSettings {
+            mode,
+            compute_missing: false,
+        }
+    }
+
+    fn db(gain: f32) -> f32 {
+        20.0 * gain.log10()
+    }
+
+    fn assert_db(gain: f32, expected: f32) {
+        assert!((db(gain) - expected).abs() < 1e-4, "{} dB", db(gain));
+    }
+
+    #[test]
+    fn gains_are_parsed_with_or_without_a_unit() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+1.2 db"), Some(1.2));
+        assert_eq!(parse_gain(" 3.5dB "), Some(3.5));
+        assert_eq!(parse_gain("0"), Some(0.0));
+        assert_eq!(parse_gain("loud"), None);
+        assert_eq!(parse_gain("dB"), None);
+        assert_eq!(parse_gain(""), None);
+    }
+
+    #[test]
+    fn off_leaves_everything_alone() {
+        let tags = ReplayGainTags {
+            track_gain: Some(-6.0),
+            ..Default::default()
+        };
+        assert_eq!(track_gain(settings(ReplayGainMode::Off), &tags, None), 1.0);
+        assert!(ReplayGainSettings::default().mode == ReplayGainMode::Off);
+    }
+
+    #[test]
+    fn modes_fall_back_on_each_other() {
+        let track_only = ReplayGainTags {
+            track_gain: Some(-6.0),
+            ..Default::default()
+        };
+        let album_only = ReplayGainTags {
+            album_gain: Some(-3.0),
+            ..Default::default()
+        };
+        let both = ReplayGainTags {
+            track_gain: Some(-6.0),
+            album_gain: Some(-3.0),
+            ..Default::default()
+        };
+        let track = settings(ReplayGainMode::Track);
+        let album = settings(ReplayGainMode::Album);
+
+        assert_db(track_gain(track, &both, None), -6.0);
+        assert_db(track_gain(album, &both, None), -3.0);
+        assert_db(track_gain(album, &track_only, None), -6.0);
+        assert_db(track_gain(track, &album_only, None), -3.0);
+    }
+
+    #[test]
+    fn tags_win_over_measurements() {
+        let measured = TrackLoudness {
+            integrated_lufs: -8.0,
+            peak: 0.5,
+        };
+        let track = settings(ReplayGainMode::Track);
+        let tags = ReplayGainTags {
+            track_gain: Some(-6.0),
+            ..Default::default()
+        };
+
+        assert_db(track_gain(track, &tags, Some(measured)), -6.0);
+        // -8 LUFS is 10 dB over the reference
+        let untagged = ReplayGainTags::default();
+        assert_db(track_gain(track, &untagged, Some(measured)), -10.0);
+        assert_eq!(track_gain(track, &untagged, None), 1.0);
+    }
+
+    #[test]
+    fn gain_is_pulled_back_to_keep_peaks_in() {
+        let tags = ReplayGainTags {
+            track_gain: Some(12.0),
+            track_peak: Some(0.5),
+            album_gain: Some(3.0),
+            album_peak: Some(0.9),
+        };
+        // +12 dB would take a 0.5 peak way past full scale
+        assert_eq!(
+            track_gain(settings(ReplayGainMode::Track), &tags, None),
+            2.0
+        );
+        // +3 dB is fine at 0.5, but not at 0.9
+        let album = track_gain(settings(ReplayGainMode::Album), &tags, None);
+        assert!((album - 1.0 / 0.9).abs() < 1e-6, "{album}");
+    }
+}
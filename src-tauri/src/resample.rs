@@ -0,0 +1,216 @@
+use rubato::{FftFixedIn, Resampler};
+
+/// how many frames the resampler works on at a time
+const CHUNK_FRAMES: usize = 256;
+
+/// sample rate conversion for planar audio that comes in however many frames
+/// at a time the decoder feels like. frames that don't fill a whole chunk
+/// are held on to until the next call, so nothing gets dropped between
+/// packets
+pub struct ResampleStage {
+    resampler: FftFixedIn<f32>,
+    in_rate: u32,
+    out_rate: u32,
+    /// frames waiting for a whole chunk, one buffer per channel
+    pending: Vec<Vec<f32>>,
+    /// frames of the resampler's delay we still have to throw away
+    to_skip: usize,
+    frames_in: u64,
+    frames_out: u64,
+}
+impl ResampleStage {
+    pub fn new(in_rate: u32, out_rate: u32, channels: usize) -> Self {
+        let resampler = FftFixedIn::new(
+            in_rate as usize,
+            out_rate as usize,
+            CHUNK_FRAMES,
+            2,
+            channels,
+        )
+        .unwrap();
+        Self {
+            to_skip: resampler.output_delay(),
+            resampler,
+            in_rate,
+            out_rate,
+            pending: vec![Vec::with_capacity(CHUNK_FRAMES); channels],
+            frames_in: 0,
+            frames_out: 0,
+        }
+    }
+
+    /// resamples `planes` (one per channel, all the same length), returns as
+    /// many resampled frames as there are whole chunks for
+    pub fn process<P: AsRef<[f32]>>(&mut self, planes: &[P]) -> Vec<Vec<f32>> {
+        let mut out = vec![Vec::new(); self.pending.len()];
+        let frames = planes.first().map_or(0, |p| p.as_ref().len());
+        self.frames_in += frames as u64;
+
+        let mut start = 0;
+        while start < frames {
+            let take = (CHUNK_FRAMES - self.pending[0].len()).min(frames - start);
+            for (pending, plane) in self.pending.iter_mut().zip(planes) {
+                pending.extend_from_slice(&plane.as_ref()[start..start + take]);
+            }
+            start += take;
+
+            if self.pending[0].len() == CHUNK_FRAMES {
+                let resampled = self.resampler.process(&self.pending, None).unwrap();
+                self.pending.iter_mut().for_each(Vec::clear);
+                self.push_output(&mut out, resampled);
+            }
+        }
+        out
+    }
+
+    /// resamples whatever is still held back, call this once at the end of
+    /// the track. the total comes out to exactly as many frames as the
+    /// input is worth at the new rate
+    pub fn finish(&mut self) -> Vec<Vec<f32>> {
+        let mut out = vec![Vec::new(); self.pending.len()];
+        let expected = self.frames_in * self.out_rate as u64 / self.in_rate as u64;
+
+        // rubato doesn't like being given empty buffers here
+        if !self.pending[0].is_empty() {
+            let resampled = self
+                .resampler
+                .process_partial(Some(&self.pending), None)
+                .unwrap();
+            self.pending.iter_mut().for_each(Vec::clear);
+            self.push_output(&mut out, resampled);
+        }
+        // the last of the real audio is still stuck in the resampler's delay,
+        // so push silence through until it comes out
+        while self.frames_out < expected {
+            let resampled = self
+                .resampler
+                .process_partial::<Vec<f32>>(None, None)
+                .unwrap();
+            self.push_output(&mut out, resampled);
+        }
+
+        // and then cut off the silence that came out after it
+        let extra = (self.frames_out - expected) as usize;
+        for plane in out.iter_mut() {
+            plane.truncate(plane.len().saturating_sub(extra));
+        }
+        self.frames_out = expected;
+        out
+    }
+
+    /// throws away everything in progress, for when we jump somewhere else
+    /// in the track
+    pub fn reset(&mut self) {
+        self.resampler.reset();
+        self.pending.iter_mut().for_each(Vec::clear);
+        self.to_skip = self.resampler.output_delay();
+        self.frames_in = 0;
+        self.frames_out = 0;
+    }
+
+    fn push_output(&mut self, out: &mut [Vec<f32>], resampled: Vec<Vec<f32>>) {
+        let frames = resampled.first().map_or(0, Vec::len);
+        let skip = self.to_skip.min(frames);
+        self.to_skip -= skip;
+        self.frames_out += (frames - skip) as u64;
+        for (out, plane) in out.iter_mut().zip(resampled) {
+            out.extend_from_slice(&plane[skip..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    /// feeds `packets` frames at a time through a stage and returns
+    /// everything that comes out
+    fn run(in_rate: u32, out_rate: u32, input: &[Vec<f32>], packets: &[usize]) -> Vec<Vec<f32>> {
+        let mut stage = ResampleStage::new(in_rate, out_rate, input.len());
+        let mut out = vec![Vec::new(); input.len()];
+        let mut start = 0;
+        for &len in packets.iter().cycle() {
+            if start >= input[0].len() {
+                break;
+            }
+            let end = (start + len).min(input[0].len());
+            let planes: Vec<_> = input.iter().map(|p| &p[start..end]).collect();
+            for (out, plane) in out.iter_mut().zip(stage.process(&planes)) {
+                out.extend(plane);
+            }
+            start = end;
+        }
+        for (out, plane) in out.iter_mut().zip(stage.finish()) {
+            out.extend(plane);
+        }
+        out
+    }
+
+    fn sine(rate: u32, freq: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (TAU * freq * i as f32 / rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn sample_count_is_preserved() {
+        for (in_rate, out_rate) in [(44100, 48000), (48000, 44100), (96000, 48000)] {
+            for frames in [0, 1, 100, 255, 256, 257, 4608, 44100, 123_457] {
+                let input = vec![vec![0.25; frames]; 2];
+                // odd sized packets that never line up with the chunk size
+                let out = run(in_rate, out_rate, &input, &[1152, 37, 4096, 1, 300]);
+                let expected = frames as u64 * out_rate as u64 / in_rate as u64;
+                for plane in &out {
+                    assert_eq!(
+                        plane.len() as u64,
+                        expected,
+                        "{frames} frames from {in_rate} to {out_rate}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packet_size_does_not_matter() {
+        let input = vec![sine(44100, 440.0, 20_000), sine(44100, 660.0, 20_000)];
+        let whole = run(44100, 48000, &input, &[20_000]);
+        let split = run(44100, 48000, &input, &[1, 999, 37, 4096]);
+        for (whole, split) in whole.iter().zip(&split) {
+            assert_eq!(whole.len(), split.len());
+            for (a, b) in whole.iter().zip(split) {
+                assert!((a - b).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn no_gaps_at_packet_boundaries() {
+        // low enough that the resampler's fraction of a frame of delay
+        // doesn't matter
+        let input = vec![sine(44100, 100.0, 44100)];
+        let out = run(44100, 48000, &input, &[1152, 37, 513]);
+        let reference = sine(48000, 100.0, out[0].len());
+        // leave the very start and end out, the filter rings a bit there
+        for i in 1000..out[0].len() - 1000 {
+            assert!(
+                (out[0][i] - reference[i]).abs() < 0.01,
+                "frame {i}: {} vs {}",
+                out[0][i],
+                reference[i]
+            );
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut stage = ResampleStage::new(44100, 48000, 1);
+        stage.process(&[vec![0.5; 1000]]);
+        stage.reset();
+        let mut total = stage.process(&[vec![0.5; 441]])[0].len();
+        total += stage.finish()[0].len();
+        assert_eq!(total, 480);
+    }
+}
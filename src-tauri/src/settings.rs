@@ -0,0 +1,244 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tauri_plugin_http::reqwest::Url;
+
+use crate::{
+    crossfade::CrossfadeSettings,
+    equalizer::EqualizerSettings,
+    error::{Error, Result},
+    replay_gain::ReplayGainSettings,
+    stereo::StereoSettings,
+};
+
+const DEFAULT_SERVER_URL: &str = "http://localhost:8080";
+
+/// a pi-fi server the user has saved
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerProfile {
+    pub name: String,
+    pub url: String,
+}
+
+/// everything the user can configure, saved as json in the app data dir
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Settings {
+    pub servers: Vec<ServerProfile>,
+    /// name of the server we're talking to
+    pub active_server: String,
+    /// play tracks at their own sample rate when the output device can
+    #[serde(default)]
+    pub native_sample_rate: bool,
+    /// name of the output device to play through, `None` for the default
+    #[serde(default)]
+    pub output_device: Option<String>,
+    /// 0 to 1
+    #[serde(default = "full_volume")]
+    pub volume: f64,
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub replay_gain: ReplayGainSettings,
+    #[serde(default)]
+    pub equalizer: EqualizerSettings,
+    #[serde(default)]
+    pub stereo: StereoSettings,
+    #[serde(default)]
+    pub crossfade: CrossfadeSettings,
+}
+fn full_volume() -> f64 {
+    1.0
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            servers: vec![ServerProfile {
+                name: "Local".into(),
+                url: DEFAULT_SERVER_URL.into(),
+            }],
+            active_server: "Local".into(),
+            native_sample_rate: false,
+            output_device: None,
+            volume: full_volume(),
+            muted: false,
+            replay_gain: ReplayGainSettings::default(),
+            equalizer: EqualizerSettings::default(),
+            stereo: StereoSettings::default(),
+            crossfade: CrossfadeSettings::default(),
+        }
+    }
+}
+impl Settings {
+    /// loads the saved settings, falling back to the defaults if there
+    /// aren't any (or they can't be read)
+    pub fn load(path: &Path) -> Self {
+        let saved = match fs::read(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                eprintln!("couldn't read settings: {e}");
+                return Self::default();
+            }
+        };
+        serde_json::from_slice(&saved).unwrap_or_else(|e| {
+            eprintln!("couldn't parse settings: {e}");
+            Self::default()
+        })
+    }
+
+    /// the settings are written out next to the old ones and moved into
+    /// place, so a save that doesn't finish can't leave half a file behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).unwrap();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, json)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| Error::Storage(e.to_string()))
+    }
+
+    pub fn active_server(&self) -> Result<&ServerProfile> {
+        self.server(&self.active_server)
+    }
+
+    /// switches to the first saved server (or puts the default one back if
+    /// there aren't any), for when `active_server` names one that's gone
+    pub fn reset_active_server(&mut self) -> &ServerProfile {
+        if self.servers.is_empty() {
+            self.servers = Self::default().servers;
+        }
+        self.active_server = self.servers[0].name.clone();
+        &self.servers[0]
+    }
+
+    pub fn server(&self, name: &str) -> Result<&ServerProfile> {
+        self.servers
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| Error::NotFound(format!("server \"{name}\"")))
+    }
+
+    /// adds a server, or updates the url of the one with the same name
+    pub fn save_server(&mut self, mut profile: ServerProfile) -> Result<()> {
+        profile.url = profile.url.trim_end_matches('/').into();
+        if profile.name.is_empty() {
+            return Err(Error::InvalidSettings("server needs a name".into()));
+        }
+        Url::parse(&profile.url).map_err(|e| Error::InvalidSettings(e.to_string()))?;
+        match self.servers.iter_mut().find(|s| s.name == profile.name) {
+            Some(s) => s.url = profile.url,
+            None => self.servers.push(profile),
+        }
+        Ok(())
+    }
+
+    /// makes a server we found on the network the active one. if there's
+    /// already a profile for its url that one gets used, whatever it's
+    /// called, but a profile with the same name somewhere else is left alone
+    pub fn use_discovered_server(&mut self, mut profile: ServerProfile) -> Result<()> {
+        profile.url = profile.url.trim_end_matches('/').into();
+        if let Some(saved) = self.servers.iter().find(|s| s.url == profile.url) {
+            self.active_server = saved.name.clone();
+            return Ok(());
+        }
+        if self.servers.iter().any(|s| s.name == profile.name) {
+            return Err(Error::InvalidSettings(format!(
+                "there's already a server called \"{}\"",
+                profile.name
+            )));
+        }
+        let name = profile.name.clone();
+        self.save_server(profile)?;
+        self.active_server = name;
+        Ok(())
+    }
+
+    /// removes a server, the active one can't be removed
+    pub fn remove_server(&mut self, name: &str) -> Result<()> {
+        if name == self.active_server {
+            return Err(Error::InvalidSettings(
+                "can't remove the server that's in use".into(),
+            ));
+        }
+        self.server(name)?;
+        self.servers.retain(|s| s.name != name);
+        Ok(())
+    }
+}
+
+/// where the library for `url` is saved, every server gets its own so
+/// switching between them doesn't mix their libraries up
+pub fn library_path(data_dir: &Path, url: &str) -> PathBuf {
+    let name: String = url
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    data_dir.join(format!("library-{name}.redb"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn profile(name: &str, url: &str) -> ServerProfile {
+        ServerProfile {
+            name: name.into(),
+            url: url.into(),
+        }
+    }
+
+    #[test]
+    fn saved_settings_load_back() {
+        let path = env::temp_dir().join(format!("pi-fi-settings-{}.json", process::id()));
+        let mut settings = Settings::default();
+        settings
+            .save_server(profile("Living Room", "http://10.0.0.2:8080"))
+            .unwrap();
+        settings.save(&path).unwrap();
+        let loaded = Settings::load(&path);
+        let leftover = path.with_extension("tmp").exists();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded.servers.len(), 2);
+        assert!(!leftover);
+    }
+
+    #[test]
+    fn discovered_servers_are_added_and_used() {
+        let mut settings = Settings::default();
+        settings
+            .use_discovered_server(profile("Pi", "http://10.0.0.2:8080/"))
+            .unwrap();
+        assert_eq!(
+            settings.active_server().unwrap().url,
+            "http://10.0.0.2:8080"
+        );
+        assert_eq!(settings.servers.len(), 2);
+    }
+
+    #[test]
+    fn discovered_servers_use_the_profile_for_their_url() {
+        let mut settings = Settings::default();
+        settings
+            .save_server(profile("Living Room", "http://10.0.0.2:8080"))
+            .unwrap();
+        settings
+            .use_discovered_server(profile("Pi", "http://10.0.0.2:8080"))
+            .unwrap();
+        assert_eq!(settings.active_server, "Living Room");
+        assert_eq!(settings.servers.len(), 2);
+    }
+
+    #[test]
+    fn discovered_servers_dont_replace_profiles_with_their_name() {
+        let mut settings = Settings::default();
+        let clash = settings.use_discovered_server(profile("Local", "http://10.0.0.2:8080"));
+        assert!(matches!(clash, Err(Error::InvalidSettings(_))));
+        assert_eq!(settings.active_server().unwrap().url, DEFAULT_SERVER_URL);
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    f32::consts::PI,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::volume::RAMP_MS;
+
+/// how much of each side gets fed into the other, these are the levels
+/// bs2b ships with
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Crossfeed {
+    /// close to listening on speakers
+    Bauer,
+    ChuMoy,
+    /// the strongest, for records with instruments panned hard to one side
+    Meier,
+}
+impl Crossfeed {
+    /// cutoff of the feed to the other side in Hz, and how much quieter than
+    /// the direct signal it is at low frequencies in dB
+    fn params(self) -> (f32, f32) {
+        match self {
+            Crossfeed::Bauer => (700.0, 4.5),
+            Crossfeed::ChuMoy => (700.0, 6.0),
+            Crossfeed::Meier => (650.0, 9.5),
+        }
+    }
+}
+
+/// headphone and stereo image settings, all of these only touch the front
+/// left and right channels
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct StereoSettings {
+    pub crossfeed: Option<Crossfeed>,
+    /// both sides get the same mix of left and right, crossfeed doesn't do
+    /// anything with this on
+    pub mono: bool,
+    /// -1 is all the way left, 1 all the way right
+    pub balance: f32,
+}
+
+/// stereo settings shared between the main stream and its handle
+#[derive(Default)]
+pub struct StereoState {
+    /// 0 is off, otherwise one more than the `Crossfeed`
+    crossfeed: AtomicU8,
+    mono: AtomicBool,
+    /// an f32
+    balance: AtomicU32,
+}
+impl StereoState {
+    pub fn set(&self, settings: StereoSettings) {
+        let crossfeed = settings.crossfeed.map_or(0, |c| c as u8 + 1);
+        self.crossfeed.store(crossfeed, Ordering::Release);
+        self.mono.store(settings.mono, Ordering::Release);
+        self.balance.store(
+            settings.balance.clamp(-1.0, 1.0).to_bits(),
+            Ordering::Release,
+        );
+    }
+    pub fn get(&self) -> StereoSettings {
+        let crossfeed = match self.crossfeed.load(Ordering::Acquire) {
+            1 => Some(Crossfeed::Bauer),
+            2 => Some(Crossfeed::ChuMoy),
+            3 => Some(Crossfeed::Meier),
+            _ => None,
+        };
+        StereoSettings {
+            crossfeed,
+            mono: self.mono.load(Ordering::Acquire),
+            balance: f32::from_bits(self.balance.load(Ordering::Acquire)),
+        }
+    }
+}
+
+/// applies the stereo settings to the mix. the settings are read every
+/// time, so changing them doesn't interrupt anything that's playing. mono,
+/// crossfeed and balance all fade from where they were to where they should
+/// be, rather than jumping there and clicking
+pub struct StereoStage {
+    /// the crossfeed filter that's running and the rate it's for. a
+    /// different crossfeed only takes over once this one has faded out
+    crossfeed: Option<(Crossfeed, u32, CrossfeedFilter)>,
+    /// how much of the mono mix and the crossfeed is in the output, 0 is
+    /// none and 1 is all of it
+    mono_mix: f32,
+    crossfeed_mix: f32,
+    /// left and right gain for the balance
+    balance: [f32; 2],
+}
+impl Default for StereoStage {
+    fn default() -> Self {
+        Self {
+            crossfeed: None,
+            mono_mix: 0.0,
+            crossfeed_mix: 0.0,
+            balance: [1.0; 2],
+        }
+    }
+}
+impl StereoStage {
+    /// `buf` is interleaved, `channels` per frame
+    pub fn process(&mut self, state: &StereoState, buf: &mut [f32], channels: usize, rate: u32) {
+        if channels < 2 {
+            return;
+        }
+        let settings = state.get();
+        // crossfeed doesn't do anything to a mono mix
+        let wanted = settings.crossfeed.filter(|_| !settings.mono);
+        if self.crossfeed_mix == 0.0 {
+            self.crossfeed = match (self.crossfeed.take(), wanted) {
+                (Some(running), Some(c)) if running.0 == c && running.1 == rate => Some(running),
+                (_, Some(c)) => Some((c, rate, CrossfeedFilter::new(c, rate))),
+                (_, None) => None,
+            };
+        } else if let Some((c, r, filter)) = self.crossfeed.as_mut() {
+            // the output was reopened, there's no fading across that
+            if *r != rate {
+                *filter = CrossfeedFilter::new(*c, rate);
+                *r = rate;
+            }
+        }
+
+        let mono = if settings.mono { 1.0 } else { 0.0 };
+        let crossfeed = match (&self.crossfeed, wanted) {
+            (Some((running, ..)), Some(c)) if *running == c => 1.0,
+            _ => 0.0,
+        };
+        let balance = [
+            (1.0 - settings.balance).min(1.0),
+            (1.0 + settings.balance).min(1.0),
+        ];
+        if self.crossfeed.is_none()
+            && [self.mono_mix, mono] == [0.0; 2]
+            && [self.balance, balance] == [[1.0; 2]; 2]
+        {
+            return;
+        }
+
+        let step = 1000.0 / (RAMP_MS * rate as f32);
+        for frame in buf.chunks_mut(channels) {
+            let mix = approach(&mut self.mono_mix, mono, step);
+            if mix > 0.0 {
+                let mid = (frame[0] + frame[1]) * 0.5;
+                frame[0] += (mid - frame[0]) * mix;
+                frame[1] += (mid - frame[1]) * mix;
+            }
+
+            let mix = approach(&mut self.crossfeed_mix, crossfeed, step);
+            if let Some((.., filter)) = self.crossfeed.as_mut() {
+                let dry = [frame[0], frame[1]];
+                filter.process(&mut frame[..2]);
+                for (s, dry) in frame.iter_mut().zip(dry) {
+                    *s = dry + (*s - dry) * mix;
+                }
+            }
+
+            for (c, target) in balance.into_iter().enumerate() {
+                frame[c] *= approach(&mut self.balance[c], target, step);
+            }
+        }
+
+        // faded all the way out, so it can stop running
+        if self.crossfeed_mix == 0.0 && wanted.is_none() {
+            self.crossfeed = None;
+        }
+    }
+}
+
+/// moves `value` one `step` closer to `target`, and returns where it ended up
+fn approach(value: &mut f32, target: f32, step: f32) -> f32 {
+    *value = match *value < target {
+        true => (*value + step).min(target),
+        false => (*value - step).max(target),
+    };
+    *value
+}
+
+/// the bs2b crossfeed: each side gets a low passed copy of the other side
+/// mixed in, and its own high frequencies boosted a little to make up for
+/// the low end getting louder
+struct CrossfeedFilter {
+    /// low pass for the signal going to the other side
+    lo_a0: f32,
+    lo_b1: f32,
+    /// high shelf for the direct signal
+    hi_a0: f32,
+    hi_a1: f32,
+    hi_b1: f32,
+    gain: f32,
+    /// filter state for left and right
+    lo: [f32; 2],
+    hi: [f32; 2],
+    last_in: [f32; 2],
+}
+impl CrossfeedFilter {
+    fn new(crossfeed: Crossfeed, rate: u32) -> Self {
+        let (cutoff, feed_db) = crossfeed.params();
+        let rate = rate as f32;
+
+        let lo_gain_db = feed_db * -5.0 / 6.0 - 3.0;
+        let hi_gain_db = feed_db / 6.0 - 3.0;
+        let lo_gain = 10f32.powf(lo_gain_db / 20.0);
+        let hi_gain = 1.0 - 10f32.powf(hi_gain_db / 20.0);
+        let hi_cutoff = cutoff * 2f32.powf((lo_gain_db - 20.0 * hi_gain.log10()) / 12.0);
+
+        let x = (-2.0 * PI * cutoff / rate).exp();
+        let (lo_a0, lo_b1) = (lo_gain * (1.0 - x), x);
+        let x = (-2.0 * PI * hi_cutoff / rate).exp();
+        let (hi_a0, hi_a1, hi_b1) = (1.0 - hi_gain * (1.0 - x), -x, x);
+
+        Self {
+            lo_a0,
+            lo_b1,
+            hi_a0,
+            hi_a1,
+            hi_b1,
+            gain: 1.0 / (1.0 - hi_gain + lo_gain),
+            lo: [0.0; 2],
+            hi: [0.0; 2],
+            last_in: [0.0; 2],
+        }
+    }
+
+    /// `frame` is one left and right sample
+    fn process(&mut self, frame: &mut [f32]) {
+        for (c, &x) in frame.iter().enumerate() {
+            self.lo[c] = self.lo_a0 * x + self.lo_b1 * self.lo[c];
+            self.hi[c] = self.hi_a0 * x + self.hi_a1 * self.last_in[c] + self.hi_b1 * self.hi[c];
+            self.last_in[c] = x;
+        }
+        frame[0] = (self.hi[0] + self.lo[1]) * self.gain;
+        frame[1] = (self.hi[1] + self.lo[0]) * self.gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(settings: StereoSettings) -> StereoState {
+        let state = StereoState::default();
+        state.set(settings);
+        state
+    }
+
+    /// `frames` of the same `frame` over and over, through a stage that's
+    /// been running with `settings`
+    fn run(stage: &mut StereoStage, state: &StereoState, frame: &[f32], frames: usize) -> Vec<f32> {
+        let mut buf = frame.repeat(frames);
+        stage.process(state, &mut buf, frame.len(), 48000);
+        buf
+    }
+
+    #[test]
+    fn mono_sums_left_and_right() {
+        let state = state(StereoSettings {
+            mono: true,
+            ..Default::default()
+        });
+        let mut stage = StereoStage::default();
+        let out = run(&mut stage, &state, &[0.8, -0.2], 2000);
+
+        assert_eq!(out[out.len() - 2..], [0.3, 0.3]);
+    }
+
+    #[test]
+    fn balance_all_the_way_mutes_the_other_side() {
+        for (balance, expected) in [(1.0, [0.0, 0.5]), (-1.0, [0.5, 0.0])] {
+            let state = state(StereoSettings {
+                balance,
+                ..Default::default()
+            });
+            let mut stage = StereoStage::default();
+            let out = run(&mut stage, &state, &[0.5, 0.5], 2000);
+            assert_eq!(out[out.len() - 2..], expected, "balance {balance}");
+        }
+    }
+
+    #[test]
+    fn crossfeed_leaves_the_middle_alone() {
+        for crossfeed in [Crossfeed::Bauer, Crossfeed::ChuMoy, Crossfeed::Meier] {
+            let state = state(StereoSettings {
+                crossfeed: Some(crossfeed),
+                ..Default::default()
+            });
+            let mut stage = StereoStage::default();
+            let out = run(&mut stage, &state, &[0.5, 0.5], 48000);
+
+            for s in &out[out.len() - 2..] {
+                assert!((s - 0.5).abs() < 1e-3, "{s}");
+            }
+        }
+    }
+
+    #[test]
+    fn crossfeed_uses_the_front_pair_of_anything_bigger() {
+        let state = state(StereoSettings {
+            crossfeed: Some(Crossfeed::Meier),
+            ..Default::default()
+        });
+        let mut stage = StereoStage::default();
+        // only the front left has anything in it
+        let out = run(&mut stage, &state, &[0.5, 0.0, 0.25, 0.25, 0.0, 0.0], 4800);
+
+        let last = &out[out.len() - 6..];
+        assert!(last[1] > 0.05, "{last:?}");
+        assert_eq!(last[2..], [0.25, 0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn changes_fade_in_and_out() {
+        let state = state(StereoSettings::default());
+        let mut stage = StereoStage::default();
+        let frame = [0.8, -0.8];
+        let mut out = run(&mut stage, &state, &frame, 100);
+        for settings in [
+            StereoSettings {
+                mono: true,
+                ..Default::default()
+            },
+            StereoSettings {
+                crossfeed: Some(Crossfeed::Bauer),
+                ..Default::default()
+            },
+            StereoSettings {
+                crossfeed: Some(Crossfeed::Meier),
+                ..Default::default()
+            },
+            StereoSettings::default(),
+        ] {
+            state.set(settings);
+            out.extend(run(&mut stage, &state, &frame, 3000));
+        }
+
+        // everything moves a step at a time, nothing jumps
+        let step = 1000.0 / (RAMP_MS * 48000.0);
+        for side in out.chunks(2).collect::<Vec<_>>().windows(2) {
+            for c in 0..2 {
+                assert!((side[1][c] - side[0][c]).abs() < 2.0 * step, "{side:?}");
+            }
+        }
+        // and ends up back where it started
+        assert_eq!(out[out.len() - 2..], frame);
+        assert!(stage.crossfeed.is_none());
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// how long it takes the gain to catch up with a volume change, jumping
+/// straight there makes a click (or zipper noise while dragging a slider)
+pub const RAMP_MS: f32 = 20.0;
+
+/// volume settings shared between the main stream and its handle
+pub struct Volume {
+    /// an f32, 0 to 1
+    level: AtomicU32,
+    muted: AtomicBool,
+}
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            level: AtomicU32::new(1.0f32.to_bits()),
+            muted: AtomicBool::new(false),
+        }
+    }
+}
+impl Volume {
+    pub fn set_level(&self, level: f32) {
+        self.level
+            .store(level.clamp(0.0, 1.0).to_bits(), Ordering::Release);
+    }
+    pub fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Acquire))
+    }
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Release);
+    }
+    pub fn muted(&self) -> bool {
+        self.muted.load(Ordering::Acquire)
+    }
+    /// the gain samples should end up multiplied by. the level goes through
+    /// a cubic curve so the slider feels even, a straight line puts all the
+    /// audible change in the bottom few percent
+    fn gain(&self) -> f32 {
+        match self.muted() {
+            true => 0.0,
+            false => self.level().powi(3),
+        }
+    }
+}
+
+/// the gain the main stream is actually applying, which follows the volume
+/// settings a little bit behind
+pub struct GainRamp {
+    gain: f32,
+}
+impl GainRamp {
+    pub fn new(volume: &Volume) -> Self {
+        Self {
+            gain: volume.gain(),
+        }
+    }
+
+    /// applies the volume to `buf` (interleaved, `channels` per frame),
+    /// ramping towards it if it changed
+    pub fn apply(&mut self, volume: &Volume, buf: &mut [f32], channels: usize, out_rate: u32) {
+        let target = volume.gain();
+        if self.gain == target {
+            if target != 1.0 {
+                buf.iter_mut().for_each(|s| *s *= target);
+            }
+            return;
+        }
+
+        let step = 1000.0 / (RAMP_MS * out_rate as f32);
+        for frame in buf.chunks_mut(channels) {
+            self.gain = match self.gain < target {
+                true => (self.gain + step).min(target),
+                false => (self.gain - step).max(target),
+            };
+            frame.iter_mut().for_each(|s| *s *= self.gain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn volume(level: f32) -> Volume {
+        let volume = Volume::default();
+        volume.set_level(level);
+        volume
+    }
+
+    /// runs a buffer of ones through the ramp, so what comes out is the
+    /// gain at every sample
+    fn gains(ramp: &mut GainRamp, volume: &Volume, frames: usize) -> Vec<f32> {
+        let mut buf = vec![1.0; frames * 2];
+        ramp.apply(volume, &mut buf, 2, 48000);
+        buf
+    }
+
+    #[test]
+    fn the_whole_way_takes_the_ramp_length() {
+        let volume = volume(0.0);
+        let mut ramp = GainRamp::new(&volume);
+        volume.set_level(1.0);
+        let out = gains(&mut ramp, &volume, 2000);
+
+        // 20ms at 48kHz, give or take rounding on the way there
+        let reached = out.iter().position(|g| *g == 1.0).unwrap() / 2;
+        assert!((958..=962).contains(&reached), "{reached} frames");
+        assert!(out.windows(2).all(|w| w[1] >= w[0]));
+    }
+
+    #[test]
+    fn smaller_changes_are_quicker() {
+        let volume = volume(1.0);
+        let mut ramp = GainRamp::new(&volume);
+        // half the gain, after the curve
+        volume.set_level(0.5f32.cbrt());
+        let out = gains(&mut ramp, &volume, 2000);
+
+        let reached = out.iter().position(|g| (*g - 0.5).abs() < 1e-6).unwrap() / 2;
+        assert!((478..=482).contains(&reached), "{reached} frames");
+    }
+
+    #[test]
+    fn ends_up_exactly_on_the_target() {
+        let volume = volume(0.8);
+        let mut ramp = GainRamp::new(&volume);
+        volume.set_level(0.3);
+        // across a few buffers, which shouldn't matter
+        for _ in 0..10 {
+            gains(&mut ramp, &volume, 128);
+        }
+
+        let target = 0.3f32.powi(3);
+        assert_eq!(ramp.gain, target);
+        assert!(gains(&mut ramp, &volume, 64).iter().all(|g| *g == target));
+    }
+
+    #[test]
+    fn every_channel_in_a_frame_gets_the_same_gain() {
+        let volume = volume(0.0);
+        let mut ramp = GainRamp::new(&volume);
+        volume.set_level(1.0);
+        let out = gains(&mut ramp, &volume, 500);
+
+        for frame in out.chunks(2) {
+            assert_eq!(frame[0], frame[1]);
+        }
+        // and it moves on every frame, not every sample
+        assert!(out[2] > out[0]);
+    }
+
+    #[test]
+    fn muting_ramps_down_to_silence() {
+        let volume = volume(1.0);
+        let mut ramp = GainRamp::new(&volume);
+        volume.set_muted(true);
+        let out = gains(&mut ramp, &volume, 2000);
+
+        assert!(out[0] > 0.99);
+        assert_eq!(*out.last().unwrap(), 0.0);
+    }
+}
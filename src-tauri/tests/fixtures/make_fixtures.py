@@ -0,0 +1,84 @@
+#!/usr/bin/env python3
+"""writes the flac and cover art fixtures the mock server serves.
+
+there's no encoder needed, every subframe is stored verbatim. run this from
+anywhere, the files end up next to it.
+"""
+
+import math
+import struct
+import zlib
+from pathlib import Path
+
+RATE = 44100
+BLOCK_SIZE = 1024
+BLOCKS = 8
+HERE = Path(__file__).parent
+
+
+def crc8(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x07) & 0xFF if crc & 0x80 else (crc << 1) & 0xFF
+    return crc
+
+
+def crc16(data):
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x8005) & 0xFFFF if crc & 0x8000 else (crc << 1) & 0xFFFF
+    return crc
+
+
+def sine(freq, i):
+    return round(math.sin(2 * math.pi * freq * i / RATE) * 16000)
+
+
+def flac(freqs):
+    """16 bit stereo, a different sine on each side"""
+    frames = BLOCK_SIZE * BLOCKS
+    streaminfo = struct.pack(">HH", BLOCK_SIZE, BLOCK_SIZE)
+    # min and max frame size, unknown
+    streaminfo += bytes(6)
+    # rate (20 bits), channels - 1 (3), bits per sample - 1 (5), frames (36)
+    packed = (RATE << 44) | (1 << 41) | (15 << 36) | frames
+    streaminfo += packed.to_bytes(8, "big")
+    # no md5
+    streaminfo += bytes(16)
+    out = b"fLaC" + bytes([0x80]) + len(streaminfo).to_bytes(3, "big") + streaminfo
+
+    for block in range(BLOCKS):
+        # fixed blocksize, 16 bit blocksize at the end of the header, 44.1kHz,
+        # independent left and right, 16 bits per sample, frame number
+        header = bytes([0xFF, 0xF8, 0x79, 0x18, block])
+        header += struct.pack(">H", BLOCK_SIZE - 1)
+        header += bytes([crc8(header)])
+        frame = header
+        for freq in freqs:
+            start = block * BLOCK_SIZE
+            samples = [sine(freq, i) for i in range(start, start + BLOCK_SIZE)]
+            # verbatim subframe
+            frame += bytes([0x02]) + struct.pack(f">{BLOCK_SIZE}h", *samples)
+        out += frame + struct.pack(">H", crc16(frame))
+    return out
+
+
+def png():
+    """a single grey pixel"""
+
+    def chunk(kind, data):
+        body = kind + data
+        return struct.pack(">I", len(data)) + body + struct.pack(">I", zlib.crc32(body))
+
+    ihdr = struct.pack(">IIBBBBB", 1, 1, 8, 0, 0, 0, 0)
+    idat = zlib.compress(bytes([0, 0x80]))
+    return b"\x89PNG\r\n\x1a\n" + chunk(b"IHDR", ihdr) + chunk(b"IDAT", idat) + chunk(b"IEND", b"")
+
+
+(HERE / "1.flac").write_bytes(flac([440, 660]))
+(HERE / "2.flac").write_bytes(flac([550, 825]))
+(HERE / "1.png").write_bytes(png())
@@ -2,7 +2,9 @@ import "./App.css";
 import { Route, Router } from "@solidjs/router";
 import Library from "./routes/Library";
 import Album from "./routes/Album";
+import Settings from "./routes/Settings";
 import Player from "./components/Player";
+import Login from "./components/Login";
 
 
 function App() {
@@ -12,9 +14,11 @@ function App() {
         <Router>
           <Route path="/" component={Library} />
           <Route path="/album/:id" component={Album} />
+          <Route path="/settings" component={Settings} />
         </Router>
       </div>
       <Player />
+      <Login />
     </main>
   );
 }
@@ -0,0 +1,33 @@
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { createEffect, createResource, onCleanup } from "solid-js";
+
+// images go through the backend so they get fetched with our credentials
+const getImage = async (id: number): Promise<string> => {
+  const image = await invoke<ArrayBuffer>("get_image", { id });
+  return URL.createObjectURL(new Blob([image]));
+};
+
+function CoverArt(props: { id: number; class?: string }) {
+  const [url, { refetch }] = createResource(() => props.id, getImage);
+
+  const unlisten = [
+    listen("server-changed", () => refetch()),
+    listen("credentials-changed", () => refetch()),
+  ];
+  // each refetch makes a new url, the one it replaces isn't shown anymore
+  createEffect((previous?: string) => {
+    const current = url.latest;
+    if (previous && previous !== current) URL.revokeObjectURL(previous);
+    return current;
+  });
+  onCleanup(() => {
+    unlisten.forEach((u) => u.then((f) => f()));
+    const current = url.latest;
+    if (current) URL.revokeObjectURL(current);
+  });
+
+  return <img class={props.class} src={url.latest} />;
+}
+
+export default CoverArt;
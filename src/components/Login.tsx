@@ -0,0 +1,68 @@
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { createSignal, onCleanup, Show } from "solid-js";
+import { AppError, describeError } from "../error";
+
+function Login() {
+  const [open, setOpen] = createSignal(false);
+  const [useToken, setUseToken] = createSignal(false);
+  const [username, setUsername] = createSignal("");
+  const [password, setPassword] = createSignal("");
+  const [token, setToken] = createSignal("");
+  const [error, setError] = createSignal<AppError | null>(null);
+
+  const unlisten = listen("login-required", () => setOpen(true));
+  onCleanup(() => unlisten.then((f) => f()));
+
+  const submit = () => {
+    const login = useToken()
+      ? invoke("set_api_token", { token: token() })
+      : invoke("login", { username: username(), password: password() });
+    login
+      .then(() => {
+        setError(null);
+        setPassword("");
+        setOpen(false);
+      })
+      .catch((e: AppError) => setError(e));
+  };
+
+  return (
+    <Show when={open()}>
+      <div class="fixed inset-0 z-50 bg-black flex items-center justify-center">
+        <form
+          class="flex flex-col space-y-4 w-2/3"
+          onSubmit={(e) => {
+            e.preventDefault();
+            submit();
+          }}
+        >
+          <h1 class="text-4xl font-bold font-serif">Log in</h1>
+          <Show when={error()}>
+            {(error) => <p class="text-red-500">{describeError(error())}</p>}
+          </Show>
+          <Show
+            when={useToken()}
+            fallback={
+              <>
+                <input class="bg-black border px-2" placeholder="Username" value={username()} onInput={(e) => setUsername(e.currentTarget.value)} />
+                <input class="bg-black border px-2" type="password" placeholder="Password" value={password()} onInput={(e) => setPassword(e.currentTarget.value)} />
+              </>
+            }
+          >
+            <input class="bg-black border px-2" type="password" placeholder="API token" value={token()} onInput={(e) => setToken(e.currentTarget.value)} />
+          </Show>
+          <button type="button" onClick={() => setUseToken(!useToken())}>
+            {useToken() ? "Use a username and password" : "Use an API token"}
+          </button>
+          <div class="flex flex-row space-x-4">
+            <button type="submit">Log in</button>
+            <button type="button" onClick={() => setOpen(false)}>Cancel</button>
+          </div>
+        </form>
+      </div>
+    </Show>
+  )
+}
+
+export default Login;
@@ -1,11 +1,22 @@
 import { Channel, invoke } from "@tauri-apps/api/core";
-import { IoPauseSharp, IoPlaySharp, IoPlaySkipForwardSharp } from "solid-icons/io";
-import { createSignal, Match, onMount, Show, Switch } from "solid-js";
+import { listen } from "@tauri-apps/api/event";
+import { IoPauseSharp, IoPlaySharp, IoPlaySkipBackSharp, IoPlaySkipForwardSharp, IoVolumeHighSharp, IoVolumeMuteSharp } from "solid-icons/io";
+import { createEffect, createSignal, Match, onCleanup, onMount, Show, Switch } from "solid-js";
 import { createStore } from "solid-js/store";
-import { SERVER_URL } from "..";
+import CoverArt from "./CoverArt";
+import SeekBar from "./SeekBar";
+import Visualizer, { Levels } from "./Visualizer";
+import { AppError, describeError } from "../error";
 
 type PlayerData = {
   playing: boolean;
+  position: number;
+  duration: number | null;
+  volume: number;
+  muted: boolean;
+  error: AppError | null;
+  output_device: string | null;
+  levels: Levels | null;
   current_track: {
     track_title: string;
     artist_title: string;
@@ -27,11 +38,42 @@ type PlayerUpdateMsg = {
       cover_art_id: number;
     };
   };
+} | {
+  event: "UpdatePosition";
+  data: {
+    position: number;
+  };
+} | {
+  event: "UpdateDuration";
+  data: {
+    duration: number;
+  };
+} | {
+  event: "UpdateVolume";
+  data: {
+    volume: number;
+    muted: boolean;
+  };
+} | {
+  event: "PlaybackError";
+  data: {
+    error: AppError;
+  };
+} | {
+  event: "OutputDeviceChanged";
+  data: {
+    device: string;
+  };
+} | {
+  event: "UpdateLevels";
+  data: {
+    levels: Levels;
+  };
 };
 
 function Player() {
   const [playerBig, setPlayerBig] = createSignal(false);
-  const [playerData, setPlayerData] = createStore<PlayerData>({ playing: false, current_track: null });
+  const [playerData, setPlayerData] = createStore<PlayerData>({ playing: false, position: 0, duration: null, volume: 1, muted: false, error: null, output_device: null, levels: null, current_track: null });
 
   onMount(() => {
     const channel = new Channel<PlayerUpdateMsg>();
@@ -43,10 +85,47 @@ function Player() {
         case "UpdateCurrentTrack":
           console.log(JSON.stringify(message.data));
           setPlayerData("current_track", message.data.current_track);
+          setPlayerData("error", null);
+          setPlayerData("position", 0);
+          setPlayerData("duration", null);
+          break;
+        case "UpdatePosition":
+          setPlayerData("position", message.data.position);
+          break;
+        case "UpdateDuration":
+          setPlayerData("duration", message.data.duration);
+          break;
+        case "UpdateVolume":
+          setPlayerData("volume", message.data.volume);
+          setPlayerData("muted", message.data.muted);
+          break;
+        case "PlaybackError":
+          setPlayerData("error", message.data.error);
+          break;
+        case "OutputDeviceChanged":
+          setPlayerData("output_device", message.data.device);
+          break;
+        case "UpdateLevels":
+          setPlayerData("levels", message.data.levels);
           break;
       }
     };
-    invoke("setup_player", { channel });
+    const setup = () =>
+      invoke("setup_player", { channel })
+        .then(() => setPlayerData("error", null))
+        .catch((e: AppError) => setPlayerData("error", e));
+    setup();
+    // picking an output device can bring up an output that wasn't there
+    // before
+    const unlisten = listen("output-ready", setup);
+    onCleanup(() => unlisten.then((f) => f()));
+  });
+
+  // the levels are only worth working out while the visualizer is showing
+  createEffect(() => {
+    const big = playerBig();
+    invoke("set_metering", { enabled: big }).catch(() => {});
+    if (!big) setPlayerData("levels", null);
   });
 
   return (
@@ -56,13 +135,31 @@ function Player() {
       <Show when={playerData.current_track !== null} fallback={<div>uhhh</div>}>
         <div class={`flex ${playerBig() ? "flex-col" : "flex-row"} justify-between w-full h-full`}>
           <div class={`flex ${playerBig() ? "flex-col w-full" : "flex-row max-w-2/3 space-x-4"} overflow-hidden`}>
-            <img class={`w-14 h-14`} src={`${SERVER_URL}/get-image?id=${playerData.current_track?.cover_art_id}`} />
+            <CoverArt class="w-14 h-14" id={playerData.current_track!.cover_art_id} />
             <div class="flex flex-col w-full overflow-hidden">
               <p class="font-bold font-serif text-xl text-nowrap overflow-hidden text-ellipsis w-full">{playerData.current_track?.track_title}</p>
               <p>{playerData.current_track?.artist_title}</p>
+              <Show when={playerData.output_device}>
+                {(device) => <p class="text-sm">Now playing through {device()}</p>}
+              </Show>
+              <Show when={playerData.error}>
+                {(error) => <p class="text-red-500">{describeError(error())}</p>}
+              </Show>
+              <Show when={playerBig() && playerData.levels}>
+                {(levels) => <Visualizer levels={levels()} />}
+              </Show>
+              <Show when={playerData.duration}>
+                {(duration) => <SeekBar position={playerData.position} duration={duration()} />}
+              </Show>
             </div>
           </div>
           <div class="relative flex flex-row space-x-4">
+            <button onClick={(e) => {
+              e.stopPropagation();
+              invoke("skip_back");
+            }}>
+              <IoPlaySkipBackSharp size={32} />
+            </button>
             <button onClick={(e) => {
               e.stopPropagation();
               invoke("toggle_playing");
@@ -83,6 +180,30 @@ function Player() {
             }}>
               <IoPlaySkipForwardSharp size={32} />
             </button>
+            <button onClick={(e) => {
+              e.stopPropagation();
+              invoke("set_muted", { muted: !playerData.muted });
+            }}>
+              <Switch>
+                <Match when={playerData.muted}>
+                  <IoVolumeMuteSharp size={32} />
+                </Match>
+                <Match when={!playerData.muted}>
+                  <IoVolumeHighSharp size={32} />
+                </Match>
+              </Switch>
+            </button>
+            <input
+              type="range"
+              class="w-24"
+              min={0}
+              max={1}
+              step={0.01}
+              value={playerData.volume}
+              onClick={(e) => e.stopPropagation()}
+              onInput={(e) => invoke("set_volume", { volume: Number(e.currentTarget.value), persist: false })}
+              onChange={(e) => invoke("set_volume", { volume: Number(e.currentTarget.value), persist: true })}
+            />
           </div>
         </div>
       </Show>
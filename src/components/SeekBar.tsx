@@ -0,0 +1,27 @@
+import { invoke } from "@tauri-apps/api/core";
+import { createSignal } from "solid-js";
+
+// `position` and `duration` are in seconds. while the thumb is held it stays
+// where the user put it, instead of following the position updates
+function SeekBar(props: { position: number; duration: number }) {
+  const [dragging, setDragging] = createSignal<number | null>(null);
+
+  return (
+    <input
+      type="range"
+      class="w-full"
+      min={0}
+      max={props.duration}
+      step={0.1}
+      value={dragging() ?? props.position}
+      onClick={(e) => e.stopPropagation()}
+      onInput={(e) => setDragging(Number(e.currentTarget.value))}
+      onChange={(e) => {
+        setDragging(null);
+        invoke("seek", { position: Number(e.currentTarget.value) });
+      }}
+    />
+  );
+}
+
+export default SeekBar;
@@ -0,0 +1,41 @@
+import { For, Index } from "solid-js";
+
+// levels come in as dBFS, -90 is as quiet as they go
+export type Levels = {
+  peak: [number, number];
+  rms: [number, number];
+  spectrum: number[];
+};
+
+const FLOOR_DB = -90;
+const SPECTRUM_FLOOR_DB = -60;
+
+const percent = (db: number, floor: number) => `${Math.max(0, Math.min(1, (db - floor) / -floor)) * 100}%`;
+
+function Visualizer(props: { levels: Levels }) {
+  return (
+    <div class="flex flex-row space-x-4 w-full h-32">
+      <div class="flex flex-row items-end space-x-px flex-1 h-full">
+        <Index each={props.levels.spectrum}>
+          {(db) => <div class="flex-1 bg-white" style={{ height: percent(db(), SPECTRUM_FLOOR_DB) }} />}
+        </Index>
+      </div>
+      <For each={[0, 1]}>
+        {(channel) => (
+          <div class="relative w-3 h-full border">
+            <div
+              class="absolute bottom-0 w-full bg-white"
+              style={{ height: percent(props.levels.rms[channel], FLOOR_DB) }}
+            />
+            <div
+              class="absolute w-full h-px bg-red-500"
+              style={{ bottom: percent(props.levels.peak[channel], FLOOR_DB) }}
+            />
+          </div>
+        )}
+      </For>
+    </div>
+  );
+}
+
+export default Visualizer;
@@ -0,0 +1,25 @@
+export type AppError = {
+  kind: "Network" | "NotFound" | "Unauthorized" | "Decode" | "AudioDevice" | "ServerProtocol" | "Storage" | "InvalidSettings";
+  message: string;
+};
+
+export const describeError = (error: AppError): string => {
+  switch (error.kind) {
+    case "Network":
+      return "Server offline";
+    case "NotFound":
+      return "Not found";
+    case "Unauthorized":
+      return "Log in to this server";
+    case "Decode":
+      return "Couldn't play this track";
+    case "AudioDevice":
+      return "No audio output";
+    case "ServerProtocol":
+      return "The server sent something unexpected";
+    case "Storage":
+      return "Couldn't save to disk";
+    case "InvalidSettings":
+      return "Invalid settings";
+  }
+};
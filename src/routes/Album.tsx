@@ -1,7 +1,9 @@
 import { useParams } from "@solidjs/router";
 import { invoke } from "@tauri-apps/api/core";
-import { Index, Suspense, createResource } from "solid-js";
-import { SERVER_URL } from "..";
+import { listen } from "@tauri-apps/api/event";
+import { Index, Show, Suspense, createResource, createSignal, onCleanup } from "solid-js";
+import CoverArt from "../components/CoverArt";
+import { AppError, describeError } from "../error";
 
 type Album = {
   title: string;
@@ -14,20 +16,50 @@ type Track = {
   title: string;
   track_number: number;
 };
+type ChangedIds = {
+  upserted: number[];
+  deleted: number[];
+};
+type LibraryChanges = {
+  albums: ChangedIds;
+  artists: ChangedIds;
+  tracks: ChangedIds;
+};
 
 const getAlbum = async (id: number): Promise<Album> => await invoke("get_album", { id });
 
 function Album() {
   const { id } = useParams();
-  const [album] = createResource(Number(id), getAlbum);
+  const [album, { refetch }] = createResource(Number(id), getAlbum);
+
+  const unlisten = listen<LibraryChanges>("library-changed", (event) => {
+    if (event.payload.albums.upserted.includes(Number(id))) {
+      refetch();
+    }
+  });
+  const unlistenServer = listen("server-changed", () => refetch());
+  onCleanup(() => {
+    unlisten.then((f) => f());
+    unlistenServer.then((f) => f());
+  });
 
-  const playTrack = (id: number) => invoke("play_track", { id });
+  const [playError, setPlayError] = createSignal<AppError | null>(null);
+  const playTrack = (id: number) =>
+    invoke("play_track", { id })
+      .then(() => setPlayError(null))
+      .catch((e: AppError) => setPlayError(e));
 
   return (
     <div class="flex flex-col space-y-8 h-full w-full">
+      <Show when={album.error}>
+        <p class="text-red-500">{describeError(album.error as AppError)}</p>
+      </Show>
+      <Show when={playError()}>
+        {(error) => <p class="text-red-500">{describeError(error())}</p>}
+      </Show>
       <Suspense>
         <div class="flex flex-col space-y-4">
-          <img src={`${SERVER_URL}/get-image?id=${id}`} />
+          <CoverArt id={Number(id)} />
           <div>
             <h1
               class="text-4xl font-serif font-bold text-nowrap text-ellipsis overflow-hidden"
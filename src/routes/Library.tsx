@@ -1,7 +1,9 @@
-import { createResource, For, Suspense } from "solid-js";
+import { createResource, For, onCleanup, Show, Suspense } from "solid-js";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { A } from "@solidjs/router";
-import { SERVER_URL } from "..";
+import CoverArt from "../components/CoverArt";
+import { AppError, describeError } from "../error";
 
 type LibraryData = {
   albums: AlbumData[];
@@ -13,13 +15,31 @@ type AlbumData = {
 };
 
 const getLibrary = async (): Promise<LibraryData> => await invoke("get_library");
+const getStartupErrors = async (): Promise<AppError[]> => await invoke("get_startup_errors");
 
 function Library() {
-  const [lib] = createResource(getLibrary);
+  const [lib, { refetch }] = createResource(getLibrary);
+  const [startupErrors] = createResource(getStartupErrors);
+
+  const unlisten = listen("library-changed", () => refetch());
+  const unlistenServer = listen("server-changed", () => refetch());
+  onCleanup(() => {
+    unlisten.then((f) => f());
+    unlistenServer.then((f) => f());
+  });
 
   return (
     <div class="flex flex-col w-full h-full space-y-8">
-      <h1 class="text-4xl font-bold font-serif">Library</h1>
+      <div class="flex flex-row justify-between items-center">
+        <h1 class="text-4xl font-bold font-serif">Library</h1>
+        <A href="/settings">Settings</A>
+      </div>
+      <For each={startupErrors()}>
+        {(error) => <p class="text-red-500">{describeError(error)}: {error.message}</p>}
+      </For>
+      <Show when={lib.error}>
+        <p class="text-red-500">{describeError(lib.error as AppError)}</p>
+      </Show>
       <div class="flex flex-col space-y-4">
         <div>
           <h2 class="text-2xl font-bold">Albums</h2>
@@ -30,7 +50,7 @@ function Library() {
             <For each={lib()?.albums}>
               {(album) => (
                 <A href={`/album/${album.id}`} class="max-w-1/3 flex flex-col space-y-2">
-                  <img src={`${SERVER_URL}/get-image?id=${album.id}`} />
+                  <CoverArt id={album.id} />
                   <div>
                     <h3 class="text-md font-bold font-serif text-nowrap text-ellipsis overflow-hidden">{album.title}</h3>
                     <p>by {album.artist_name}</p>
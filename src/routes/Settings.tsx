@@ -0,0 +1,306 @@
+import { invoke } from "@tauri-apps/api/core";
+import { A } from "@solidjs/router";
+import { listen } from "@tauri-apps/api/event";
+import { createResource, createSignal, For, onCleanup, Show } from "solid-js";
+import { AppError, describeError } from "../error";
+import {
+  BandKind,
+  Crossfeed,
+  DiscoveredServer,
+  EqBand,
+  EqCurve,
+  getDiscoveredServers,
+  getOutputDevices,
+  getSettings,
+  OutputConfig,
+  ReplayGainMode,
+  ReplayGainSettings,
+  StereoSettings,
+} from "../settings";
+
+const describeConfig = (config: OutputConfig) =>
+  config.min_sample_rate === config.max_sample_rate
+    ? `${config.channels}ch ${config.sample_format} ${config.min_sample_rate} Hz`
+    : `${config.channels}ch ${config.sample_format} ${config.min_sample_rate}-${config.max_sample_rate} Hz`;
+
+function Settings() {
+  const [settings, { refetch }] = createResource(getSettings);
+  const [name, setName] = createSignal("");
+  const [url, setUrl] = createSignal("");
+  const [error, setError] = createSignal<AppError | null>(null);
+  const [discovered, { mutate: setDiscovered }] = createResource(getDiscoveredServers);
+  const [devices] = createResource(getOutputDevices);
+
+  const unlisten = listen<DiscoveredServer[]>("servers-discovered", (event) => setDiscovered(event.payload));
+  onCleanup(() => unlisten.then((f) => f()));
+
+  const run = (command: string, args: Record<string, unknown>) =>
+    invoke(command, args)
+      .then(() => setError(null))
+      .catch((e: AppError) => setError(e))
+      .finally(refetch);
+
+  const setReplayGain = (changes: Partial<ReplayGainSettings>) => {
+    const current = settings()?.replay_gain ?? { mode: "Off", compute_missing: false };
+    run("set_replay_gain", { settings: { ...current, ...changes } });
+  };
+
+  const setStereo = (changes: Partial<StereoSettings>) => {
+    const current = settings()?.stereo ?? { crossfeed: null, mono: false, balance: 0 };
+    run("set_stereo", { stereo: { ...current, ...changes } });
+  };
+
+  const [presetName, setPresetName] = createSignal("");
+  const curve = (): EqCurve => settings()?.equalizer.curve ?? { preamp_db: 0, bands: [] };
+  const setEqualizer = (enabled: boolean, curve: EqCurve) => run("set_equalizer", { enabled, curve });
+  const setCurve = (curve: EqCurve) => setEqualizer(settings()?.equalizer.enabled ?? false, curve);
+  const setBand = (index: number, changes: Partial<EqBand>) =>
+    setCurve({ ...curve(), bands: curve().bands.map((b, i) => (i === index ? { ...b, ...changes } : b)) });
+  const addBand = () =>
+    setCurve({ ...curve(), bands: [...curve().bands, { kind: "Peaking", frequency: 1000, gain_db: 0, q: 1 }] });
+  const removeBand = (index: number) => setCurve({ ...curve(), bands: curve().bands.filter((_, i) => i !== index) });
+
+  return (
+    <div class="flex flex-col w-full h-full space-y-8">
+      <A href="/">Back</A>
+      <h1 class="text-4xl font-bold font-serif">Settings</h1>
+      <Show when={error()}>
+        {(error) => <p class="text-red-500">{describeError(error())}: {error().message}</p>}
+      </Show>
+      <div class="flex flex-col space-y-4">
+        <div>
+          <h2 class="text-2xl font-bold">Servers</h2>
+          <hr />
+        </div>
+        <For each={settings()?.servers}>
+          {(server) => (
+            <div class="flex flex-row space-x-4 items-center">
+              <button onClick={() => run("set_active_server", { name: server.name })}>
+                {server.name === settings()?.active_server ? "●" : "○"}
+              </button>
+              <div class="flex flex-col">
+                <span class="font-bold">{server.name}</span>
+                <span>{server.url}</span>
+              </div>
+              <button onClick={() => { setName(server.name); setUrl(server.url); }}>Edit</button>
+              <Show
+                when={server.name !== settings()?.active_server}
+                fallback={<button onClick={() => run("logout", {})}>Log out</button>}
+              >
+                <button onClick={() => run("remove_server", { name: server.name })}>Remove</button>
+              </Show>
+            </div>
+          )}
+        </For>
+        <form
+          class="flex flex-row space-x-2"
+          onSubmit={(e) => {
+            e.preventDefault();
+            run("save_server", { name: name(), url: url() });
+          }}
+        >
+          <input class="bg-black border px-2" placeholder="Name" value={name()} onInput={(e) => setName(e.currentTarget.value)} />
+          <input class="bg-black border px-2" placeholder="http://pi-fi.local:8080" value={url()} onInput={(e) => setUrl(e.currentTarget.value)} />
+          <button type="submit">Save</button>
+        </form>
+      </div>
+      <div class="flex flex-col space-y-4">
+        <div>
+          <h2 class="text-2xl font-bold">On this network</h2>
+          <hr />
+        </div>
+        <For each={discovered()} fallback={<p>Looking for servers...</p>}>
+          {(server) => (
+            <div class="flex flex-row space-x-4 items-center">
+              <div class="flex flex-col">
+                <span class="font-bold">{server.name}</span>
+                <span>{server.url}</span>
+              </div>
+              <button onClick={() => run("use_discovered_server", { name: server.name })}>Use</button>
+            </div>
+          )}
+        </For>
+      </div>
+      <div class="flex flex-col space-y-4">
+        <div>
+          <h2 class="text-2xl font-bold">Playback</h2>
+          <hr />
+        </div>
+        <select
+          class="bg-black border px-2"
+          onChange={(e) => run("set_output_device", { name: e.currentTarget.value || null })}
+        >
+          <option value="" selected={!settings()?.output_device}>Default output</option>
+          <For each={devices()}>
+            {(device) => (
+              <option
+                value={device.name}
+                selected={device.name === settings()?.output_device}
+                title={device.configs.map(describeConfig).join("\n")}
+              >
+                {device.name}{device.default ? " (default)" : ""}
+              </option>
+            )}
+          </For>
+        </select>
+        <label class="flex flex-row space-x-2 items-center">
+          <input
+            type="checkbox"
+            checked={settings()?.native_sample_rate ?? false}
+            onChange={(e) => run("set_native_sample_rate", { enabled: e.currentTarget.checked })}
+          />
+          <span>Play tracks at their own sample rate when the output device supports it</span>
+        </label>
+        <label class="flex flex-row space-x-2 items-center">
+          <span>ReplayGain</span>
+          <select
+            class="bg-black border px-2"
+            onChange={(e) => setReplayGain({ mode: e.currentTarget.value as ReplayGainMode })}
+          >
+            <For each={["Off", "Track", "Album"] as ReplayGainMode[]}>
+              {(mode) => <option value={mode} selected={settings()?.replay_gain.mode === mode}>{mode}</option>}
+            </For>
+          </select>
+        </label>
+        <label class="flex flex-row space-x-2 items-center">
+          <input
+            type="checkbox"
+            checked={settings()?.replay_gain.compute_missing ?? false}
+            onChange={(e) => setReplayGain({ compute_missing: e.currentTarget.checked })}
+          />
+          <span>Measure the loudness of tracks that don't have ReplayGain tags</span>
+        </label>
+      </div>
+      <div class="flex flex-col space-y-4">
+        <div>
+          <h2 class="text-2xl font-bold">Equalizer</h2>
+          <hr />
+        </div>
+        <label class="flex flex-row space-x-2 items-center">
+          <input
+            type="checkbox"
+            checked={settings()?.equalizer.enabled ?? false}
+            onChange={(e) => setEqualizer(e.currentTarget.checked, curve())}
+          />
+          <span>Enabled</span>
+        </label>
+        <label class="flex flex-row space-x-2 items-center">
+          <span>Preamp (dB)</span>
+          <input
+            class="bg-black border px-2 w-20"
+            type="number"
+            step="0.5"
+            value={curve().preamp_db}
+            onChange={(e) => setCurve({ ...curve(), preamp_db: e.currentTarget.valueAsNumber })}
+          />
+        </label>
+        <For each={curve().bands}>
+          {(band, i) => (
+            <div class="flex flex-row space-x-2 items-center">
+              <select
+                class="bg-black border px-2"
+                onChange={(e) => setBand(i(), { kind: e.currentTarget.value as BandKind })}
+              >
+                <option value="Peaking" selected={band.kind === "Peaking"}>Peaking</option>
+                <option value="LowShelf" selected={band.kind === "LowShelf"}>Low shelf</option>
+                <option value="HighShelf" selected={band.kind === "HighShelf"}>High shelf</option>
+              </select>
+              <input
+                class="bg-black border px-2 w-24"
+                type="number"
+                min="1"
+                value={band.frequency}
+                onChange={(e) => setBand(i(), { frequency: e.currentTarget.valueAsNumber })}
+              />
+              <span>Hz</span>
+              <input
+                class="bg-black border px-2 w-20"
+                type="number"
+                step="0.5"
+                value={band.gain_db}
+                onChange={(e) => setBand(i(), { gain_db: e.currentTarget.valueAsNumber })}
+              />
+              <span>dB</span>
+              <input
+                class="bg-black border px-2 w-20"
+                type="number"
+                min="0.1"
+                step="0.1"
+                value={band.q}
+                onChange={(e) => setBand(i(), { q: e.currentTarget.valueAsNumber })}
+              />
+              <span>Q</span>
+              <button onClick={() => removeBand(i())}>Remove</button>
+            </div>
+          )}
+        </For>
+        <button class="self-start" onClick={addBand}>Add band</button>
+        <For each={settings()?.equalizer.presets}>
+          {(preset) => (
+            <div class="flex flex-row space-x-2 items-center">
+              <span>{preset.name}</span>
+              <button onClick={() => run("load_eq_preset", { name: preset.name })}>Load</button>
+              <button onClick={() => run("remove_eq_preset", { name: preset.name })}>Remove</button>
+            </div>
+          )}
+        </For>
+        <form
+          class="flex flex-row space-x-2"
+          onSubmit={(e) => {
+            e.preventDefault();
+            run("save_eq_preset", { name: presetName() });
+          }}
+        >
+          <input
+            class="bg-black border px-2"
+            placeholder="Preset name"
+            value={presetName()}
+            onInput={(e) => setPresetName(e.currentTarget.value)}
+          />
+          <button type="submit">Save preset</button>
+        </form>
+      </div>
+      <div class="flex flex-col space-y-4">
+        <div>
+          <h2 class="text-2xl font-bold">Headphones</h2>
+          <hr />
+        </div>
+        <label class="flex flex-row space-x-2 items-center">
+          <span>Crossfeed</span>
+          <select
+            class="bg-black border px-2"
+            onChange={(e) => setStereo({ crossfeed: (e.currentTarget.value || null) as Crossfeed | null })}
+          >
+            <option value="" selected={!settings()?.stereo.crossfeed}>Off</option>
+            <option value="Bauer" selected={settings()?.stereo.crossfeed === "Bauer"}>Bauer</option>
+            <option value="ChuMoy" selected={settings()?.stereo.crossfeed === "ChuMoy"}>Chu Moy</option>
+            <option value="Meier" selected={settings()?.stereo.crossfeed === "Meier"}>Jan Meier</option>
+          </select>
+        </label>
+        <label class="flex flex-row space-x-2 items-center">
+          <input
+            type="checkbox"
+            checked={settings()?.stereo.mono ?? false}
+            onChange={(e) => setStereo({ mono: e.currentTarget.checked })}
+          />
+          <span>Mono</span>
+        </label>
+        <label class="flex flex-row space-x-2 items-center">
+          <span>L</span>
+          <input
+            type="range"
+            min="-1"
+            max="1"
+            step="0.05"
+            value={settings()?.stereo.balance ?? 0}
+            onChange={(e) => setStereo({ balance: e.currentTarget.valueAsNumber })}
+            onDblClick={() => setStereo({ balance: 0 })}
+          />
+          <span>R</span>
+        </label>
+      </div>
+    </div>
+  )
+}
+
+export default Settings;
@@ -0,0 +1,75 @@
+import { invoke } from "@tauri-apps/api/core";
+
+export type ServerProfile = {
+  name: string;
+  url: string;
+};
+export type Settings = {
+  servers: ServerProfile[];
+  active_server: string;
+  native_sample_rate: boolean;
+  output_device: string | null;
+  replay_gain: ReplayGainSettings;
+  equalizer: EqualizerSettings;
+  stereo: StereoSettings;
+  crossfade: CrossfadeSettings;
+};
+
+export type ReplayGainMode = "Off" | "Track" | "Album";
+export type ReplayGainSettings = {
+  mode: ReplayGainMode;
+  compute_missing: boolean;
+};
+
+export type BandKind = "Peaking" | "LowShelf" | "HighShelf";
+export type EqBand = {
+  kind: BandKind;
+  frequency: number;
+  gain_db: number;
+  q: number;
+};
+export type EqCurve = {
+  preamp_db: number;
+  bands: EqBand[];
+};
+export type EqualizerSettings = {
+  enabled: boolean;
+  curve: EqCurve;
+  presets: { name: string; curve: EqCurve }[];
+};
+
+export type Crossfeed = "Bauer" | "ChuMoy" | "Meier";
+export type StereoSettings = {
+  crossfeed: Crossfeed | null;
+  mono: boolean;
+  balance: number;
+};
+
+export type FadeCurve = "Linear" | "EqualPower";
+// `duration` is in seconds, 0 is off
+export type CrossfadeSettings = {
+  duration: number;
+  curve: FadeCurve;
+};
+
+export type DiscoveredServer = {
+  name: string;
+  url: string;
+};
+
+export type OutputConfig = {
+  channels: number;
+  min_sample_rate: number;
+  max_sample_rate: number;
+  sample_format: string;
+};
+export type OutputDevice = {
+  name: string;
+  default: boolean;
+  configs: OutputConfig[];
+};
+
+export const getSettings = async (): Promise<Settings> => await invoke("get_settings");
+export const getDiscoveredServers = async (): Promise<DiscoveredServer[]> =>
+  await invoke("get_discovered_servers");
+export const getOutputDevices = async (): Promise<OutputDevice[]> => await invoke("get_output_devices");